@@ -0,0 +1,421 @@
+use std::{fmt, str::FromStr};
+
+use super::{
+	cursor::{is_atext, quote, unquote, Cursor},
+	ParseFieldError,
+};
+use crate::smtp::args::{ParsePathError, Path};
+
+/// Someone's address in a header field, like `Gen <gen@nyble.dev>` (RFC 5322
+/// section 3.4)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mailbox {
+	/// The display name, with any quoting removed
+	pub name: Option<String>,
+	/// The local part, quoted if it has to be. Obsolete forms like
+	/// `"john".doe` are rewritten as one quoted string.
+	pub local_part: String,
+	/// A domain name or a literal like `[192.0.2.1]`
+	pub domain: String,
+}
+
+/// What address fields like To hold: mailboxes, or named groups of them
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+	Mailbox(Mailbox),
+	/// Like `friends: a@example.com, b@example.com;`. The list can be empty.
+	Group {
+		name: String,
+		members: Vec<Mailbox>,
+	},
+}
+
+impl Mailbox {
+	/// The addr-spec, like `gen@nyble.dev`
+	pub fn address(&self) -> String {
+		format!("{}@{}", self.local_part, self.domain)
+	}
+
+	/// The mailbox as an SMTP path. Not every address that can be written in
+	/// a header can be used in SMTP, like ones with comments in a domain
+	/// literal.
+	pub fn to_path(&self) -> Result<Path, ParsePathError> {
+		Ok(Path::new(self.local_part.parse()?, self.domain.parse()?))
+	}
+}
+
+impl Address {
+	/// The mailbox, or everyone in the group
+	pub fn mailboxes(&self) -> &[Mailbox] {
+		match self {
+			Address::Mailbox(mailbox) => std::slice::from_ref(mailbox),
+			Address::Group { members, .. } => members,
+		}
+	}
+}
+
+/// A mailbox-list, like the From field holds
+pub fn mailbox_list(s: &str) -> Result<Vec<Mailbox>, ParseFieldError> {
+	list(s, mailbox)
+}
+
+/// An address-list, like the To and Cc fields hold
+pub fn address_list(s: &str) -> Result<Vec<Address>, ParseFieldError> {
+	list(s, address)
+}
+
+impl FromStr for Mailbox {
+	type Err = ParseFieldError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		whole(s, mailbox)
+	}
+}
+
+impl FromStr for Address {
+	type Err = ParseFieldError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		whole(s, address)
+	}
+}
+
+fn whole<T>(
+	s: &str,
+	parse: fn(&mut Cursor) -> Result<T, ParseFieldError>,
+) -> Result<T, ParseFieldError> {
+	let mut cursor = Cursor::new(s);
+	let parsed = parse(&mut cursor)?;
+
+	match cursor.is_empty() {
+		true => Ok(parsed),
+		false => Err(cursor.unexpected()),
+	}
+}
+
+fn list<T>(
+	s: &str,
+	parse: fn(&mut Cursor) -> Result<T, ParseFieldError>,
+) -> Result<Vec<T>, ParseFieldError> {
+	let mut cursor = Cursor::new(s);
+	let items = elements(&mut cursor, parse)?;
+
+	match (cursor.is_empty(), items.is_empty()) {
+		(true, false) => Ok(items),
+		_ => Err(cursor.unexpected()),
+	}
+}
+
+/// Comma separated elements, stopping at the end or a semicolon. The
+/// obsolete syntax allows empty elements, like `a@b, , c@d,` (RFC 5322
+/// section 4.4).
+fn elements<T>(
+	cursor: &mut Cursor,
+	parse: fn(&mut Cursor) -> Result<T, ParseFieldError>,
+) -> Result<Vec<T>, ParseFieldError> {
+	let mut items = vec![];
+
+	loop {
+		cursor.skip_cfws()?;
+		if cursor.eat(',') {
+			continue;
+		}
+
+		if cursor.is_empty() || cursor.peek() == Some(';') {
+			return Ok(items);
+		}
+
+		items.push(parse(cursor)?);
+		if !cursor.eat(',') {
+			return Ok(items);
+		}
+	}
+}
+
+fn address(cursor: &mut Cursor) -> Result<Address, ParseFieldError> {
+	match named(cursor)? {
+		Named::Mailbox(mailbox) => Ok(Address::Mailbox(mailbox)),
+		Named::Group(name) => {
+			let members = elements(cursor, mailbox)?;
+			cursor.expect(';')?;
+			cursor.skip_cfws()?;
+			Ok(Address::Group { name, members })
+		}
+	}
+}
+
+fn mailbox(cursor: &mut Cursor) -> Result<Mailbox, ParseFieldError> {
+	match named(cursor)? {
+		Named::Mailbox(mailbox) => Ok(mailbox),
+		Named::Group(_) => Err(ParseFieldError::GroupNotAllowed),
+	}
+}
+
+enum Named {
+	Mailbox(Mailbox),
+	/// The name of a group. The cursor is just past the colon.
+	Group(String),
+}
+
+/// A mailbox, or the start of a group. They can't be told apart until what
+/// follows the name.
+fn named(cursor: &mut Cursor) -> Result<Named, ParseFieldError> {
+	// A bare addr-spec
+	let start = *cursor;
+	if let Ok((local_part, domain)) = addr_spec(cursor) {
+		return Ok(Named::Mailbox(Mailbox {
+			name: None,
+			local_part,
+			domain,
+		}));
+	}
+	*cursor = start;
+
+	cursor.skip_cfws()?;
+	let name = match cursor.peek() {
+		Some('<') => None,
+		_ => Some(cursor.phrase()?),
+	};
+
+	if cursor.eat('<') {
+		skip_route(cursor)?;
+		let (local_part, domain) = addr_spec(cursor)?;
+		cursor.expect('>')?;
+		cursor.skip_cfws()?;
+
+		return Ok(Named::Mailbox(Mailbox {
+			name,
+			local_part,
+			domain,
+		}));
+	}
+
+	match name {
+		Some(name) if cursor.eat(':') => Ok(Named::Group(name)),
+		_ => Err(cursor.unexpected()),
+	}
+}
+
+/// Source routes like the `@relay.example:` in
+/// `<@relay.example:gen@nyble.dev>` are obsolete (RFC 5322 section 4.4) and
+/// ignored
+fn skip_route(cursor: &mut Cursor) -> Result<(), ParseFieldError> {
+	cursor.skip_cfws()?;
+	if !matches!(cursor.peek(), Some('@' | ',')) {
+		return Ok(());
+	}
+
+	loop {
+		cursor.skip_cfws()?;
+		if cursor.eat('@') {
+			domain(cursor)?;
+		} else if !cursor.eat(',') {
+			return cursor.expect(':');
+		}
+	}
+}
+
+/// local-part "@" domain, with the CFWS around them
+fn addr_spec(cursor: &mut Cursor) -> Result<(String, String), ParseFieldError> {
+	let local_part = local_part(cursor)?;
+	cursor.expect('@')?;
+	let domain = domain(cursor)?;
+
+	Ok((local_part, domain))
+}
+
+/// A dot-atom or quoted string. The obsolete syntax allows words of either
+/// kind between the periods, with CFWS around them.
+fn local_part(cursor: &mut Cursor) -> Result<String, ParseFieldError> {
+	let mut words = vec![];
+	let mut quoted = false;
+
+	loop {
+		cursor.skip_cfws()?;
+		match cursor.quoted_string()? {
+			Some(word) => {
+				quoted = true;
+				words.push(word.to_owned());
+			}
+			None => words.push(cursor.atom().ok_or(cursor.unexpected())?.to_owned()),
+		}
+		cursor.skip_cfws()?;
+
+		if !cursor.eat('.') {
+			break;
+		}
+	}
+
+	if !quoted || words.len() == 1 {
+		return Ok(words.join("."));
+	}
+
+	let unquoted: Vec<String> = words
+		.iter()
+		.map(|word| match word.starts_with('"') {
+			true => unquote(word),
+			false => word.to_owned(),
+		})
+		.collect();
+	Ok(quote(&unquoted.join(".")))
+}
+
+/// A domain name or literal. The obsolete syntax allows CFWS around the
+/// periods.
+fn domain(cursor: &mut Cursor) -> Result<String, ParseFieldError> {
+	cursor.skip_cfws()?;
+	if let Some(literal) = cursor.domain_literal()? {
+		cursor.skip_cfws()?;
+		return Ok(literal.to_owned());
+	}
+
+	let mut labels = vec![];
+	loop {
+		cursor.skip_cfws()?;
+		labels.push(cursor.atom().ok_or(cursor.unexpected())?);
+		cursor.skip_cfws()?;
+
+		if !cursor.eat('.') {
+			return Ok(labels.join("."));
+		}
+	}
+}
+
+/// A display name, quoted if it has to be
+struct DisplayName<'a>(&'a str);
+
+impl fmt::Display for DisplayName<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let atoms = self
+			.0
+			.split(' ')
+			.all(|word| !word.is_empty() && word.chars().all(is_atext));
+
+		match atoms {
+			true => write!(f, "{}", self.0),
+			false => write!(f, "{}", quote(self.0)),
+		}
+	}
+}
+
+impl fmt::Display for Mailbox {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.name {
+			Some(name) => write!(f, "{} <{}>", DisplayName(name), self.address()),
+			None => write!(f, "{}", self.address()),
+		}
+	}
+}
+
+impl fmt::Display for Address {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Address::Mailbox(mailbox) => write!(f, "{}", mailbox),
+			Address::Group { name, members } => {
+				write!(f, "{}:", DisplayName(name))?;
+				for (index, member) in members.iter().enumerate() {
+					match index {
+						0 => write!(f, " {}", member)?,
+						_ => write!(f, ", {}", member)?,
+					}
+				}
+				write!(f, ";")
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::{fs::File, io::Read};
+
+	#[test]
+	fn mailboxes() {
+		let mailbox: Mailbox = "\"Gen, the\" <gen@nyble.dev>".parse().unwrap();
+		assert_eq!(mailbox.name.as_deref(), Some("Gen, the"));
+		assert_eq!(mailbox.address(), "gen@nyble.dev");
+		assert_eq!(mailbox.to_string(), "\"Gen, the\" <gen@nyble.dev>");
+
+		let mailbox: Mailbox = "gen.nyble (Gen) @ [192.0.2.1]".parse().unwrap();
+		assert_eq!(mailbox.name, None);
+		assert_eq!(mailbox.address(), "gen.nyble@[192.0.2.1]");
+
+		let mailbox: Mailbox = "<\"a b\"@example.com>".parse().unwrap();
+		assert_eq!(mailbox.local_part, "\"a b\"");
+	}
+
+	#[test]
+	fn lists() {
+		let list = address_list("Gen <gen@nyble.dev>, group: a@b, c@d;, e@f").unwrap();
+		assert_eq!(list.len(), 3);
+		assert_eq!(list[1].to_string(), "group: a@b, c@d;");
+		assert_eq!(list[1].mailboxes().len(), 2);
+
+		assert_eq!(
+			address_list("undisclosed-recipients:;").unwrap()[0],
+			Address::Group {
+				name: String::from("undisclosed-recipients"),
+				members: vec![]
+			}
+		);
+
+		assert_eq!(
+			mailbox_list("a@b, group: c@d;"),
+			Err(ParseFieldError::GroupNotAllowed)
+		);
+		assert!(mailbox_list("Gen gen@nyble.dev").is_err());
+	}
+
+	#[test]
+	fn obsolete_syntax() {
+		let mailbox: Mailbox = "John Q. Public <@relay.example:\"john\".public@example.com>"
+			.parse()
+			.unwrap();
+		assert_eq!(mailbox.name.as_deref(), Some("John Q. Public"));
+		assert_eq!(mailbox.local_part, "\"john.public\"");
+		assert_eq!(
+			mailbox.to_string(),
+			"\"John Q. Public\" <\"john.public\"@example.com>"
+		);
+	}
+
+	#[test]
+	fn paths() {
+		let path = "Gen <gen@nyble.dev>".parse::<Mailbox>().unwrap().to_path();
+		assert_eq!(path.unwrap().to_string(), "<gen@nyble.dev>");
+
+		let path = "\"a b\"@[192.0.2.1]".parse::<Mailbox>().unwrap().to_path();
+		assert_eq!(path.unwrap().to_string(), "<\"a b\"@[192.0.2.1]>");
+
+		// Fine in a header, but not a domain SMTP can use
+		let mailbox: Mailbox = "gen@-nyble.dev".parse().unwrap();
+		assert!(mailbox.to_path().is_err());
+	}
+
+	fn get_test_data(filename: &str) -> Vec<String> {
+		let mut file = File::open(format!("testfiles/data/{}", filename)).unwrap();
+		let mut buf = String::new();
+		file.read_to_string(&mut buf).unwrap();
+		buf.lines().map(|line| line.to_owned()).collect()
+	}
+
+	#[test]
+	fn corpus() {
+		for line in get_test_data("valid_address_lists.txt") {
+			let (list, expected) = line.rsplit_once(" =>").unwrap();
+			let addresses = address_list(list).unwrap_or_else(|e| panic!("{}: {}", list, e));
+
+			let found: Vec<String> = addresses
+				.iter()
+				.flat_map(Address::mailboxes)
+				.map(Mailbox::address)
+				.collect();
+			assert_eq!(found.join(" "), expected.trim(), "on {}", list);
+		}
+
+		for list in get_test_data("invalid_address_lists.txt") {
+			assert!(address_list(&list).is_err(), "passed on {}", list);
+		}
+	}
+}
@@ -0,0 +1,236 @@
+use super::ParseFieldError;
+
+/// Reads the lexical tokens of structured field bodies (RFC 5322 section
+/// 3.2). Bodies have to be unfolded first. It's Copy so parsers can go back
+/// and try something else.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cursor<'a> {
+	rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+	pub fn new(s: &'a str) -> Self {
+		Self { rest: s }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.rest.is_empty()
+	}
+
+	pub fn peek(&self) -> Option<char> {
+		self.rest.chars().next()
+	}
+
+	/// Take `c` if it's next
+	pub fn eat(&mut self, c: char) -> bool {
+		match self.rest.strip_prefix(c) {
+			Some(rest) => {
+				self.rest = rest;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Take `c`, or say what was there instead
+	pub fn expect(&mut self, c: char) -> Result<(), ParseFieldError> {
+		match self.eat(c) {
+			true => Ok(()),
+			false => Err(self.unexpected()),
+		}
+	}
+
+	/// The error for whatever is next
+	pub fn unexpected(&self) -> ParseFieldError {
+		match self.peek() {
+			Some(c) => ParseFieldError::Unexpected(c),
+			None => ParseFieldError::UnexpectedEnd,
+		}
+	}
+
+	/// Skip whitespace and comments, which can nest
+	pub fn skip_cfws(&mut self) -> Result<(), ParseFieldError> {
+		loop {
+			self.rest = self.rest.trim_start_matches(is_wsp);
+
+			if !self.eat('(') {
+				return Ok(());
+			}
+
+			let mut depth = 1;
+			while depth > 0 {
+				let mut chars = self.rest.chars();
+				match chars.next() {
+					None => return Err(ParseFieldError::UnclosedComment),
+					Some('(') => depth += 1,
+					Some(')') => depth -= 1,
+					Some('\\') => {
+						chars.next();
+					}
+					Some(_) => (),
+				}
+				self.rest = chars.as_str();
+			}
+		}
+	}
+
+	/// One or more atext characters
+	pub fn atom(&mut self) -> Option<&'a str> {
+		self.take_while(is_atext)
+	}
+
+	/// Atoms joined by single periods, like `gen.nyble`
+	pub fn dot_atom_text(&mut self) -> Option<&'a str> {
+		let start = self.rest;
+		self.atom()?;
+
+		loop {
+			let before = *self;
+			if !self.eat('.') || self.atom().is_none() {
+				*self = before;
+				break;
+			}
+		}
+
+		Some(&start[..start.len() - self.rest.len()])
+	}
+
+	/// A quoted string as it was written, quotes and escapes included. None
+	/// if there isn't one next.
+	pub fn quoted_string(&mut self) -> Result<Option<&'a str>, ParseFieldError> {
+		if self.peek() != Some('"') {
+			return Ok(None);
+		}
+
+		let mut chars = self.rest.char_indices().skip(1);
+		while let Some((index, c)) = chars.next() {
+			match c {
+				'\\' => {
+					chars.next();
+				}
+				'"' => {
+					let (quoted, rest) = self.rest.split_at(index + 1);
+					self.rest = rest;
+					return Ok(Some(quoted));
+				}
+				_ => (),
+			}
+		}
+
+		Err(ParseFieldError::UnclosedQuote)
+	}
+
+	/// A domain literal like `[192.0.2.1]`, as it was written
+	pub fn domain_literal(&mut self) -> Result<Option<&'a str>, ParseFieldError> {
+		if self.peek() != Some('[') {
+			return Ok(None);
+		}
+
+		match self.rest.find(']') {
+			Some(end) if !self.rest[1..end].contains(['[', '\\']) => {
+				let (literal, rest) = self.rest.split_at(end + 1);
+				self.rest = rest;
+				Ok(Some(literal))
+			}
+			Some(_) => Err(ParseFieldError::Unexpected('[')),
+			None => Err(ParseFieldError::UnexpectedEnd),
+		}
+	}
+
+	/// An atom or quoted string with the CFWS around it. Quoted strings
+	/// come back with their quotes removed.
+	pub fn word(&mut self) -> Result<Option<String>, ParseFieldError> {
+		let before = *self;
+		self.skip_cfws()?;
+
+		let word = match self.quoted_string()? {
+			Some(quoted) => unquote(quoted),
+			None => match self.atom() {
+				Some(atom) => atom.to_owned(),
+				None => {
+					*self = before;
+					return Ok(None);
+				}
+			},
+		};
+
+		self.skip_cfws()?;
+		Ok(Some(word))
+	}
+
+	/// One or more words, like a display name. They're joined by single
+	/// spaces. The obsolete syntax allows periods after the first word, like
+	/// in `John Q. Public` (RFC 5322 section 4.1).
+	pub fn phrase(&mut self) -> Result<String, ParseFieldError> {
+		let mut words: Vec<String> = vec![];
+		loop {
+			if let Some(word) = self.word()? {
+				words.push(word);
+				continue;
+			}
+
+			match words.last_mut() {
+				Some(last) if self.eat('.') => {
+					last.push('.');
+					self.skip_cfws()?;
+				}
+				_ => break,
+			}
+		}
+
+		match words.is_empty() {
+			true => Err(self.unexpected()),
+			false => Ok(words.join(" ")),
+		}
+	}
+
+	fn take_while<F: Fn(char) -> bool>(&mut self, accept: F) -> Option<&'a str> {
+		let end = self.rest.find(|c| !accept(c)).unwrap_or(self.rest.len());
+		if end == 0 {
+			return None;
+		}
+
+		let (taken, rest) = self.rest.split_at(end);
+		self.rest = rest;
+		Some(taken)
+	}
+}
+
+pub(crate) fn is_wsp(c: char) -> bool {
+	c == ' ' || c == '\t'
+}
+
+/// Characters that can be in an atom. RFC 6532 section 3.2 adds UTF-8.
+pub(crate) fn is_atext(c: char) -> bool {
+	c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+/// `s` as a quoted string, escaping what has to be
+pub(crate) fn quote(s: &str) -> String {
+	let mut quoted = String::with_capacity(s.len() + 2);
+	quoted.push('"');
+	for c in s.chars() {
+		if c == '"' || c == '\\' {
+			quoted.push('\\');
+		}
+		quoted.push(c);
+	}
+	quoted.push('"');
+	quoted
+}
+
+/// The contents of a quoted string, without the quotes and escapes
+pub(crate) fn unquote(quoted: &str) -> String {
+	let inner = &quoted[1..quoted.len() - 1];
+	let mut unquoted = String::with_capacity(inner.len());
+
+	let mut chars = inner.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => unquoted.extend(chars.next()),
+			c => unquoted.push(c),
+		}
+	}
+
+	unquoted
+}
@@ -0,0 +1,244 @@
+use thiserror::Error;
+
+use super::cursor::is_wsp;
+
+/// A header field, kept exactly as it was written (RFC 5322 section 2.2)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+	/// Everything before the colon. The obsolete syntax allows whitespace
+	/// before it (section 4.5).
+	name: String,
+	/// Everything after the colon up to the final CRLF, folding and all
+	body: Vec<u8>,
+}
+
+impl Field {
+	/// A field written as `Name: value`. The value must already be folded if
+	/// it's long.
+	pub fn new<N: Into<String>, V: AsRef<str>>(name: N, value: V) -> Self {
+		Self {
+			name: name.into(),
+			body: format!(" {}", value.as_ref()).into_bytes(),
+		}
+	}
+
+	pub fn name(&self) -> &str {
+		self.name.trim_end_matches(is_wsp)
+	}
+
+	/// Whether this field is called `name`. Field names ignore case.
+	pub fn is(&self, name: &str) -> bool {
+		self.name().eq_ignore_ascii_case(name)
+	}
+
+	/// The body as it was written, folding included
+	pub fn raw_body(&self) -> &[u8] {
+		&self.body
+	}
+
+	/// The body with the folding undone (section 2.2.3) and the whitespace
+	/// around it trimmed. Anything that isn't UTF-8 is replaced.
+	pub fn value(&self) -> String {
+		let body = String::from_utf8_lossy(&self.body);
+		let mut unfolded = String::with_capacity(body.len());
+
+		let mut rest = &body[..];
+		while let Some(end) = rest.find("\r\n") {
+			unfolded.push_str(&rest[..end]);
+			rest = &rest[end + 2..];
+		}
+		unfolded.push_str(rest);
+
+		unfolded.trim_matches(is_wsp).to_owned()
+	}
+
+	/// The field as it goes in a message, CRLF included
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(self.name.len() + self.body.len() + 3);
+		bytes.extend_from_slice(self.name.as_bytes());
+		bytes.push(b':');
+		bytes.extend_from_slice(&self.body);
+		bytes.extend_from_slice(b"\r\n");
+		bytes
+	}
+}
+
+/// The header section of a message. Fields keep the order they were written
+/// in, and looking one up ignores case.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+	fields: Vec<Field>,
+}
+
+impl Headers {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add a field written as `Name: value` to the end
+	pub fn push<N: Into<String>, V: AsRef<str>>(&mut self, name: N, value: V) {
+		self.fields.push(Field::new(name, value));
+	}
+
+	pub fn push_field(&mut self, field: Field) {
+		self.fields.push(field);
+	}
+
+	/// The first field called `name`
+	pub fn get(&self, name: &str) -> Option<&Field> {
+		self.fields.iter().find(|field| field.is(name))
+	}
+
+	/// Every field called `name`, like each Received field
+	pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Field> {
+		self.fields.iter().filter(move |field| field.is(name))
+	}
+
+	/// The unfolded value of the first field called `name`
+	pub fn value(&self, name: &str) -> Option<String> {
+		self.get(name).map(Field::value)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Field> {
+		self.fields.iter()
+	}
+
+	pub fn len(&self) -> usize {
+		self.fields.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.fields.is_empty()
+	}
+
+	/// Read the header section at the start of `bytes`. Returns the headers
+	/// and where the body starts, after the blank line. A message with no
+	/// blank line is all header.
+	pub fn parse(bytes: &[u8]) -> Result<(Self, usize), ParseHeaderError> {
+		let mut headers = Self::new();
+		let mut position = 0;
+
+		loop {
+			let rest = &bytes[position..];
+			if rest.is_empty() {
+				return Ok((headers, position));
+			}
+
+			if rest.starts_with(b"\r\n") {
+				return Ok((headers, position + 2));
+			}
+
+			if rest.starts_with(b" ") || rest.starts_with(b"\t") {
+				return Err(ParseHeaderError::FoldWithoutField);
+			}
+
+			let line_length = rest.iter().position(|b| *b == b'\n').map(|lf| lf + 1);
+			let colon = rest[..line_length.unwrap_or(rest.len())]
+				.iter()
+				.position(|b| *b == b':')
+				.ok_or(ParseHeaderError::NoColon(headers.len() + 1))?;
+
+			// Printable ASCII, but no colon (section 3.6.8)
+			let name = &rest[..colon];
+			let printable = name.trim_ascii_end();
+			if printable.is_empty() || !printable.iter().all(|b| (33..=126).contains(b)) {
+				return Err(ParseHeaderError::InvalidFieldName(
+					String::from_utf8_lossy(name).into_owned(),
+				));
+			}
+
+			// The field goes on for as long as lines start with whitespace
+			let mut end = colon + 1;
+			let body_end = loop {
+				match rest[end..].windows(2).position(|pair| pair == b"\r\n") {
+					Some(crlf) => {
+						let next = end + crlf + 2;
+						match rest.get(next) {
+							Some(b' ' | b'\t') => end = next,
+							_ => break end + crlf,
+						}
+					}
+					None => break rest.len(),
+				}
+			};
+
+			headers.fields.push(Field {
+				// Only ASCII made it this far
+				name: String::from_utf8_lossy(name).into_owned(),
+				body: rest[colon + 1..body_end].to_vec(),
+			});
+			position += (body_end + 2).min(rest.len());
+		}
+	}
+
+	/// The header section as it goes in a message, without the blank line
+	/// that ends it
+	pub fn to_bytes(&self) -> Vec<u8> {
+		self.fields.iter().flat_map(Field::to_bytes).collect()
+	}
+}
+
+impl<'a> IntoIterator for &'a Headers {
+	type Item = &'a Field;
+	type IntoIter = std::slice::Iter<'a, Field>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.fields.iter()
+	}
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseHeaderError {
+	#[error("header field {0} has no colon")]
+	NoColon(usize),
+	#[error("'{0}' isn't a valid field name")]
+	InvalidFieldName(String),
+	#[error("the header section starts with a folded line")]
+	FoldWithoutField,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn folding() {
+		let raw = b"Subject: a long\r\n\tsubject\r\nX-Empty:\r\nfrom : obs\r\n\r\nbody\r\n";
+		let (headers, body) = Headers::parse(raw).unwrap();
+
+		assert_eq!(headers.len(), 3);
+		assert_eq!(&raw[body..], b"body\r\n");
+		assert_eq!(headers.value("subject").unwrap(), "a long\tsubject");
+		assert_eq!(headers.value("X-EMPTY").unwrap(), "");
+		assert_eq!(headers.get("From").unwrap().name(), "from");
+		assert_eq!(headers.to_bytes(), &raw[..body - 2]);
+	}
+
+	#[test]
+	fn ordered_lookup() {
+		let raw = b"Received: one\r\nSubject: hi\r\nreceived: two\r\n";
+		let (headers, body) = Headers::parse(raw).unwrap();
+
+		assert_eq!(body, raw.len());
+		let received: Vec<String> = headers.get_all("RECEIVED").map(Field::value).collect();
+		assert_eq!(received, ["one", "two"]);
+		assert_eq!(headers.value("Received").unwrap(), "one");
+		assert!(headers.get("To").is_none());
+	}
+
+	#[test]
+	fn malformed() {
+		assert_eq!(
+			Headers::parse(b" folded\r\n"),
+			Err(ParseHeaderError::FoldWithoutField)
+		);
+		assert_eq!(
+			Headers::parse(b"Subject: hi\r\nno colon here\r\n\r\n"),
+			Err(ParseHeaderError::NoColon(2))
+		);
+		assert!(matches!(
+			Headers::parse(b"Bad Name: hi\r\n"),
+			Err(ParseHeaderError::InvalidFieldName(_))
+		));
+	}
+}
@@ -0,0 +1,28 @@
+//! The Internet Message Format (RFC 5322): header fields and the structured
+//! values some of them hold. See IMF.md for notes on the RFC.
+
+mod address;
+mod cursor;
+mod field;
+mod msgid;
+
+pub use address::*;
+pub use field::*;
+pub use msgid::*;
+
+use thiserror::Error;
+
+/// Why a structured field body couldn't be parsed
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseFieldError {
+	#[error("unexpected end of field")]
+	UnexpectedEnd,
+	#[error("unexpected '{0}'")]
+	Unexpected(char),
+	#[error("unclosed comment")]
+	UnclosedComment,
+	#[error("unclosed quoted string")]
+	UnclosedQuote,
+	#[error("groups aren't allowed here")]
+	GroupNotAllowed,
+}
@@ -0,0 +1,84 @@
+use std::{fmt, str::FromStr};
+
+use super::{cursor::Cursor, ParseFieldError};
+
+/// A message identifier, like `<1234@nyble.dev>` (RFC 5322 section 3.6.4)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MessageId {
+	pub left: String,
+	pub right: String,
+}
+
+impl MessageId {
+	fn parse(cursor: &mut Cursor) -> Result<Self, ParseFieldError> {
+		cursor.skip_cfws()?;
+		cursor.expect('<')?;
+
+		let left = cursor.dot_atom_text().ok_or(cursor.unexpected())?;
+		cursor.expect('@')?;
+		let right = match cursor.domain_literal()? {
+			Some(literal) => literal,
+			None => cursor.dot_atom_text().ok_or(cursor.unexpected())?,
+		};
+
+		cursor.expect('>')?;
+		cursor.skip_cfws()?;
+
+		Ok(Self {
+			left: left.to_owned(),
+			right: right.to_owned(),
+		})
+	}
+}
+
+/// One or more message identifiers, like In-Reply-To and References hold
+pub fn message_ids(s: &str) -> Result<Vec<MessageId>, ParseFieldError> {
+	let mut cursor = Cursor::new(s);
+	let mut ids = vec![MessageId::parse(&mut cursor)?];
+
+	while !cursor.is_empty() {
+		ids.push(MessageId::parse(&mut cursor)?);
+	}
+
+	Ok(ids)
+}
+
+impl FromStr for MessageId {
+	type Err = ParseFieldError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut cursor = Cursor::new(s);
+		let id = Self::parse(&mut cursor)?;
+
+		match cursor.is_empty() {
+			true => Ok(id),
+			false => Err(cursor.unexpected()),
+		}
+	}
+}
+
+impl fmt::Display for MessageId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<{}@{}>", self.left, self.right)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn ids() {
+		let id: MessageId = " <1234.5@nyble.dev> (sent)".parse().unwrap();
+		assert_eq!(id.left, "1234.5");
+		assert_eq!(id.to_string(), "<1234.5@nyble.dev>");
+
+		let ids = message_ids("<a@b>\t<c@[192.0.2.1]>").unwrap();
+		assert_eq!(ids.len(), 2);
+		assert_eq!(ids[1].right, "[192.0.2.1]");
+
+		assert!("<a@b".parse::<MessageId>().is_err());
+		assert!("a@b".parse::<MessageId>().is_err());
+		assert!(message_ids("").is_err());
+	}
+}
@@ -1,3 +1,4 @@
+pub mod imf;
 pub mod net;
 pub mod policy;
 pub mod smtp;
@@ -1,68 +1,290 @@
-use std::net::IpAddr;
+use std::{collections::HashMap, fmt, future::Future, net::IpAddr, pin::Pin};
 
-use hickory_resolver::{ResolveError, Resolver};
+use hickory_resolver::{ResolveError, TokioResolver};
 use thiserror::Error;
 
-pub struct DnsLookup {
+/// A lookup in progress
+pub type Lookup<'a, T> = Pin<Box<dyn Future<Output = Result<T, DnsLookupError>> + Send + 'a>>;
+
+/// Where DNS answers come from. Names that have no records of the type asked
+/// for give an empty list rather than an error, so errors mean the lookup
+/// itself failed and might work later.
+pub trait Resolver: fmt::Debug + Send + Sync {
+	/// The MX records of a domain as preference and exchange
+	fn mx<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<(u16, String)>>;
+	/// The A and AAAA records of a name
+	fn ip<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<IpAddr>>;
+	/// The TXT records of a name, with each record's strings joined together
+	fn txt<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<String>>;
+	/// The names an address points back to
+	fn ptr(&self, ip: IpAddr) -> Lookup<'_, Vec<String>>;
+}
+
+/// Lookups through hickory with the system's resolver configuration. Clones
+/// share the same cache, so make one and pass it around.
+#[derive(Clone)]
+pub struct HickoryResolver {
+	resolver: TokioResolver,
+}
+
+impl HickoryResolver {
+	pub fn new() -> Result<Self, DnsLookupError> {
+		Ok(Self {
+			resolver: TokioResolver::builder_tokio()?.build(),
+		})
+	}
+
+	/// An empty answer is not an error for us
+	fn records<T>(result: Result<Vec<T>, ResolveError>) -> Result<Vec<T>, DnsLookupError> {
+		match result {
+			Ok(records) => Ok(records),
+			Err(e) if e.is_no_records_found() => Ok(vec![]),
+			Err(e) => Err(e.into()),
+		}
+	}
+}
+
+impl fmt::Debug for HickoryResolver {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("HickoryResolver").finish_non_exhaustive()
+	}
+}
+
+impl Resolver for HickoryResolver {
+	fn mx<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<(u16, String)>> {
+		Box::pin(async move {
+			Self::records(self.resolver.mx_lookup(name).await.map(|lookup| {
+				lookup
+					.iter()
+					.map(|mx| (mx.preference(), mx.exchange().to_string()))
+					.collect()
+			}))
+		})
+	}
+
+	fn ip<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<IpAddr>> {
+		Box::pin(async move {
+			Self::records(
+				self.resolver
+					.lookup_ip(name)
+					.await
+					.map(|lookup| lookup.iter().collect()),
+			)
+		})
+	}
+
+	fn txt<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<String>> {
+		Box::pin(async move {
+			Self::records(
+				self.resolver
+					.txt_lookup(name)
+					.await
+					.map(|lookup| lookup.iter().map(|txt| txt.to_string()).collect()),
+			)
+		})
+	}
+
+	fn ptr(&self, ip: IpAddr) -> Lookup<'_, Vec<String>> {
+		Box::pin(async move {
+			Self::records(
+				self.resolver
+					.reverse_lookup(ip)
+					.await
+					.map(|lookup| lookup.iter().map(|ptr| ptr.to_string()).collect()),
+			)
+		})
+	}
+}
+
+/// Answers from memory, for tests and anywhere else the network shouldn't be
+/// touched. Names are matched without case or a trailing dot.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+	mx: HashMap<String, Vec<(u16, String)>>,
+	ip: HashMap<String, Vec<IpAddr>>,
+	txt: HashMap<String, Vec<String>>,
+	ptr: HashMap<IpAddr, Vec<String>>,
+	/// Names that fail to resolve for every record type
+	failing: Vec<String>,
+}
+
+impl StaticResolver {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn with_mx<N: AsRef<str>, E: Into<String>>(
+		mut self,
+		name: N,
+		preference: u16,
+		exchange: E,
+	) -> Self {
+		self.mx
+			.entry(Self::key(name.as_ref()))
+			.or_default()
+			.push((preference, exchange.into()));
+		self
+	}
+
+	pub fn with_ip<N: AsRef<str>>(mut self, name: N, ip: IpAddr) -> Self {
+		self.ip
+			.entry(Self::key(name.as_ref()))
+			.or_default()
+			.push(ip);
+		self
+	}
+
+	pub fn with_txt<N: AsRef<str>, T: Into<String>>(mut self, name: N, txt: T) -> Self {
+		self.txt
+			.entry(Self::key(name.as_ref()))
+			.or_default()
+			.push(txt.into());
+		self
+	}
+
+	pub fn with_ptr<N: Into<String>>(mut self, ip: IpAddr, name: N) -> Self {
+		self.ptr.entry(ip).or_default().push(name.into());
+		self
+	}
+
+	/// Make every lookup of `name` fail like the server couldn't be reached
+	pub fn with_failure<N: AsRef<str>>(mut self, name: N) -> Self {
+		self.failing.push(Self::key(name.as_ref()));
+		self
+	}
+
+	fn key(name: &str) -> String {
+		name.trim_end_matches('.').to_lowercase()
+	}
+
+	fn answer<T: Clone>(
+		&self,
+		records: &HashMap<String, Vec<T>>,
+		name: &str,
+	) -> Result<Vec<T>, DnsLookupError> {
+		let key = Self::key(name);
+		if self.failing.contains(&key) {
+			return Err(ResolveError::from(format!("lookup of {} failed", name)).into());
+		}
+
+		Ok(records.get(&key).cloned().unwrap_or_default())
+	}
+}
+
+impl Resolver for StaticResolver {
+	fn mx<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<(u16, String)>> {
+		Box::pin(async move { self.answer(&self.mx, name) })
+	}
+
+	fn ip<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<IpAddr>> {
+		Box::pin(async move { self.answer(&self.ip, name) })
+	}
+
+	fn txt<'a>(&'a self, name: &'a str) -> Lookup<'a, Vec<String>> {
+		Box::pin(async move { self.answer(&self.txt, name) })
+	}
+
+	fn ptr(&self, ip: IpAddr) -> Lookup<'_, Vec<String>> {
+		Box::pin(async move { Ok(self.ptr.get(&ip).cloned().unwrap_or_default()) })
+	}
+}
+
+pub struct DnsLookup<'r> {
+	resolver: &'r dyn Resolver,
 	/// A Vec containing possible mail server names. It is sorted in reverse
 	/// order of preference. The least preferred servers are at the front of the
 	/// Vec. This lets you use Vec::pop to get the next preferred server.
 	mx_records: Vec<String>,
 	/// A Vec containing possible IP addresses of the last popped domain.
 	ip_addresses: Vec<IpAddr>,
+	/// The mail server the addresses belong to
+	exchange: String,
+	/// Why the last mail server we couldn't get addresses for failed
+	last_error: Option<DnsLookupError>,
 }
 
-impl DnsLookup {
-	pub async fn new(fqdn: &str) -> Result<Self, DnsLookupError> {
-		let resolver = Resolver::builder_tokio().unwrap().build();
+impl<'r> DnsLookup<'r> {
+	pub async fn new(resolver: &'r dyn Resolver, fqdn: &str) -> Result<Self, DnsLookupError> {
+		let mx_rec = resolver.mx(fqdn).await?;
 
-		match resolver.mx_lookup(fqdn).await {
-			Ok(mxlookup) => {
-				let mut mx_rec: Vec<(u16, String)> = mxlookup
-					.iter()
-					.map(|mx| (mx.preference(), mx.exchange().to_string()))
-					.collect();
+		if mx_rec.is_empty() {
+			// No MX means the domain itself is the mail server (RFC 5321
+			// section 5.1)
+			return Self::host(resolver, fqdn).await;
+		}
 
-				mx_rec.sort_by(|(pref1, _), (pref2, _)| pref1.cmp(pref2).reverse());
+		Ok(Self {
+			resolver,
+			mx_records: Self::order_exchanges(mx_rec)?,
+			ip_addresses: vec![],
+			exchange: String::new(),
+			last_error: None,
+		})
+	}
 
-				Ok(Self {
-					mx_records: mx_rec.into_iter().map(|(_, domain)| domain).collect(),
-					ip_addresses: vec![],
-				})
-			}
+	/// Only the addresses of a host, for when we already know which server
+	/// we're talking to
+	pub async fn host(resolver: &'r dyn Resolver, fqdn: &str) -> Result<Self, DnsLookupError> {
+		let mut ip_addresses = resolver.ip(fqdn).await?;
+		ip_addresses.reverse();
 
-			Err(err) => {
-				if err.is_no_records_found() {
-					Ok(Self {
-						mx_records: vec![],
-						ip_addresses: Self::get_addresses(fqdn).await?,
-					})
-				} else {
-					todo!()
-				}
-			}
+		Ok(Self {
+			resolver,
+			mx_records: vec![],
+			ip_addresses,
+			exchange: fqdn.to_owned(),
+			last_error: None,
+		})
+	}
+
+	/// Sort the exchanges so the most preferred is at the end, or fail if the
+	/// domain has a null MX and doesn't accept mail at all (RFC 7505)
+	fn order_exchanges(mut mx_rec: Vec<(u16, String)>) -> Result<Vec<String>, DnsLookupError> {
+		if mx_rec.iter().any(|(_, exchange)| exchange == ".") {
+			return Err(DnsLookupError::NullMx);
 		}
+
+		mx_rec.sort_by(|(pref1, _), (pref2, _)| pref1.cmp(pref2).reverse());
+		Ok(mx_rec.into_iter().map(|(_, domain)| domain).collect())
+	}
+
+	/// The name of the mail server the last address came from
+	pub fn exchange(&self) -> &str {
+		self.exchange.trim_end_matches('.')
 	}
 
+	/// The next address to try, going through every address of every mail
+	/// server in order of preference. Mail servers we can't resolve are
+	/// skipped. Once they've all been tried, this returns the last lookup
+	/// error, or [DnsLookupError::NoMoreRecords] if there wasn't one.
 	pub async fn next_address(&mut self) -> Result<IpAddr, DnsLookupError> {
 		loop {
 			match self.ip_addresses.pop() {
 				Some(addr) => return Ok(addr),
 				None => {
-					let domain = self.mx_records.pop().ok_or(DnsLookupError::NoMoreRecords)?;
-					self.ip_addresses = Self::get_addresses(&domain).await?;
-					continue;
+					let domain = match self.mx_records.pop() {
+						Some(domain) => domain,
+						None => {
+							return Err(self
+								.last_error
+								.take()
+								.unwrap_or(DnsLookupError::NoMoreRecords))
+						}
+					};
+
+					match self.resolver.ip(&domain).await {
+						Ok(mut addresses) => {
+							// Try them in the order they were given
+							addresses.reverse();
+							self.ip_addresses = addresses;
+							self.exchange = domain;
+						}
+						Err(e) => self.last_error = Some(e),
+					}
 				}
 			}
 		}
 	}
-
-	async fn get_addresses(fqdn: &str) -> Result<Vec<IpAddr>, DnsLookupError> {
-		let resolver = Resolver::builder_tokio().unwrap().build();
-
-		let ip = resolver.lookup_ip(fqdn).await?;
-		Ok(ip.iter().collect())
-	}
 }
 
 #[derive(Debug, Error)]
@@ -71,4 +293,103 @@ pub enum DnsLookupError {
 	ResolveError(#[from] ResolveError),
 	#[error("no more MX records to check")]
 	NoMoreRecords,
+	#[error("the domain does not accept mail (null MX)")]
+	NullMx,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	async fn addresses(resolver: &StaticResolver, domain: &str) -> Vec<(String, IpAddr)> {
+		let mut lookup = DnsLookup::new(resolver, domain).await.unwrap();
+		let mut addresses = vec![];
+		while let Ok(addr) = lookup.next_address().await {
+			addresses.push((lookup.exchange().to_owned(), addr));
+		}
+		addresses
+	}
+
+	#[test]
+	fn exchange_order() {
+		let ordered = DnsLookup::order_exchanges(vec![
+			(20, String::from("backup.example.")),
+			(5, String::from("mx1.example.")),
+			(10, String::from("mx2.example.")),
+		])
+		.unwrap();
+
+		assert_eq!(
+			ordered,
+			vec!["backup.example.", "mx2.example.", "mx1.example."]
+		);
+	}
+
+	#[tokio::test]
+	async fn every_address() {
+		let resolver = StaticResolver::new()
+			.with_mx("b.example", 20, "backup.b.example.")
+			.with_mx("b.example", 10, "mx.b.example.")
+			.with_mx("b.example", 15, "down.b.example.")
+			.with_ip("mx.b.example", ip("192.0.2.1"))
+			.with_ip("mx.b.example", ip("2001:db8::1"))
+			.with_failure("down.b.example")
+			.with_ip("backup.b.example", ip("192.0.2.9"));
+
+		assert_eq!(
+			addresses(&resolver, "B.example.").await,
+			vec![
+				(String::from("mx.b.example"), ip("192.0.2.1")),
+				(String::from("mx.b.example"), ip("2001:db8::1")),
+				(String::from("backup.b.example"), ip("192.0.2.9"))
+			]
+		);
+
+		// Without MX records the domain is its own mail server
+		let resolver = StaticResolver::new().with_ip("c.example", ip("192.0.2.3"));
+		assert_eq!(
+			addresses(&resolver, "c.example.").await,
+			vec![(String::from("c.example"), ip("192.0.2.3"))]
+		);
+
+		// We only hear about a lookup failure if nothing else worked
+		let resolver = StaticResolver::new()
+			.with_mx("d.example", 10, "mx.d.example.")
+			.with_failure("mx.d.example");
+		let mut lookup = DnsLookup::new(&resolver, "d.example").await.unwrap();
+		assert!(matches!(
+			lookup.next_address().await,
+			Err(DnsLookupError::ResolveError(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn null_mx() {
+		let resolver = StaticResolver::new().with_mx("e.example", 0, ".");
+		assert!(matches!(
+			DnsLookup::new(&resolver, "e.example").await,
+			Err(DnsLookupError::NullMx)
+		));
+	}
+
+	#[tokio::test]
+	async fn other_records() {
+		let resolver = StaticResolver::new()
+			.with_txt("f.example", "v=spf1 -all")
+			.with_ptr(ip("192.0.2.1"), "mx.f.example.");
+
+		assert_eq!(
+			resolver.txt("F.example.").await.unwrap(),
+			vec!["v=spf1 -all"]
+		);
+		assert!(resolver.txt("g.example").await.unwrap().is_empty());
+		assert_eq!(
+			resolver.ptr(ip("192.0.2.1")).await.unwrap(),
+			vec!["mx.f.example."]
+		);
+	}
 }
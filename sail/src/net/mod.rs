@@ -1,73 +1,332 @@
-use std::{net::IpAddr, time::Duration};
+use std::{
+	io::{self, Read, Seek, SeekFrom},
+	net::SocketAddr,
+	str::FromStr,
+	time::{Duration, SystemTime},
+};
 
 use thiserror::Error;
 use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt},
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 	net::TcpStream,
 	time::{error::Elapsed, timeout},
 };
+use tokio_rustls::rustls::pki_types::ServerName;
 
-use crate::smtp::{args::Domain, Client, Envelope, ForeignEnvelope, Message};
+use crate::smtp::{
+	args::{Domain, ParseDomainError},
+	Client, Command, Credentials, DataEncoder, ForeignEnvelope, Output,
+};
 
-use self::dns::DnsLookup;
+use self::dns::{DnsLookup, DnsLookupError, Resolver};
 
 pub mod dns;
+mod report;
+pub mod tls;
+
+pub use report::{DeliveryReport, DeliveryStatus, RecipientOutcome};
+
+/// Which way mail for a domain leaves
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Route {
+	/// Straight to the domain's mail servers, found through its MX records
+	#[default]
+	Mx,
+	/// Through another server that relays it for us, like when port 25 is
+	/// blocked
+	Relayhost(Relayhost),
+}
+
+/// A fixed server to hand mail to instead of looking up MX records
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relayhost {
+	pub host: Domain,
+	pub port: u16,
+	/// What to AUTH with. These are only ever sent over TLS.
+	pub credentials: Option<Credentials>,
+	/// Whether to give up rather than relay in plaintext
+	pub require_tls: bool,
+}
+
+impl Relayhost {
+	fn client(&self, message: ForeignEnvelope) -> Client {
+		let client = match &self.credentials {
+			Some(credentials) => Client::initiate(message).with_credentials(credentials.clone()),
+			None => Client::initiate(message),
+		};
+
+		match self.require_tls {
+			true => client.require_tls(),
+			false => client,
+		}
+	}
+}
+
+/// Parses `host` or `host:port`, where the host can be an address literal
+/// like `[192.0.2.1]`. The port defaults to 25.
+impl FromStr for Relayhost {
+	type Err = ParseRelayhostError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		// Address literals can have colons in them, so look past them
+		let host_end = match s.starts_with('[') {
+			true => s.find(']').map(|end| end + 1).unwrap_or(s.len()),
+			false => s.find(':').unwrap_or(s.len()),
+		};
+
+		let (host, port) = s.split_at(host_end);
+		let port = match port.strip_prefix(':') {
+			Some(port) => port
+				.parse()
+				.map_err(|_| ParseRelayhostError::InvalidPort(port.to_owned()))?,
+			None if port.is_empty() => 25,
+			None => return Err(ParseRelayhostError::InvalidPort(port.to_owned())),
+		};
 
+		Ok(Self {
+			host: host.parse()?,
+			port,
+			credentials: None,
+			require_tls: false,
+		})
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum ParseRelayhostError {
+	#[error("invalid relay host: {0}")]
+	InvalidHost(#[from] ParseDomainError),
+	#[error("'{0}' is not a port")]
+	InvalidPort(String),
+}
+
+/// Where the message is read from as it's sent, like the file it's spooled
+/// in. It's read from the start for every server we try.
+pub trait MessageSource: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> MessageSource for T {}
+
+/// Try to deliver the message to the domain's mail server. Once we've talked
+/// to a server, what it did with each recipient is in the [DeliveryReport].
+/// Errors mean we never got that far; see [RelayError::is_transient] for
+/// which are worth trying again later.
+///
+/// The message is streamed from `data`. The envelope's data only needs the
+/// header section.
 pub async fn relay(
+	resolver: &dyn Resolver,
+	route: &Route,
 	domain: Domain,
 	message: ForeignEnvelope,
+	data: &mut dyn MessageSource,
 	// rx: watch::Receiver<bool>,
-) -> Option<Envelope> {
-	let _sender = message.reverse_path.clone();
-	match run(domain, message /*, rx*/).await {
-		Ok(_) => None,
-		Err(_err) => None,
-	}
+) -> Result<DeliveryReport, RelayError> {
+	run(resolver, route, domain, message, data /*, rx*/).await
 }
 
 async fn run(
+	resolver: &dyn Resolver,
+	route: &Route,
 	domain: Domain,
 	message: ForeignEnvelope,
+	data: &mut dyn MessageSource,
 	// rx: watch::Receiver<bool>,
-) -> Result<(), RelayError> {
+) -> Result<DeliveryReport, RelayError> {
+	if message.forward_paths.is_empty() {
+		return Err(RelayError::NoForwardPaths);
+	}
+
 	for path in &message.forward_paths {
 		if path.0.domain != domain {
 			return Err(RelayError::MismatchedDomains);
 		}
 	}
 
-	let ip = match domain {
-		Domain::FQDN(domain) => DnsLookup::new(&format!("{}.", domain))
-			.await
-			.unwrap()
-			.next_address()
-			.await
-			.unwrap(),
-		Domain::Literal(ip) => ip,
+	let (host, port) = match route {
+		Route::Mx => (domain, 25),
+		Route::Relayhost(relayhost) => (relayhost.host.clone(), relayhost.port),
+	};
+	let client = || match route {
+		Route::Mx => Client::initiate(message.clone()),
+		Route::Relayhost(relayhost) => relayhost.client(message.clone()),
+	};
+
+	// DNS only knows names as A-labels
+	let host = host.to_ascii().unwrap_or(host);
+	let verify_as = match route {
+		Route::Mx => None,
+		Route::Relayhost(_) => Some(server_name(&host)?),
+	};
+	let mut lookup = match (route, host) {
+		(_, Domain::Literal(ip)) => {
+			let host = Domain::Literal(ip).to_string();
+			return send_to(
+				host,
+				SocketAddr::new(ip, port),
+				verify_as,
+				client(),
+				data, /*, rx*/
+			)
+			.await;
+		}
+		(Route::Mx, Domain::FQDN(domain)) => {
+			DnsLookup::new(resolver, &format!("{}.", domain)).await?
+		}
+		(Route::Relayhost(_), Domain::FQDN(host)) => {
+			DnsLookup::host(resolver, &format!("{}.", host)).await?
+		}
 	};
 
-	send_to_ip(ip, message /*, rx*/).await
+	// Try every address of every mail server until one of them takes the
+	// message, or does something final with it (RFC 5321 section 5.1)
+	let mut last = None;
+	loop {
+		let ip = match lookup.next_address().await {
+			Ok(ip) => ip,
+			Err(e) => return last.unwrap_or(Err(e.into())),
+		};
+
+		let host = lookup.exchange().to_owned();
+		let result = send_to(
+			host,
+			SocketAddr::new(ip, port),
+			verify_as.clone(),
+			client(),
+			data, /*, rx*/
+		)
+		.await;
+		if !should_try_next(&result) {
+			return result;
+		}
+
+		match &result {
+			Ok(report) => eprintln!("{}", report),
+			Err(e) => eprintln!("Failed to relay to {}: {}", ip, e),
+		}
+		last = Some(result);
+	}
 }
 
-async fn send_to_ip(
-	addr: IpAddr,
-	message: ForeignEnvelope,
+/// Whether another server might do better. That's when we couldn't talk to
+/// this one, or it put off everyone before the message was accepted, like
+/// with a 4xx greeting.
+fn should_try_next(result: &Result<DeliveryReport, RelayError>) -> bool {
+	match result {
+		Ok(report) => report.all_deferred(),
+		Err(
+			RelayError::ConnectionTimeout(_)
+			| RelayError::ConnectionClosed
+			| RelayError::ConnectionError(_)
+			| RelayError::TlsHandshake(_),
+		) => true,
+		Err(_) => false,
+	}
+}
+
+/// How long to wait for the greeting and the reply to each command (RFC 5321
+/// section 4.5.3.2)
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long to wait for the reply to the message, which the server might be
+/// processing before it answers
+const MESSAGE_REPLY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How long each block we write can take, so large messages get more time
+/// overall but a stalled server is still noticed
+const WRITE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+const WRITE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// The name a relayhost's certificate has to be for
+fn server_name(host: &Domain) -> Result<ServerName<'static>, RelayError> {
+	match host {
+		Domain::Literal(ip) => Ok(ServerName::IpAddress((*ip).into())),
+		Domain::FQDN(name) => ServerName::try_from(name.clone())
+			.map_err(|_| RelayError::UnverifiableHost(name.clone())),
+	}
+}
+
+/// Deliver to one server. If `verify_as` is given, the server's certificate
+/// has to be valid for that name, and a failed handshake ends the
+/// connection before anything else is sent. Otherwise STARTTLS is
+/// opportunistic and any certificate will do, and if the handshake fails we
+/// connect again and send in plaintext.
+async fn send_to(
+	host: String,
+	addr: SocketAddr,
+	verify_as: Option<ServerName<'static>>,
+	mut client: Client,
+	data: &mut dyn MessageSource,
 	// mut rx: watch::Receiver<bool>,
+) -> Result<DeliveryReport, RelayError> {
+	let started = SystemTime::now();
+	let plaintext = client.clone().without_starttls();
+	let mut result = talk(addr, verify_as.clone(), &mut client, data).await;
+
+	if verify_as.is_none() {
+		if let Err(RelayError::TlsHandshake(e)) = &result {
+			eprintln!("TLS with {} failed, sending in plaintext: {}", host, e);
+			client = plaintext;
+			result = talk(addr, None, &mut client, data).await;
+		}
+	}
+
+	match result {
+		Err(e) if client.accepted_reply().is_none() => Err(e),
+		// Once the message has been accepted, losing the connection doesn't
+		// change that, and trying again would deliver it twice
+		Err(e) => {
+			eprintln!("Lost {} after the message was accepted: {}", host, e);
+			Ok(DeliveryReport::from_client(&client, host, addr, started))
+		}
+		Ok(()) => Ok(DeliveryReport::from_client(&client, host, addr, started)),
+	}
+}
+
+/// Connect and see the conversation through, starting TLS if the client
+/// wants to
+async fn talk(
+	addr: SocketAddr,
+	verify_as: Option<ServerName<'static>>,
+	client: &mut Client,
+	data: &mut dyn MessageSource,
 ) -> Result<(), RelayError> {
-	println!("{}:{}", addr, 25);
 	//todo: send failed connection message if port 25 is blocked, or something
-	let mut stream = timeout(
-		Duration::from_millis(2500),
-		TcpStream::connect(format!("{}:{}", addr, 25)),
-	)
-	.await??;
+	let mut stream = timeout(Duration::from_millis(2500), TcpStream::connect(addr)).await??;
 
-	let mut client = Client::initiate(message.clone());
+	converse(&mut stream, client, data).await?;
 
+	if client.should_start_tls() {
+		let (connector, name) = match verify_as {
+			Some(name) => (tls::verified_connector(), name),
+			None => (
+				tls::opportunistic_connector(),
+				ServerName::IpAddress(addr.ip().into()),
+			),
+		};
+		let mut stream = timeout(REPLY_TIMEOUT, connector.connect(name, stream))
+			.await
+			.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+			.map_err(RelayError::TlsHandshake)?;
+
+		let ehlo = client.tls_started();
+		write(&mut stream, &ehlo.to_bytes()).await?;
+
+		converse(&mut stream, client, data).await?;
+	}
+
+	Ok(())
+}
+
+/// Pass replies to the client and write its commands back until it's finished
+/// or wants to start TLS.
+async fn converse<S: AsyncRead + AsyncWrite + Unpin>(
+	stream: &mut S,
+	client: &mut Client,
+	data: &mut dyn MessageSource,
+) -> Result<(), RelayError> {
 	let mut buf = vec![0; 1024];
+	let mut wait = REPLY_TIMEOUT;
 
-	while !client.should_exit() {
-		let read = stream.read(&mut buf).await?;
+	while !client.should_exit() && !client.should_start_tls() {
+		let read = timeout(wait, stream.read(&mut buf)).await??;
 		/*tokio::select! {
 			_ = rx.changed() => {
 				timeout(
@@ -86,20 +345,74 @@ async fn send_to_ip(
 			return Err(RelayError::ConnectionClosed);
 		}
 
-		println!("{}", String::from_utf8_lossy(&buf[..read]));
-		let command = client.push(String::from_utf8_lossy(&buf[..read]).as_ref());
+		let command = client.push(&buf[..read]);
 
 		if let Some(command) = command {
-			println!("{}", command);
-			timeout(
-				Duration::from_millis(500),
-				stream.write_all(command.to_string().as_bytes()),
-			)
-			.await??;
+			wait = match command.is_message() {
+				true => MESSAGE_REPLY_TIMEOUT,
+				false => REPLY_TIMEOUT,
+			};
+			send(stream, &command, data).await?;
 		}
 	}
 
-	Err(RelayError::UndeliverableMail(client.undeliverable()))
+	Ok(())
+}
+
+/// Write what the client asked for. The message is read from `data` a block
+/// at a time, so it never has to be in memory all at once.
+async fn send<S: AsyncWrite + Unpin>(
+	stream: &mut S,
+	output: &Output,
+	data: &mut dyn MessageSource,
+) -> Result<(), RelayError> {
+	let mut block = vec![0; WRITE_BLOCK_SIZE];
+	match output {
+		Output::Data => {
+			data.rewind()?;
+
+			let mut encoder = DataEncoder::default();
+			loop {
+				let read = data.read(&mut block)?;
+				if read == 0 {
+					break;
+				}
+				write(stream, &encoder.encode(&block[..read])).await?;
+			}
+
+			write(stream, encoder.finish()).await
+		}
+		Output::Bdat => {
+			let size = data.seek(SeekFrom::End(0))?;
+			data.rewind()?;
+
+			let command = Command::Bdat {
+				size: size as usize,
+				last: true,
+			};
+			write(stream, format!("{}\r\n", command).as_bytes()).await?;
+
+			loop {
+				let read = data.read(&mut block)?;
+				if read == 0 {
+					break;
+				}
+				write(stream, &block[..read]).await?;
+			}
+
+			Ok(())
+		}
+		output => write(stream, &output.to_bytes()).await,
+	}
+}
+
+/// Write everything, a block at a time
+async fn write<S: AsyncWrite + Unpin>(stream: &mut S, bytes: &[u8]) -> Result<(), RelayError> {
+	for block in bytes.chunks(WRITE_BLOCK_SIZE) {
+		timeout(WRITE_TIMEOUT, stream.write_all(block)).await??;
+	}
+
+	Ok(())
 }
 
 #[derive(Debug, Error)]
@@ -108,12 +421,195 @@ pub enum RelayError {
 	NoForwardPaths,
 	#[error("there were forward paths with more than one domain")]
 	MismatchedDomains,
-	#[error("timed out before reaching the server")]
+	#[error("timed out talking to the server")]
 	ConnectionTimeout(#[from] Elapsed),
 	#[error("Connection unexpectedly closed by server")]
 	ConnectionClosed,
 	#[error("there was an error connecting to the host")]
 	ConnectionError(#[from] std::io::Error),
-	#[error("Undeliverable mail")]
-	UndeliverableMail(Option<Message>),
+	#[error("DNS lookup failed: {0}")]
+	Dns(#[from] DnsLookupError),
+	#[error("'{0}' can't be checked against a certificate")]
+	UnverifiableHost(String),
+	#[error("the TLS handshake failed: {0}")]
+	TlsHandshake(std::io::Error),
+}
+
+impl RelayError {
+	/// True if the same message might be delivered if we try again later.
+	/// RFC 5321 section 4.5.4.1 says to keep trying for a few days.
+	pub fn is_transient(&self) -> bool {
+		match self {
+			RelayError::ConnectionTimeout(_)
+			| RelayError::ConnectionClosed
+			| RelayError::ConnectionError(_)
+			| RelayError::TlsHandshake(_) => true,
+			// A domain with no records isn't going to grow some
+			RelayError::Dns(DnsLookupError::ResolveError(e)) => !e.is_no_records_found(),
+			RelayError::Dns(DnsLookupError::NoMoreRecords | DnsLookupError::NullMx) => false,
+			RelayError::NoForwardPaths
+			| RelayError::MismatchedDomains
+			| RelayError::UnverifiableHost(_) => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::smtp::{args::ForeignPath, Extensions, Response, ResponseCode};
+
+	fn report(statuses: &[DeliveryStatus]) -> DeliveryReport {
+		DeliveryReport {
+			host: String::from("mx.b.example"),
+			address: "192.0.2.1:25".parse().unwrap(),
+			extensions: Extensions::new(),
+			recipients: statuses
+				.iter()
+				.map(|status| RecipientOutcome {
+					recipient: ForeignPath("<a@b.example>".parse().unwrap()),
+					status: *status,
+					reply: Response::new(ResponseCode::ServiceNotAvailable),
+				})
+				.collect(),
+			started: SystemTime::now(),
+			finished: SystemTime::now(),
+		}
+	}
+
+	#[test]
+	fn relayhosts() {
+		let relayhost: Relayhost = "smtp.example.net:587".parse().unwrap();
+		assert_eq!(relayhost.host, Domain::FQDN("smtp.example.net".into()));
+		assert_eq!(relayhost.port, 587);
+
+		let relayhost: Relayhost = "[IPv6:2001:db8::1]".parse().unwrap();
+		assert_eq!(
+			relayhost.host,
+			Domain::Literal("2001:db8::1".parse().unwrap())
+		);
+		assert_eq!(relayhost.port, 25);
+
+		assert!("[192.0.2.1]:2525".parse::<Relayhost>().is_ok());
+		assert!(server_name(&"smtp.example.net".parse().unwrap()).is_ok());
+		assert!(matches!(
+			server_name(&"[192.0.2.1]".parse().unwrap()),
+			Ok(ServerName::IpAddress(_))
+		));
+		assert!("smtp.example.net:smtp".parse::<Relayhost>().is_err());
+		assert!("[192.0.2.1]2525".parse::<Relayhost>().is_err());
+	}
+
+	#[test]
+	fn streamed_message() {
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		let mut data = std::io::Cursor::new(b"Subject: hi\r\n\r\n.\r\n".to_vec());
+
+		let mut written = vec![];
+		runtime
+			.block_on(send(&mut written, &Output::Bdat, &mut data))
+			.unwrap();
+		assert_eq!(written, b"BDAT 18 LAST\r\nSubject: hi\r\n\r\n.\r\n");
+
+		// Every attempt reads the message from the start
+		let mut written = vec![];
+		runtime
+			.block_on(send(&mut written, &Output::Data, &mut data))
+			.unwrap();
+		assert_eq!(written, b"Subject: hi\r\n\r\n..\r\n.\r\n");
+	}
+
+	/// A server that takes one message over every connection it's given.
+	/// It offers STARTTLS but answers the handshake with nonsense, and hangs
+	/// up on QUIT without answering.
+	async fn fake_server(listener: tokio::net::TcpListener) {
+		use tokio::io::{AsyncBufReadExt, BufReader};
+
+		loop {
+			let (mut socket, _) = listener.accept().await.unwrap();
+			let (reader, mut writer) = socket.split();
+			let mut lines = BufReader::new(reader).lines();
+			writer.write_all(b"220 mx.test\r\n").await.unwrap();
+
+			// The client's half of the handshake isn't lines, which ends this
+			// connection
+			let mut in_data = false;
+			while let Ok(Some(line)) = lines.next_line().await {
+				let reply: &[u8] = match line.as_str() {
+					"." if in_data => {
+						in_data = false;
+						b"250 queued\r\n"
+					}
+					_ if in_data => continue,
+					"DATA" => {
+						in_data = true;
+						b"354 go ahead\r\n"
+					}
+					"EHLO Sail" => b"250-mx.test\r\n250 STARTTLS\r\n",
+					"STARTTLS" => b"220 go ahead\r\nthis isn't TLS\r\n",
+					"QUIT" => break,
+					_ => b"250 ok\r\n",
+				};
+				writer.write_all(reply).await.unwrap();
+			}
+		}
+	}
+
+	/// Send a message to a [fake_server]
+	fn send_to_fake_server() -> Result<DeliveryReport, RelayError> {
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+
+		runtime.block_on(async {
+			let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+			let addr = listener.local_addr().unwrap();
+			tokio::spawn(fake_server(listener));
+
+			let message = ForeignEnvelope::from_parts(
+				"<gen@nyble.dev>".parse().unwrap(),
+				vec![ForeignPath("<a@b.example>".parse().unwrap())],
+				crate::smtp::Message::empty(),
+			);
+			let mut data = std::io::Cursor::new(b"Subject: hi\r\n\r\nhi\r\n".to_vec());
+			send_to(
+				String::from("mx.test"),
+				addr,
+				None,
+				Client::initiate(message),
+				&mut data,
+			)
+			.await
+		})
+	}
+
+	#[test]
+	fn accepted_then_hung_up() {
+		// The handshake fails, so it goes in plaintext on a second
+		// connection, and the server hangs up after taking the message
+		let report = send_to_fake_server().unwrap();
+		assert_eq!(report.recipients.len(), 1);
+		assert_eq!(report.recipients[0].status, DeliveryStatus::Delivered);
+	}
+
+	#[test]
+	fn next_server() {
+		assert!(should_try_next(&Err(RelayError::ConnectionClosed)));
+
+		// Everyone put off, like with a 4xx greeting
+		let everyone = report(&[DeliveryStatus::Deferred, DeliveryStatus::Deferred]);
+		assert!(should_try_next(&Ok(everyone)));
+
+		// Some of it was final, so this server has had its say
+		let some = report(&[DeliveryStatus::Deferred, DeliveryStatus::Failed]);
+		assert!(!should_try_next(&Ok(some)));
+		assert!(!should_try_next(&Err(RelayError::Dns(
+			DnsLookupError::NullMx
+		))));
+		assert!(!RelayError::Dns(DnsLookupError::NullMx).is_transient());
+	}
 }
@@ -0,0 +1,187 @@
+use std::{fmt, net::SocketAddr, time::SystemTime};
+
+use crate::smtp::{args::ForeignPath, Client, Extensions, Response};
+
+/// What happened to every recipient of a message we relayed, and who we
+/// relayed it to
+#[derive(Clone, Debug)]
+pub struct DeliveryReport {
+	/// The name of the server we talked to
+	pub host: String,
+	pub address: SocketAddr,
+	/// What the server said it supports. If it didn't offer DSN, any
+	/// notifications the sender asked for are still up to us.
+	pub extensions: Extensions,
+	pub recipients: Vec<RecipientOutcome>,
+	/// When we started trying to reach the server
+	pub started: SystemTime,
+	/// When we were done with it
+	pub finished: SystemTime,
+}
+
+/// What happened to a single recipient
+#[derive(Clone, Debug)]
+pub struct RecipientOutcome {
+	pub recipient: ForeignPath,
+	pub status: DeliveryStatus,
+	/// The reply that decided it. For delivered recipients, that's the reply
+	/// to the message data.
+	pub reply: Response,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+	Delivered,
+	/// A temporary failure, try again later
+	Deferred,
+	/// The server refused it for good
+	Failed,
+}
+
+impl DeliveryReport {
+	/// Collect what the client found out once it's done
+	pub(super) fn from_client(
+		client: &Client,
+		host: String,
+		address: SocketAddr,
+		started: SystemTime,
+	) -> Self {
+		let mut recipients = vec![];
+
+		if let Some(reply) = client.accepted_reply() {
+			recipients.extend(client.delivered().iter().map(|recipient| RecipientOutcome {
+				recipient: recipient.clone(),
+				status: DeliveryStatus::Delivered,
+				reply: reply.clone(),
+			}));
+		}
+
+		for (failures, status) in [
+			(client.deferred(), DeliveryStatus::Deferred),
+			(client.rejected(), DeliveryStatus::Failed),
+		] {
+			recipients.extend(failures.iter().map(|failure| RecipientOutcome {
+				recipient: failure.recipient.clone(),
+				status,
+				reply: failure.reply.clone(),
+			}));
+		}
+
+		Self {
+			host,
+			address,
+			extensions: client.extensions().clone(),
+			recipients,
+			started,
+			finished: SystemTime::now(),
+		}
+	}
+
+	pub fn with_status(&self, status: DeliveryStatus) -> impl Iterator<Item = &RecipientOutcome> {
+		self.recipients
+			.iter()
+			.filter(move |outcome| outcome.status == status)
+	}
+
+	/// True if nothing was decided, so another server might do better
+	pub fn all_deferred(&self) -> bool {
+		self.recipients
+			.iter()
+			.all(|outcome| outcome.status == DeliveryStatus::Deferred)
+	}
+}
+
+impl fmt::Display for DeliveryStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			DeliveryStatus::Delivered => write!(f, "delivered"),
+			DeliveryStatus::Deferred => write!(f, "deferred"),
+			DeliveryStatus::Failed => write!(f, "failed"),
+		}
+	}
+}
+
+/// One line per recipient, for logs
+impl fmt::Display for DeliveryReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let elapsed = self
+			.finished
+			.duration_since(self.started)
+			.unwrap_or_default();
+
+		for (index, outcome) in self.recipients.iter().enumerate() {
+			if index > 0 {
+				writeln!(f)?;
+			}
+
+			write!(
+				f,
+				"to={} relay={}[{}] delay={:.1}s status={} ({})",
+				outcome.recipient.0,
+				self.host,
+				self.address,
+				elapsed.as_secs_f32(),
+				outcome.status,
+				format!("{}", outcome.reply).replace("\r\n", " "),
+			)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::smtp::{ForeignEnvelope, Message};
+
+	#[test]
+	fn outcomes() {
+		let path = |s: &str| ForeignPath(s.parse().unwrap());
+		let mut client = Client::initiate(ForeignEnvelope::from_parts(
+			"<gen@nyble.dev>".parse().unwrap(),
+			vec![
+				path("<a@mx.test>"),
+				path("<b@mx.test>"),
+				path("<c@mx.test>"),
+			],
+			Message::empty(),
+		));
+
+		client.push(b"220 mx.test ready\r\n");
+		client.push(b"250 mx.test\r\n");
+		client.push(b"250 ok\r\n");
+		client.push(b"450 busy\r\n");
+		client.push(b"550 no such user\r\n");
+		client.push(b"250 ok\r\n");
+		client.push(b"354 go ahead\r\n");
+		client.push(b"250 queued as 12\r\n");
+
+		let report = DeliveryReport::from_client(
+			&client,
+			String::from("mx.test"),
+			"192.0.2.1:25".parse().unwrap(),
+			SystemTime::now(),
+		);
+
+		let status = |address: &str| {
+			report
+				.recipients
+				.iter()
+				.find(|outcome| outcome.recipient.0.to_string() == address)
+				.map(|outcome| (outcome.status, outcome.reply.code.as_code()))
+		};
+		assert_eq!(
+			status("<a@mx.test>"),
+			Some((DeliveryStatus::Delivered, 250))
+		);
+		assert_eq!(status("<b@mx.test>"), Some((DeliveryStatus::Failed, 550)));
+		assert_eq!(status("<c@mx.test>"), Some((DeliveryStatus::Deferred, 450)));
+		assert!(!report.all_deferred());
+
+		assert!(report.to_string().lines().any(|line| {
+			line
+			== "to=<a@mx.test> relay=mx.test[192.0.2.1:25] delay=0.0s status=delivered (250 queued as 12)"
+		}));
+	}
+}
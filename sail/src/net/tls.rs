@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use tokio_rustls::{
+	rustls::{
+		self,
+		client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+		crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+		pki_types::{CertificateDer, ServerName, UnixTime},
+		ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+	},
+	TlsConnector,
+};
+
+/// A connector for opportunistic STARTTLS when relaying. RFC 3207 section 4.1
+/// notes that publicly-referenced servers can't expect a certificate a client
+/// can verify, and failing here would only push us back to plaintext, so the
+/// server's certificate is accepted as-is. We still get an encrypted channel.
+pub fn opportunistic_connector() -> TlsConnector {
+	let provider = Arc::new(ring::default_provider());
+
+	let config = ClientConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()
+		.expect("ring supports the default protocol versions")
+		.dangerous()
+		.with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+		.with_no_client_auth();
+
+	TlsConnector::from(Arc::new(config))
+}
+
+/// A connector that checks the server's certificate against the webpki
+/// roots. Relayhosts are configured by name and we may send them
+/// credentials, so unlike an MX they have to prove who they are.
+pub fn verified_connector() -> TlsConnector {
+	let roots = RootCertStore {
+		roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+	};
+
+	let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.expect("ring supports the default protocol versions")
+		.with_root_certificates(roots)
+		.with_no_client_auth();
+
+	TlsConnector::from(Arc::new(config))
+}
+
+/// Accepts any certificate, but still checks the handshake signatures so the
+/// session keys really belong to whoever sent the certificate.
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+	fn verify_server_cert(
+		&self,
+		_end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(
+			message,
+			cert,
+			dss,
+			&self.0.signature_verification_algorithms,
+		)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(
+			message,
+			cert,
+			dss,
+			&self.0.signature_verification_algorithms,
+		)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.0.signature_verification_algorithms.supported_schemes()
+	}
+}
@@ -1,16 +1,119 @@
+use std::{
+	fs::File,
+	io::{self, BufRead, BufReader, Read, Seek, Write},
+};
+
 use crate::smtp::{
 	args::{Domain, Path},
-	Envelope, Response,
+	Envelope, Extensions, Message, Response,
 };
 
+/// Somewhere to check the username and password a client gives with AUTH
+pub trait CredentialBackend: Send + Sync {
+	/// True if the password is correct for this user
+	fn verify(&self, username: &str, password: &str) -> bool;
+}
+
+/// Somewhere to put message data as it arrives, like a spool file, so it
+/// doesn't have to be held in memory. What's written has already had the
+/// dot-stuffing removed.
+pub trait MessageSink: Write + Send {
+	/// Everything that's been written, from the start
+	fn reader(&mut self) -> io::Result<Box<dyn Read + '_>>;
+
+	/// Read the whole message into memory
+	fn message(&mut self) -> io::Result<Message> {
+		let mut message = Message::empty();
+		self.reader()?.read_to_end(&mut message.body)?;
+		Ok(message)
+	}
+
+	/// Read just the header section into memory, up to and including the
+	/// blank line that ends it. Like with [MessageSink::message], it isn't
+	/// parsed.
+	fn header_section(&mut self) -> io::Result<Message> {
+		let mut message = Message::empty();
+		let mut reader = BufReader::new(self.reader()?);
+		while reader.read_until(b'\n', &mut message.body)? > 0 {
+			if message.body.ends_with(b"\r\n\r\n") || message.body == b"\r\n" {
+				break;
+			}
+		}
+
+		Ok(message)
+	}
+
+	/// Where what's been written is on disk, if it is, so it can be linked
+	/// somewhere else instead of copied
+	fn path(&self) -> Option<&std::path::Path> {
+		None
+	}
+}
+
+impl MessageSink for Vec<u8> {
+	fn reader(&mut self) -> io::Result<Box<dyn Read + '_>> {
+		Ok(Box::new(self.as_slice()))
+	}
+}
+
+impl MessageSink for File {
+	fn reader(&mut self) -> io::Result<Box<dyn Read + '_>> {
+		self.rewind()?;
+		Ok(Box::new(self))
+	}
+}
+
+/// Why a recipient can't be accepted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+	/// There's nobody here by that name
+	NoSuchUser,
+	/// The mailbox is there, but it can't take any more right now
+	MailboxFull,
+	/// The domain isn't ours, and we don't relay to it
+	RelayDenied,
+}
+
 pub trait Policy: Send + Sync {
 	/// Returns the hostname that the server will present itself as
 	fn primary_host(&self) -> Domain;
 
-	/// Determines if a path is valid or not.
+	/// Determines if a path is valid or not, and why not.
 	/// This is used during the RCPT command on the server to determine if it
 	/// should accept a forward path or not, whether it's for relay or local delivery.
-	fn path_is_valid(&self, path: &Path) -> bool;
+	fn check_path(&self, path: &Path) -> Result<(), Rejection>;
+
+	/// The ESMTP extensions to advertise in the reply to EHLO. Anything not
+	/// listed here is treated as unrecognized by the server.
+	fn extensions(&self) -> Extensions {
+		Extensions::default()
+	}
+
+	/// The largest message, in octets, that the server will accept. This is
+	/// advertised with SIZE and enforced while receiving DATA.
+	fn max_message_size(&self) -> Option<usize> {
+		None
+	}
+
+	/// Where to check credentials given with AUTH. If there isn't anywhere,
+	/// nobody can authenticate.
+	fn credentials(&self) -> Option<&dyn CredentialBackend> {
+		None
+	}
+
+	/// Like `check_path`, but for a client that has authenticated as
+	/// `identity`. Authenticated clients may send anywhere by default.
+	fn check_authenticated_path(&self, _path: &Path, _identity: &str) -> Result<(), Rejection> {
+		Ok(())
+	}
+
+	/// Where to write the data of the next message. By default it's kept in
+	/// memory.
+	fn message_sink(&mut self) -> io::Result<Box<dyn MessageSink>> {
+		Ok(Box::new(Vec::new()))
+	}
 
-	fn message_received(&mut self, message: Envelope) -> Response;
+	/// The client has sent a whole message. Its data is in `data`, the sink
+	/// from [Policy::message_sink], and not in the envelope.
+	fn message_received(&mut self, message: Envelope, data: Box<dyn MessageSink>) -> Response;
 }
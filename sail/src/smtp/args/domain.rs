@@ -7,10 +7,44 @@ use thiserror::Error;
 
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 pub enum Domain {
+	/// A name as it was given to us, which may have U-labels (RFC 5890)
 	FQDN(String),
 	Literal(IpAddr),
 }
 
+impl Domain {
+	/// Whether this can be sent without SMTPUTF8
+	pub fn is_ascii(&self) -> bool {
+		match self {
+			Self::FQDN(domain) => domain.is_ascii(),
+			Self::Literal(_) => true,
+		}
+	}
+
+	/// The domain written with A-labels, like `xn--fsqu00a.xn--55qx5d`, for
+	/// DNS and servers without SMTPUTF8. None if it isn't valid IDNA.
+	pub fn to_ascii(&self) -> Option<Self> {
+		match self {
+			Self::FQDN(domain) if !domain.is_ascii() => {
+				idna::domain_to_ascii_strict(domain).ok().map(Self::FQDN)
+			}
+			_ => Some(self.clone()),
+		}
+	}
+
+	/// The domain written with U-labels, like `例子.公司`. Names that aren't
+	/// valid IDNA are left alone.
+	pub fn to_unicode(&self) -> Self {
+		match self {
+			Self::FQDN(domain) => match idna::domain_to_unicode(domain) {
+				(unicode, Ok(())) => Self::FQDN(unicode),
+				(_, Err(_)) => self.clone(),
+			},
+			Self::Literal(_) => self.clone(),
+		}
+	}
+}
+
 impl Display for Domain {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		write!(
@@ -44,6 +78,11 @@ impl std::str::FromStr for Domain {
 			}
 		} else if Validator::validate_domain(s) {
 			Ok(Self::FQDN(s.to_string()))
+		} else if !s.is_ascii()
+			&& idna::domain_to_ascii_strict(s).is_ok_and(|ascii| Validator::validate_domain(&ascii))
+		{
+			// U-labels, for SMTPUTF8 (RFC 6531 section 3.3)
+			Ok(Self::FQDN(s.to_string()))
 		} else {
 			Err(ParseDomainError::InvalidDomain)
 		}
@@ -5,6 +5,13 @@ use super::Validator;
 #[derive(Clone, Debug, PartialEq)]
 pub struct LocalPart(String);
 
+impl LocalPart {
+	/// Whether this can be sent without SMTPUTF8
+	pub fn is_ascii(&self) -> bool {
+		self.0.is_ascii()
+	}
+}
+
 impl std::fmt::Display for LocalPart {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		write!(f, "{}", self.0)
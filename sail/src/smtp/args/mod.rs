@@ -1,10 +1,12 @@
 mod domain;
 mod localpart;
+mod parameter;
 mod path;
 mod validator;
 
 pub use domain::*;
 pub use localpart::*;
+pub use parameter::*;
 pub use path::*;
 pub use validator::*;
 
@@ -196,6 +198,24 @@ mod test {
 		}
 	}
 
+	#[test]
+	fn internationalized_paths() {
+		let path = Path::from_str("<用户@例子.广告>").unwrap();
+		assert!(!path.is_ascii());
+		assert!(path.to_ascii().is_none());
+		assert!(Path::from_str("<\"用户 名\"@example.com>").is_ok());
+
+		let path = Path::from_str("<gen@例子.广告>").unwrap();
+		let ascii = path.to_ascii().unwrap();
+		assert_eq!(ascii.to_string(), "<gen@xn--fsqu00a.xn--4rr70v>");
+		assert!(ascii.is_ascii());
+		assert_eq!(ascii.domain.to_unicode(), path.domain);
+
+		// U-labels still have to make a valid domain
+		assert!(Domain::from_str("例子..广告").is_err());
+		assert!(Domain::from_str("-例子.广告").is_err());
+	}
+
 	#[test]
 	fn reverse_path_null() {
 		assert!(ReversePath::from_str("<>").is_ok());
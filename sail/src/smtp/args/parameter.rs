@@ -0,0 +1,151 @@
+use std::{
+	fmt::{Display, Formatter},
+	str::FromStr,
+};
+use thiserror::Error;
+
+/// A single ESMTP parameter given to MAIL or RCPT, as described in RFC 5321
+/// section 4.1.2: `esmtp-keyword ["=" esmtp-value]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter {
+	pub keyword: String,
+	pub value: Option<String>,
+}
+
+impl Parameter {
+	pub fn new<K: Into<String>>(keyword: K, value: Option<String>) -> Self {
+		Self {
+			keyword: keyword.into(),
+			value,
+		}
+	}
+
+	/// Keywords are case-insensitive
+	pub fn is(&self, keyword: &str) -> bool {
+		self.keyword.eq_ignore_ascii_case(keyword)
+	}
+
+	fn valid_keyword(keyword: &str) -> bool {
+		// esmtp-keyword = (ALPHA / DIGIT) *(ALPHA / DIGIT / "-")
+		let mut chars = keyword.chars();
+		match chars.next() {
+			Some(c) if c.is_ascii_alphanumeric() => {
+				chars.all(|c| c.is_ascii_alphanumeric() || c == '-')
+			}
+			_ => false,
+		}
+	}
+
+	fn valid_value(value: &str) -> bool {
+		// esmtp-value = 1*(%d33-60 / %d62-126)
+		!value.is_empty() && value.bytes().all(|b| (33..=126).contains(&b) && b != b'=')
+	}
+}
+
+impl Display for Parameter {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match &self.value {
+			None => write!(f, "{}", self.keyword),
+			Some(value) => write!(f, "{}={}", self.keyword, value),
+		}
+	}
+}
+
+impl FromStr for Parameter {
+	type Err = ParseParameterError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (keyword, value) = match s.split_once('=') {
+			None => (s, None),
+			Some((keyword, value)) => (keyword, Some(value)),
+		};
+
+		if !Self::valid_keyword(keyword) {
+			return Err(ParseParameterError::InvalidKeyword(keyword.to_owned()));
+		}
+
+		match value {
+			Some(value) if !Self::valid_value(value) => {
+				Err(ParseParameterError::InvalidValue(keyword.to_owned()))
+			}
+			_ => Ok(Self::new(keyword, value.map(<_>::to_owned))),
+		}
+	}
+}
+
+/// The parameters that followed the path in a MAIL or RCPT command, in the
+/// order they were given.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Parameters(pub Vec<Parameter>);
+
+impl Parameters {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Parameter> {
+		self.0.iter()
+	}
+
+	pub fn push(&mut self, parameter: Parameter) {
+		self.0.push(parameter)
+	}
+
+	/// The first parameter with the given keyword, ignoring case
+	pub fn get(&self, keyword: &str) -> Option<&Parameter> {
+		self.0.iter().find(|param| param.is(keyword))
+	}
+
+	pub fn contains(&self, keyword: &str) -> bool {
+		self.get(keyword).is_some()
+	}
+}
+
+/// Includes a leading space if there are any parameters so it can be written
+/// directly after the path.
+impl Display for Parameters {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		for param in &self.0 {
+			write!(f, " {}", param)?;
+		}
+
+		Ok(())
+	}
+}
+
+impl FromStr for Parameters {
+	type Err = ParseParameterError;
+
+	/// Parses a space separated list of parameters. An empty string is an
+	/// empty list.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parameters = Parameters::new();
+
+		for raw in s.split(' ').filter(|raw| !raw.is_empty()) {
+			let param: Parameter = raw.parse()?;
+
+			// Parameters may only be given once per command
+			if parameters.contains(&param.keyword) {
+				return Err(ParseParameterError::Duplicate(param.keyword));
+			}
+
+			parameters.push(param);
+		}
+
+		Ok(parameters)
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum ParseParameterError {
+	#[error("'{0}' is not a valid parameter keyword")]
+	InvalidKeyword(String),
+	#[error("the value of {0} is invalid")]
+	InvalidValue(String),
+	#[error("{0} was given more than once")]
+	Duplicate(String),
+}
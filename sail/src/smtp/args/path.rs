@@ -22,6 +22,27 @@ impl Path {
 			domain,
 		}
 	}
+
+	/// The mailbox without angle brackets, like `gen@nyble.dev`
+	pub fn address(&self) -> String {
+		format!("{}@{}", self.local_part, self.domain)
+	}
+
+	/// Whether this can be sent without SMTPUTF8
+	pub fn is_ascii(&self) -> bool {
+		self.local_part.is_ascii() && self.domain.is_ascii()
+	}
+
+	/// The path with its domain in A-labels, for a server without SMTPUTF8.
+	/// None if the local part isn't ASCII, since there's no other way to
+	/// write it (RFC 6531 section 3.2).
+	pub fn to_ascii(&self) -> Option<Self> {
+		if !self.local_part.is_ascii() {
+			return None;
+		}
+
+		Some(Self::new(self.local_part.clone(), self.domain.to_ascii()?))
+	}
 }
 
 #[derive(Clone, Debug)]
@@ -36,6 +57,24 @@ pub enum ReversePath {
 	Regular(Path),
 }
 
+impl ForwardPath {
+	pub fn is_ascii(&self) -> bool {
+		match self {
+			Self::Postmaster => true,
+			Self::Regular(path) => path.is_ascii(),
+		}
+	}
+}
+
+impl ReversePath {
+	pub fn is_ascii(&self) -> bool {
+		match self {
+			Self::Null => true,
+			Self::Regular(path) => path.is_ascii(),
+		}
+	}
+}
+
 impl Path {
 	fn parse_naked_path(naked: &str) -> Result<Self, ParsePathError> {
 		if let Some((local_part, domain)) = naked.rsplit_once('@') {
@@ -152,11 +191,13 @@ impl FromStr for ReversePath {
 	}
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for ReversePath {
 	fn default() -> Self {
 		Self::Null
 	}
 }
+#[allow(clippy::derivable_impls)]
 impl Default for ForwardPath {
 	fn default() -> Self {
 		Self::Postmaster
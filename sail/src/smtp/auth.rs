@@ -0,0 +1,237 @@
+use core::fmt;
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use thiserror::Error;
+
+/// A SASL mechanism usable with the AUTH command (RFC 4954)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mechanism {
+	/// RFC 4616
+	Plain,
+	/// The de facto LOGIN mechanism. It was never standardized, but many
+	/// clients still only speak it.
+	Login,
+}
+
+impl Mechanism {
+	pub fn keyword(&self) -> &'static str {
+		match self {
+			Mechanism::Plain => "PLAIN",
+			Mechanism::Login => "LOGIN",
+		}
+	}
+}
+
+impl fmt::Display for Mechanism {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.keyword())
+	}
+}
+
+impl FromStr for Mechanism {
+	type Err = ParseMechanismError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_uppercase().as_str() {
+			"PLAIN" => Ok(Mechanism::Plain),
+			"LOGIN" => Ok(Mechanism::Login),
+			_ => Err(ParseMechanismError::Unsupported(s.to_owned())),
+		}
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum ParseMechanismError {
+	#[error("the {0} mechanism is not supported")]
+	Unsupported(String),
+}
+
+/// Username and password that a client authenticated with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+	pub username: String,
+	pub password: String,
+}
+
+impl Credentials {
+	/// The response for PLAIN with no authorization identity
+	pub fn plain_response(&self) -> String {
+		STANDARD.encode(format!("\0{}\0{}", self.username, self.password))
+	}
+}
+
+/// The server's side of a SASL exchange that's in progress
+#[derive(Clone, Debug)]
+pub(super) struct Exchange {
+	mechanism: Mechanism,
+	username: Option<String>,
+}
+
+pub(super) enum Step {
+	/// Send this base64 challenge in a 334 reply and wait for the client
+	Challenge(&'static str),
+	/// The client has given us everything, check the credentials
+	Done(Credentials),
+	Failed(SaslError),
+}
+
+impl Exchange {
+	pub fn new(mechanism: Mechanism) -> Self {
+		Self {
+			mechanism,
+			username: None,
+		}
+	}
+
+	/// The challenge to send if the client gave no initial response
+	pub fn first_challenge(&self) -> Step {
+		match self.mechanism {
+			Mechanism::Plain => Step::Challenge(""),
+			// "Username:"
+			Mechanism::Login => Step::Challenge("VXNlcm5hbWU6"),
+		}
+	}
+
+	/// Take a line from the client. RFC 4954 says a lone "=" is an empty
+	/// initial response and a "*" cancels the exchange.
+	pub fn respond(&mut self, line: &str) -> Step {
+		let decoded = match line {
+			"*" => return Step::Failed(SaslError::Cancelled),
+			"=" => vec![],
+			_ => match STANDARD.decode(line) {
+				Ok(decoded) => decoded,
+				Err(_) => return Step::Failed(SaslError::InvalidBase64),
+			},
+		};
+
+		let decoded = match String::from_utf8(decoded) {
+			Ok(decoded) => decoded,
+			Err(_) => return Step::Failed(SaslError::InvalidUtf8),
+		};
+
+		match self.mechanism {
+			Mechanism::Plain => Self::plain(&decoded),
+			Mechanism::Login => match self.username.take() {
+				None => {
+					self.username = Some(decoded);
+					// "Password:"
+					Step::Challenge("UGFzc3dvcmQ6")
+				}
+				Some(username) => Step::Done(Credentials {
+					username,
+					password: decoded,
+				}),
+			},
+		}
+	}
+
+	/// RFC 4616: `[authzid] NUL authcid NUL passwd`
+	fn plain(message: &str) -> Step {
+		let mut parts = message.split('\0');
+
+		match (parts.next(), parts.next(), parts.next(), parts.next()) {
+			(Some(authzid), Some(authcid), Some(passwd), None) => {
+				// We don't let anyone act on behalf of somebody else
+				if !authzid.is_empty() && authzid != authcid {
+					Step::Failed(SaslError::AuthorizationIdentity)
+				} else {
+					Step::Done(Credentials {
+						username: authcid.to_owned(),
+						password: passwd.to_owned(),
+					})
+				}
+			}
+			_ => Step::Failed(SaslError::Malformed),
+		}
+	}
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SaslError {
+	#[error("authentication cancelled")]
+	Cancelled,
+	#[error("response was not valid base64")]
+	InvalidBase64,
+	#[error("response was not valid UTF-8")]
+	InvalidUtf8,
+	#[error("malformed response")]
+	Malformed,
+	#[error("cannot authorize as another identity")]
+	AuthorizationIdentity,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn done(step: Step) -> Credentials {
+		match step {
+			Step::Done(credentials) => credentials,
+			_ => panic!("exchange was not done"),
+		}
+	}
+
+	#[test]
+	fn plain() {
+		let credentials = Credentials {
+			username: String::from("gen"),
+			password: String::from("hunter2"),
+		};
+
+		let mut exchange = Exchange::new(Mechanism::Plain);
+		assert_eq!(
+			done(exchange.respond(&credentials.plain_response())),
+			credentials
+		);
+
+		// authzid matching authcid is fine, anything else is not
+		let mut exchange = Exchange::new(Mechanism::Plain);
+		assert_eq!(
+			done(exchange.respond(&STANDARD.encode("gen\0gen\0hunter2"))),
+			credentials
+		);
+		let mut exchange = Exchange::new(Mechanism::Plain);
+		assert!(matches!(
+			exchange.respond(&STANDARD.encode("root\0gen\0hunter2")),
+			Step::Failed(SaslError::AuthorizationIdentity)
+		));
+	}
+
+	#[test]
+	fn login() {
+		let mut exchange = Exchange::new(Mechanism::Login);
+		assert!(matches!(
+			exchange.first_challenge(),
+			Step::Challenge("VXNlcm5hbWU6")
+		));
+		assert!(matches!(
+			exchange.respond(&STANDARD.encode("gen")),
+			Step::Challenge("UGFzc3dvcmQ6")
+		));
+		assert_eq!(
+			done(exchange.respond(&STANDARD.encode("hunter2"))),
+			Credentials {
+				username: String::from("gen"),
+				password: String::from("hunter2"),
+			}
+		);
+	}
+
+	#[test]
+	fn cancelled_and_garbage() {
+		let mut exchange = Exchange::new(Mechanism::Login);
+		assert!(matches!(
+			exchange.respond("*"),
+			Step::Failed(SaslError::Cancelled)
+		));
+		assert!(matches!(
+			exchange.respond("not base64!"),
+			Step::Failed(SaslError::InvalidBase64)
+		));
+		assert!(matches!(
+			Exchange::new(Mechanism::Plain).respond("="),
+			Step::Failed(SaslError::Malformed)
+		));
+	}
+}
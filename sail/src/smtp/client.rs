@@ -1,24 +1,52 @@
-use std::fmt::Display;
+use std::{collections::VecDeque, fmt::Display};
 
 use crate::smtp::Response;
 
 use super::{
-	args::{ForeignPath, ReversePath},
+	args::{ForeignPath, Parameters, Path, ReversePath},
+	message::line_end,
 	Command::*,
-	ForeignEnvelope, Message, ResponseCode,
+	Credentials, Extension, Extensions, ForeignEnvelope, Mechanism, ResponseCode,
 };
 
 #[derive(Default, Clone)]
 pub struct Client {
 	state: State,
-	reply: String,
+	reply: Vec<u8>,
 	envelope: ForeignEnvelope,
 
 	last_sent_path: Option<ForeignPath>,
-	rejected_forward_paths: Vec<ForeignPath>,
+	/// Commands we've pipelined and are still waiting on replies to, oldest
+	/// first
+	pending: VecDeque<Pending>,
+	/// The reply to a pipelined MAIL, if it wasn't accepted
+	mail_failure: Option<Response>,
+	rejected_forward_paths: Vec<RecipientFailure>,
+	/// Recipients the server gave a 4xx reply to, which we can try again later
+	deferred_forward_paths: Vec<RecipientFailure>,
+	/// Recipients the server accepted with a 250
+	accepted_forward_paths: Vec<ForeignPath>,
+	/// The reply that made us give up on the transaction, if there was one
+	failure: Option<Response>,
+	/// The server's reply to the message data, if it took it
+	accepted_reply: Option<Response>,
+
+	/// The extensions the server advertised in its reply to our EHLO
+	extensions: Extensions,
+	/// Whether the connection is running over TLS
+	secure: bool,
+	/// Whether to give up rather than send the message in plaintext
+	require_tls: bool,
+	/// Whether to leave STARTTLS alone even if it's offered, like after the
+	/// handshake failed on an earlier connection
+	skip_starttls: bool,
+	/// What to AUTH with, if the server offers it
+	credentials: Option<Credentials>,
 }
 
 impl Client {
+	/// The message itself is sent by the caller, so the envelope's data only
+	/// needs the header section, to tell whether it needs SMTPUTF8
 	pub fn initiate(envelope: ForeignEnvelope) -> Self {
 		Self {
 			envelope,
@@ -26,61 +54,372 @@ impl Client {
 		}
 	}
 
-	pub fn push(&mut self, reply: &str) -> Option<Output> {
-		self.reply.push_str(reply);
+	/// Authenticate with AUTH PLAIN before sending. Credentials are never sent
+	/// in plaintext, so this also requires TLS.
+	pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+		self.credentials = Some(credentials);
+		self.require_tls()
+	}
 
-		if !self.reply.ends_with("\r\n") {
-			return None;
-		}
+	/// Give up if the server doesn't offer STARTTLS instead of carrying on
+	/// in plaintext
+	pub fn require_tls(mut self) -> Self {
+		self.require_tls = true;
+		self
+	}
 
-		self.process_reply()
+	/// Send in plaintext even if the server offers STARTTLS
+	pub fn without_starttls(mut self) -> Self {
+		self.skip_starttls = true;
+		self
 	}
 
-	pub fn undeliverable(self) -> Option<Message> {
-		if !self.rejected_forward_paths.is_empty() {
-			if let super::args::ReversePath::Regular(_) = self.envelope.reverse_path {
-				let mut reason = String::new();
+	/// Give the client what the server sent. With PIPELINING several replies
+	/// can arrive at once, so every complete reply is handled in order.
+	///
+	/// There's at most one output per push. We only send something once
+	/// every reply we were waiting on is in, so a reply after that is one we
+	/// never asked for and ends the transaction.
+	pub fn push(&mut self, reply: &[u8]) -> Option<Output> {
+		self.reply.extend_from_slice(reply);
+
+		let mut output = None;
+		while let Some(end) = self.reply_end() {
+			// Anything the server sent before the TLS handshake is forgotten
+			// (RFC 3207 section 4.2), and after QUIT there's nothing to do
+			if self.should_start_tls() || self.should_exit() {
+				break;
+			}
 
-				for path in self.rejected_forward_paths {
-					//todo: better Envelopes. can we take the text part of the
-					//response and put it here?
-					reason.push_str(&format!("The host rejected {}\r\n", path.0));
+			if output.is_some() {
+				self.reply.clear();
+				if self.state != State::SentQuit {
+					output = Some(self.unsolicited());
 				}
+				break;
+			}
 
-				Some(Message::new_now(ReversePath::Null, reason))
-			} else {
-				None
+			let reply: Vec<u8> = self.reply.drain(..end).collect();
+			output = self.process_reply(&reply);
+		}
+
+		output
+	}
+
+	/// Where the first complete reply in the buffer ends. That's after the
+	/// first line that isn't followed by another (RFC 5321 section 4.2.1).
+	fn reply_end(&self) -> Option<usize> {
+		let mut start = 0;
+
+		while let Some(length) = line_end(&self.reply[start..]) {
+			let line = &self.reply[start..start + length];
+			start += length;
+
+			if line.get(3) != Some(&b'-') {
+				return Some(start);
 			}
-		} else {
-			None
 		}
+
+		None
 	}
 
-	fn invalid_forward(&mut self) {
-		self.rejected_forward_paths
-			.push(self.last_sent_path.take().unwrap())
+	/// The reply that ended the transaction early. If it was a 4xx reply, the
+	/// recipients it affected are in [Client::deferred].
+	pub fn failure(&self) -> Option<&Response> {
+		self.failure.as_ref()
 	}
 
-	fn process_reply(&mut self) -> Option<Output> {
-		//todo: oh no.
-		if self.reply.len() < 3
-			|| !self.reply.is_ascii()
-			|| (self.reply.len() > 4
-				&& self.reply.trim_end().split("\r\n").last()?.chars().nth(3)? == '-')
-		{
-			return None;
+	/// Recipients that failed temporarily and should be tried again later
+	pub fn deferred(&self) -> &[RecipientFailure] {
+		&self.deferred_forward_paths
+	}
+
+	/// Recipients the server refused for good
+	pub fn rejected(&self) -> &[RecipientFailure] {
+		&self.rejected_forward_paths
+	}
+
+	/// Recipients the server took the message for. Empty until the server
+	/// has accepted the message data.
+	pub fn delivered(&self) -> &[ForeignPath] {
+		match self.accepted_reply {
+			Some(_) => &self.accepted_forward_paths,
+			None => &[],
 		}
+	}
+
+	/// What the server said when it took the message, usually with its queue id
+	pub fn accepted_reply(&self) -> Option<&Response> {
+		self.accepted_reply.as_ref()
+	}
 
-		let response: Response = self.reply.parse().unwrap();
+	/// True once the server has agreed to STARTTLS. The caller should perform
+	/// the TLS handshake and then call [Client::tls_started].
+	pub fn should_start_tls(&self) -> bool {
+		self.state == State::StartingTls
+	}
+
+	/// Tell the client the connection is now encrypted. The server has
+	/// forgotten our earlier greeting (RFC 3207 section 4.2), so this returns
+	/// the EHLO that must be sent before continuing.
+	pub fn tls_started(&mut self) -> Output {
+		self.secure = true;
 		self.reply.clear();
+		self.extensions = Extensions::default();
+		self.state = State::Greeted;
 
-		//todo: parse multiline replies e.g. ehlo
-		//todo: handle the unknown response codes
-		let code: ResponseCode = response.code;
+		Output::Command(Ehlo("Sail".parse().unwrap()))
+	}
+
+	pub fn is_secure(&self) -> bool {
+		self.secure
+	}
+
+	/// What the server said it supports in its reply to our EHLO
+	pub fn extensions(&self) -> &Extensions {
+		&self.extensions
+	}
+
+	/// What comes after the EHLO, once TLS is sorted out
+	fn authenticate(&mut self) -> Output {
+		if !self.secure && self.require_tls {
+			// RFC 3207 section 4's reply for when TLS isn't available
+			return self.fail(Response::with_message(
+				ResponseCode::from_code(454).unwrap(),
+				"The server did not offer STARTTLS",
+			));
+		}
+
+		match &self.credentials {
+			Some(credentials) if self.extensions.contains(Extension::Auth) => {
+				self.state = State::SentAuth;
+				Output::Command(Auth(Mechanism::Plain, Some(credentials.plain_response())))
+			}
+			// If the server wants AUTH it'll tell us when we send MAIL
+			_ => self.send_reverse_path(),
+		}
+	}
+
+	fn send_reverse_path(&mut self) -> Output {
+		// Binary messages can only go as binary (RFC 3030 section 3)
+		if self.envelope.is_binary()
+			&& !(self.extensions.contains(Extension::BinaryMime)
+				&& self.extensions.contains(Extension::Chunking))
+		{
+			return self.fail(Response::with_message(
+				ResponseCode::from_code(554).unwrap(),
+				"The server can't take binary messages",
+			));
+		}
+
+		if !self.extensions.contains(Extension::SmtpUtf8) && !self.can_downgrade() {
+			return self.fail(Response::with_message(
+				ResponseCode::MailboxNameNotAllowed,
+				"The server can't take internationalized mail",
+			));
+		}
+
+		self.state = State::SentReversePath;
+		let parameters = self.forwarded_parameters(&self.envelope.mail_parameters);
+		let reverse_path = match &self.envelope.reverse_path {
+			ReversePath::Regular(path) => ReversePath::Regular(self.outgoing_path(path)),
+			ReversePath::Null => ReversePath::Null,
+		};
+		let mail = Mail(reverse_path, parameters);
+
+		if !self.extensions.contains(Extension::Pipelining)
+			|| self.envelope.forward_paths.is_empty()
+		{
+			return Output::Command(mail);
+		}
+
+		// Send the whole envelope at once and sort out the replies as they
+		// come back (RFC 2920 section 3.1)
+		self.state = State::Pipelining;
+		let mut commands = vec![mail];
+		self.pending.push_back(Pending::Mail);
+
+		while let Some((path, parameters)) = self.next_forward_path() {
+			commands.push(self.rcpt(path.clone(), &parameters));
+			self.pending.push_back(Pending::Rcpt(path));
+		}
+
+		// BDAT can't be sent until we know someone will get the message, but
+		// DATA can go with the rest and be sorted out after
+		if !self.extensions.contains(Extension::Chunking) {
+			commands.push(Data);
+			self.pending.push_back(Pending::Data);
+		}
+
+		Output::Batch(commands)
+	}
+
+	/// The parameters we were given that the server will take. RFC 3461
+	/// section 5.2 asks us to pass DSN parameters along when we can, and
+	/// BODY tells the server what the message needs.
+	fn forwarded_parameters(&self, parameters: &Parameters) -> Parameters {
+		let mut forwarded = Parameters::new();
+
+		for parameter in parameters.iter() {
+			let extension = match parameter.keyword.to_ascii_uppercase().as_str() {
+				"BODY" => parameter.value.as_deref().and_then(Extension::from_body),
+				"SMTPUTF8" => Some(Extension::SmtpUtf8),
+				keyword => Extension::from_mail_parameter(keyword)
+					.or(Extension::from_rcpt_parameter(keyword))
+					.filter(|extension| *extension == Extension::Dsn),
+			};
+
+			if extension.is_some_and(|extension| self.extensions.contains(extension)) {
+				forwarded.push(parameter.clone());
+			}
+		}
+
+		forwarded
+	}
+
+	/// Whether the message can go to a server without SMTPUTF8. Domains can
+	/// be sent as A-labels, but there's no other way to write UTF-8 local
+	/// parts or header fields (RFC 6531 section 3.2).
+	fn can_downgrade(&self) -> bool {
+		let reverse_path = match &self.envelope.reverse_path {
+			ReversePath::Regular(path) => path.to_ascii().is_some(),
+			ReversePath::Null => true,
+		};
+
+		reverse_path
+			&& self
+				.envelope
+				.forward_paths
+				.iter()
+				.all(|path| path.0.to_ascii().is_some())
+			&& (!self.envelope.is_utf8() || self.envelope.data.has_ascii_headers())
+	}
+
+	/// The path as the server can take it. Without SMTPUTF8 the domain goes
+	/// as A-labels, which [Client::can_downgrade] made sure is possible.
+	fn outgoing_path(&self, path: &Path) -> Path {
+		match self.extensions.contains(Extension::SmtpUtf8) {
+			true => path.clone(),
+			false => path.to_ascii().unwrap_or_else(|| path.clone()),
+		}
+	}
+
+	/// The next recipient to send, and its parameters
+	fn next_forward_path(&mut self) -> Option<(ForeignPath, Parameters)> {
+		let path = self.envelope.forward_paths.pop()?;
+		let parameters = self.envelope.rcpt_parameters.pop().unwrap_or_default();
+
+		Some((path, parameters))
+	}
+
+	fn recipient_failed(&mut self, recipient: ForeignPath, reply: Response) {
+		let failure = RecipientFailure { recipient, reply };
+
+		if failure.reply.code.is_permanent() {
+			self.rejected_forward_paths.push(failure)
+		} else {
+			self.deferred_forward_paths.push(failure)
+		}
+	}
+
+	fn rcpt(&self, path: ForeignPath, parameters: &Parameters) -> super::Command {
+		let path = ForeignPath(self.outgoing_path(&path.0));
+		Rcpt(path.into(), self.forwarded_parameters(parameters))
+	}
+
+	fn send_forward_path(&mut self, (path, parameters): (ForeignPath, Parameters)) -> Output {
+		self.last_sent_path = Some(path.clone());
+		Output::Command(self.rcpt(path, &parameters))
+	}
+
+	/// Send the message to the recipients the server accepted, as a single
+	/// BDAT chunk if it takes them
+	fn send_message(&mut self) -> Output {
+		if self.extensions.contains(Extension::Chunking) {
+			self.state = State::SentData;
+			Output::Bdat
+		} else {
+			self.state = State::SentForwardPaths;
+			Output::Command(Data)
+		}
+	}
+
+	/// The reply to a pipelined DATA, once every other reply is in
+	fn pipelined_data(&mut self, response: Response) -> Output {
+		if response.code == ResponseCode::StartMailInput {
+			// The server wants a message even if nobody was accepted, so
+			// give it an empty one (RFC 2920 section 3.1)
+			self.state = State::SentData;
+			match self.accepted_forward_paths.is_empty() {
+				true => Output::EmptyData,
+				false => Output::Data,
+			}
+		} else if !self.accepted_forward_paths.is_empty() {
+			self.fail(response)
+		} else {
+			// Every recipient has been dealt with already
+			self.failure = self.mail_failure.take();
+			self.state = State::SentQuit;
+			Output::Command(Quit)
+		}
+	}
+
+	/// Give up on the transaction. Every recipient we haven't already heard
+	/// back about shares the fate of the failed command. Only a 5xx is final;
+	/// anything else, including replies that make no sense where we got them,
+	/// means the recipients can be tried again later.
+	fn fail(&mut self, response: Response) -> Output {
+		let mut remaining: Vec<ForeignPath> = self.envelope.forward_paths.drain(..).collect();
+		remaining.extend(self.last_sent_path.take());
+		remaining.extend(self.pending.drain(..).filter_map(|pending| match pending {
+			Pending::Rcpt(path) => Some(path),
+			_ => None,
+		}));
+		if matches!(
+			self.state,
+			State::SendingForwardPaths
+				| State::Pipelining
+				| State::SentForwardPaths
+				| State::SentData
+		) {
+			// The accepted recipients were waiting on a message that never made it
+			remaining.append(&mut self.accepted_forward_paths);
+		}
+
+		let failures = remaining.into_iter().map(|recipient| RecipientFailure {
+			recipient,
+			reply: response.clone(),
+		});
+
+		if response.code.is_permanent() {
+			self.rejected_forward_paths.extend(failures);
+		} else {
+			self.deferred_forward_paths.extend(failures);
+		}
+
+		self.failure = Some(response);
+		self.state = State::SentQuit;
+		Output::Command(Quit)
+	}
+
+	/// Give up because the server replied to something we didn't send. We
+	/// can't tell which command it meant, so try again later.
+	fn unsolicited(&mut self) -> Output {
+		self.fail(Response::with_message(
+			ResponseCode::ServiceNotAvailable,
+			"The server sent a reply we didn't ask for",
+		))
+	}
+
+	fn process_reply(&mut self, reply: &[u8]) -> Option<Output> {
+		// The text of a reply is only for people, so it doesn't matter much
+		// if some of it is lost
+		let parsed = String::from_utf8_lossy(reply).parse::<Response>();
 
 		// we MUST only exit when we receive a reply from the server
 		if self.state == State::SentQuit {
-			if code != ResponseCode::ServiceClosing {
+			if !matches!(parsed, Ok(ref response) if response.code == ResponseCode::ServiceClosing)
+			{
 				// RFC says server MUST send the 221 service closing
 				// we're still allowed to exit if it's not 221
 				eprintln!("server sent something other than a 221 to our quit.");
@@ -90,62 +429,158 @@ impl Client {
 			return None;
 		}
 
+		let response = match parsed {
+			Ok(response) => response,
+			Err(e) => {
+				// We can't know what the server meant, so try again later
+				return Some(self.fail(Response::with_message(
+					ResponseCode::ServiceNotAvailable,
+					format!("The server sent a malformed reply: {}", e),
+				)));
+			}
+		};
+		let code: ResponseCode = response.code;
+
 		Some(match self.state {
 			State::Initiated => match code {
 				ResponseCode::ServiceReady => {
 					self.state = State::Greeted;
 					Output::Command(Ehlo("Sail".parse().unwrap())) //todo: use actual hostname, not Sail
 				}
-				_ => todo!(),
+				_ => self.fail(response),
 			},
 			State::Greeted => match code {
-				ResponseCode::Okay => {
-					self.state = State::SentReversePath;
-					Output::Command(Mail(self.envelope.reverse_path.clone()))
+				code if code.is_completion() => {
+					self.extensions = Extensions::from_ehlo(&response);
+
+					// Opportunistic TLS, as long as we haven't already
+					if !self.secure
+						&& !self.skip_starttls
+						&& self.extensions.contains(Extension::StartTls)
+					{
+						self.state = State::SentStartTls;
+						Output::Command(StartTls)
+					} else {
+						self.authenticate()
+					}
+				}
+				// Old servers don't know EHLO (RFC 5321 section 3.2)
+				code if code.is_permanent() => {
+					self.state = State::SentHelo;
+					Output::Command(Helo("Sail".parse().unwrap()))
+				}
+				_ => self.fail(response),
+			},
+			State::SentHelo => match code {
+				code if code.is_completion() => {
+					self.extensions = Extensions::default();
+					self.authenticate()
+				}
+				_ => self.fail(response),
+			},
+			State::SentStartTls => match code {
+				ResponseCode::ServiceReady => {
+					self.state = State::StartingTls;
+					return None;
 				}
-				_ => todo!(),
+				_ if self.require_tls => self.fail(response),
+				// The server changed its mind, carry on in plaintext
+				_ => self.send_reverse_path(),
+			},
+			State::SentAuth => match code {
+				ResponseCode::AuthSucceeded => self.send_reverse_path(),
+				_ => self.fail(response),
 			},
 			State::SentReversePath => match code {
-				ResponseCode::Okay => {
+				code if code.is_completion() => {
 					self.state = State::SendingForwardPaths;
-					Output::Command(Rcpt(self.envelope.forward_paths.pop()?.into()))
+					match self.next_forward_path() {
+						Some(next) => self.send_forward_path(next),
+						None => {
+							self.state = State::SentQuit;
+							Output::Command(Quit)
+						}
+					}
 				}
-				_ => todo!(),
+				_ => self.fail(response),
 			},
 			State::SendingForwardPaths => {
-				if code.is_negative() {
-					self.invalid_forward();
+				let Some(recipient) = self.last_sent_path.take() else {
+					return Some(self.unsolicited());
+				};
+
+				// 250 and 251 both mean the server will take it
+				if code.is_completion() {
+					self.accepted_forward_paths.push(recipient);
+				} else {
+					self.recipient_failed(recipient, response);
 				}
 
-				if let Some(path) = self.envelope.forward_paths.pop() {
-					self.last_sent_path = Some(path.clone());
-					Output::Command(Rcpt(path.into()))
+				if let Some(next) = self.next_forward_path() {
+					self.send_forward_path(next)
+				} else if self.accepted_forward_paths.is_empty() {
+					// Nobody to send the message to
+					self.state = State::SentQuit;
+					Output::Command(Quit)
 				} else {
-					self.state = State::SentForwardPaths;
-					Output::Command(Data)
+					self.send_message()
 				}
 			}
-			State::SentForwardPaths => {
-				if code.is_negative() {
-					self.invalid_forward();
+			State::Pipelining => match self.pending.pop_front() {
+				Some(Pending::Mail) => {
+					if !code.is_completion() {
+						self.mail_failure = Some(response);
+					}
+					return None;
 				}
+				Some(Pending::Rcpt(path)) => {
+					match &self.mail_failure {
+						// Without a transaction the recipients go the way of the MAIL
+						Some(failure) => self.recipient_failed(path, failure.clone()),
+						None if code.is_completion() => self.accepted_forward_paths.push(path),
+						None => self.recipient_failed(path, response),
+					}
+
+					// Without DATA in the pipeline, the last RCPT ends it
+					if !self.pending.is_empty() {
+						return None;
+					}
 
-				match code {
-					ResponseCode::StartMailInput => {
-						self.state = State::SentData;
-						Output::Data(self.envelope.data.to_string())
+					match self.accepted_forward_paths.is_empty() {
+						true => {
+							self.failure = self.mail_failure.take();
+							self.state = State::SentQuit;
+							Output::Command(Quit)
+						}
+						false => self.send_message(),
 					}
-					_ => todo!(),
 				}
-			}
+				Some(Pending::Data) | None => self.pipelined_data(response),
+			},
+			State::SentForwardPaths => match code {
+				ResponseCode::StartMailInput => {
+					self.state = State::SentData;
+					Output::Data
+				}
+				_ => self.fail(response),
+			},
 			State::SentData => match code {
-				ResponseCode::Okay => {
+				// We only sent an empty message to finish off the pipeline
+				_ if self.accepted_forward_paths.is_empty() => {
+					self.failure = self.mail_failure.take();
 					self.state = State::SentQuit;
 					Output::Command(Quit)
 				}
-				_ => todo!(),
+				code if code.is_completion() => {
+					self.accepted_reply = Some(response);
+					self.state = State::SentQuit;
+					Output::Command(Quit)
+				}
+				// Nothing was delivered, so everyone that was accepted failed
+				_ => self.fail(response),
 			},
-			State::SentQuit => unreachable!(), // handled above
+			State::SentQuit => unreachable!(),    // handled above
+			State::StartingTls => unreachable!(), // we wait for the handshake
 			State::ShouldExit => unreachable!(),
 		})
 	}
@@ -155,29 +590,685 @@ impl Client {
 	}
 }
 
+/// A recipient the server wouldn't take, and what it said about it
+#[derive(Clone, Debug)]
+pub struct RecipientFailure {
+	pub recipient: ForeignPath,
+	pub reply: Response,
+}
+
 #[derive(Clone, Copy, PartialEq, Default)]
 enum State {
 	#[default]
 	Initiated,
 	Greeted,
+	SentHelo,
+	SentStartTls,
+	StartingTls,
+	SentAuth,
 	SentReversePath,
 	SendingForwardPaths,
+	/// We sent MAIL, every RCPT, and DATA (unless we're going to use BDAT)
+	/// together and are matching up the replies
+	Pipelining,
 	SentForwardPaths,
 	SentData,
 	SentQuit,
 	ShouldExit,
 }
 
+/// A pipelined command we haven't had the reply to yet
+#[derive(Clone)]
+enum Pending {
+	Mail,
+	Rcpt(ForeignPath),
+	Data,
+}
+
 pub enum Output {
 	Command(super::Command),
-	Data(String),
+	/// Commands to send together without waiting for replies in between
+	Batch(Vec<super::Command>),
+	/// The message, dot-stuffed with a [DataEncoder] as it's sent
+	Data,
+	/// An empty message, to finish off a pipeline when nobody was accepted
+	EmptyData,
+	/// The whole message as a single BDAT chunk, which needs no dot-stuffing
+	Bdat,
 }
 
-impl Display for Output {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Output {
+	/// Whether this ends with the message, so the reply to it can take a
+	/// while (RFC 5321 section 4.5.3.2.6)
+	pub fn is_message(&self) -> bool {
+		matches!(self, Self::Data | Self::EmptyData | Self::Bdat)
+	}
+
+	/// Exactly what to write to the server. The client doesn't hold the
+	/// message, so for [Output::Data] and [Output::Bdat] that's up to
+	/// whoever does.
+	pub fn to_bytes(&self) -> Vec<u8> {
 		match self {
-			Self::Command(command) => write!(f, "{}\r\n", command),
-			Self::Data(data) => write!(f, "{}\r\n.\r\n", data),
+			Self::Command(command) => format!("{}\r\n", command).into_bytes(),
+			Self::Batch(commands) => commands
+				.iter()
+				.flat_map(|command| format!("{}\r\n", command).into_bytes())
+				.collect(),
+			// Nothing but the terminator
+			Self::EmptyData => b".\r\n".to_vec(),
+			Self::Data | Self::Bdat => vec![],
 		}
 	}
 }
+
+/// Dot-stuffs a message for DATA a block at a time, so it never has to be
+/// in memory all at once. Lines that start with a period get another so
+/// they aren't mistaken for the end of the data (RFC 5321 section 4.5.2).
+#[derive(Clone, Copy, Debug)]
+pub struct DataEncoder {
+	/// The last two octets, to know where lines start across blocks
+	last: [u8; 2],
+}
+
+impl Default for DataEncoder {
+	fn default() -> Self {
+		// The data starts at the start of a line
+		Self { last: *b"\r\n" }
+	}
+}
+
+impl DataEncoder {
+	/// The next block of the message, stuffed. It's otherwise sent as is.
+	pub fn encode(&mut self, block: &[u8]) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(block.len() + 8);
+		for byte in block {
+			if *byte == b'.' && self.last == *b"\r\n" {
+				bytes.push(b'.');
+			}
+			bytes.push(*byte);
+			self.last = [self.last[1], *byte];
+		}
+
+		bytes
+	}
+
+	/// The terminator, once the whole message has been encoded. The data's
+	/// own last line break is part of it.
+	pub fn finish(&self) -> &'static [u8] {
+		match self.last == *b"\r\n" {
+			true => b".\r\n",
+			false => b"\r\n.\r\n",
+		}
+	}
+}
+
+/// What we're sending, for logs
+impl Display for Output {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", String::from_utf8_lossy(&self.to_bytes()))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::smtp::{args::ReversePath, Message};
+
+	fn client() -> Client {
+		let path: super::super::args::Path = "<gen@nyble.dev>".parse().unwrap();
+
+		Client::initiate(ForeignEnvelope::from_parts(
+			ReversePath::Regular(path.clone()),
+			vec![ForeignPath(path)],
+			Message::empty(),
+		))
+	}
+
+	#[test]
+	fn opportunistic_starttls() {
+		let mut client = client();
+
+		let ehlo = client.push(b"220 mx.test ready\r\n").unwrap();
+		assert_eq!(ehlo.to_string(), "EHLO Sail\r\n");
+
+		let starttls = client
+			.push(b"250-mx.test greets Sail\r\n250-STARTTLS\r\n250 HELP\r\n")
+			.unwrap();
+		assert_eq!(starttls.to_string(), "STARTTLS\r\n");
+
+		assert!(client.push(b"220 go ahead\r\n").is_none());
+		assert!(client.should_start_tls());
+
+		assert_eq!(client.tls_started().to_string(), "EHLO Sail\r\n");
+		assert!(client.is_secure());
+
+		// Even if the server advertises it again we don't try twice
+		let mail = client
+			.push(b"250-mx.test greets Sail\r\n250-STARTTLS\r\n250 HELP\r\n")
+			.unwrap();
+		assert_eq!(mail.to_string(), "MAIL FROM:<gen@nyble.dev>\r\n");
+	}
+
+	#[test]
+	fn starttls_refused() {
+		let mut client = client();
+
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		client
+			.push(b"250-mx.test greets Sail\r\n250 STARTTLS\r\n")
+			.unwrap();
+
+		let mail = client.push(b"454 TLS not available\r\n").unwrap();
+		assert_eq!(mail.to_string(), "MAIL FROM:<gen@nyble.dev>\r\n");
+		assert!(!client.is_secure());
+	}
+
+	#[test]
+	fn deferred_and_rejected_recipients() {
+		let path = |s: &str| ForeignPath(s.parse().unwrap());
+		let mut client = Client::initiate(ForeignEnvelope::from_parts(
+			"<gen@nyble.dev>".parse().unwrap(),
+			vec![
+				path("<a@mx.test>"),
+				path("<b@mx.test>"),
+				path("<c@mx.test>"),
+			],
+			Message::empty(),
+		));
+
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		client.push(b"250 mx.test\r\n").unwrap();
+		assert_eq!(
+			client.push(b"250 ok\r\n").unwrap().to_string(),
+			"RCPT TO:<c@mx.test>\r\n"
+		);
+		client.push(b"450 mailbox busy\r\n").unwrap();
+		client.push(b"550 no such user\r\n").unwrap();
+		assert_eq!(client.push(b"250 ok\r\n").unwrap().to_string(), "DATA\r\n");
+		client.push(b"354 go ahead\r\n").unwrap();
+		assert_eq!(
+			client.push(b"250 queued\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+
+		assert!(client.failure().is_none());
+		assert_eq!(client.deferred().len(), 1);
+		assert_eq!(client.deferred()[0].recipient.0.to_string(), "<c@mx.test>");
+		assert_eq!(client.rejected().len(), 1);
+		assert_eq!(client.rejected()[0].recipient.0.to_string(), "<b@mx.test>");
+		assert_eq!(
+			client.rejected()[0].reply.code,
+			ResponseCode::PermanentMailFail
+		);
+	}
+
+	#[test]
+	fn transient_failure() {
+		let mut busy = client();
+
+		assert_eq!(
+			busy.push(b"421 mx.test too busy\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+		assert!(busy.failure().unwrap().code.is_transient());
+		assert_eq!(busy.deferred().len(), 1);
+
+		// A failed DATA affects the recipients that were accepted
+		let mut client = client();
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		client.push(b"250 mx.test\r\n").unwrap();
+		client.push(b"250 ok\r\n").unwrap();
+		client.push(b"250 ok\r\n").unwrap();
+		client.push(b"354 go ahead\r\n").unwrap();
+		assert_eq!(
+			client.push(b"451 local error\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+		assert_eq!(client.deferred().len(), 1);
+	}
+
+	#[test]
+	fn forwards_dsn_parameters() {
+		let path: super::super::args::Path = "<gen@nyble.dev>".parse().unwrap();
+		let mut envelope = ForeignEnvelope::from_parts(
+			ReversePath::Regular(path.clone()),
+			vec![],
+			Message::empty(),
+		);
+		envelope.mail_parameters = " RET=HDRS ENVID=QQ314 BODY=8BITMIME".parse().unwrap();
+		envelope.add_recipient_with(
+			ForeignPath(path.clone()),
+			" NOTIFY=SUCCESS ORCPT=rfc822;gen@nyble.dev"
+				.parse()
+				.unwrap(),
+		);
+
+		let mut client = Client::initiate(envelope.clone());
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		assert_eq!(
+			client
+				.push(b"250-mx.test\r\n250 DSN\r\n")
+				.unwrap()
+				.to_string(),
+			"MAIL FROM:<gen@nyble.dev> RET=HDRS ENVID=QQ314\r\n"
+		);
+		assert_eq!(
+			client.push(b"250 ok\r\n").unwrap().to_string(),
+			"RCPT TO:<gen@nyble.dev> NOTIFY=SUCCESS ORCPT=rfc822;gen@nyble.dev\r\n"
+		);
+
+		// Without DSN they stay with us
+		let mut client = Client::initiate(envelope);
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		assert_eq!(
+			client.push(b"250 mx.test\r\n").unwrap().to_string(),
+			"MAIL FROM:<gen@nyble.dev>\r\n"
+		);
+		assert_eq!(
+			client.push(b"250 ok\r\n").unwrap().to_string(),
+			"RCPT TO:<gen@nyble.dev>\r\n"
+		);
+	}
+
+	#[test]
+	fn authenticates_over_tls() {
+		let mut relay = client().with_credentials(Credentials {
+			username: String::from("gen"),
+			password: String::from("hunter2"),
+		});
+
+		relay.push(b"220 relay.test ready\r\n").unwrap();
+		relay
+			.push(b"250-relay.test\r\n250-AUTH PLAIN\r\n250 STARTTLS\r\n")
+			.unwrap();
+		relay.push(b"220 go ahead\r\n");
+		relay.tls_started();
+
+		assert_eq!(
+			relay
+				.push(b"250-relay.test\r\n250 AUTH PLAIN LOGIN\r\n")
+				.unwrap()
+				.to_string(),
+			"AUTH PLAIN AGdlbgBodW50ZXIy\r\n"
+		);
+		assert_eq!(
+			relay.push(b"235 welcome\r\n").unwrap().to_string(),
+			"MAIL FROM:<gen@nyble.dev>\r\n"
+		);
+
+		// A bad password is the server's final word
+		let mut relay = client().with_credentials(Credentials {
+			username: String::from("gen"),
+			password: String::from("wrong"),
+		});
+		relay.push(b"220 relay.test ready\r\n").unwrap();
+		relay.push(b"250-relay.test\r\n250 STARTTLS\r\n").unwrap();
+		relay.push(b"220 go ahead\r\n");
+		relay.tls_started();
+		relay.push(b"250-relay.test\r\n250 AUTH PLAIN\r\n").unwrap();
+		assert_eq!(relay.push(b"535 nope\r\n").unwrap().to_string(), "QUIT\r\n");
+		assert_eq!(relay.rejected().len(), 1);
+	}
+
+	#[test]
+	fn requires_tls() {
+		// Credentials never go out in plaintext
+		let mut relay = client().with_credentials(Credentials {
+			username: String::from("gen"),
+			password: String::from("hunter2"),
+		});
+		relay.push(b"220 relay.test ready\r\n").unwrap();
+		assert_eq!(
+			relay
+				.push(b"250-relay.test\r\n250 AUTH PLAIN\r\n")
+				.unwrap()
+				.to_string(),
+			"QUIT\r\n"
+		);
+		assert_eq!(relay.deferred().len(), 1);
+
+		let mut relay = client().require_tls();
+		relay.push(b"220 relay.test ready\r\n").unwrap();
+		relay.push(b"250-relay.test\r\n250 STARTTLS\r\n").unwrap();
+		assert_eq!(
+			relay
+				.push(b"454 TLS not available\r\n")
+				.unwrap()
+				.to_string(),
+			"QUIT\r\n"
+		);
+		assert!(relay.failure().unwrap().code.is_transient());
+	}
+
+	#[test]
+	fn helo_fallback() {
+		let mut old = client();
+
+		old.push(b"220 old.test ready\r\n").unwrap();
+		assert_eq!(
+			old.push(b"502 command not implemented\r\n")
+				.unwrap()
+				.to_string(),
+			"HELO Sail\r\n"
+		);
+		assert_eq!(
+			old.push(b"250 old.test\r\n").unwrap().to_string(),
+			"MAIL FROM:<gen@nyble.dev>\r\n"
+		);
+		assert!(old.extensions().is_empty());
+
+		// If HELO doesn't work either there's nothing left to try
+		let mut older = client();
+		older.push(b"220 older.test ready\r\n").unwrap();
+		older.push(b"500 what\r\n").unwrap();
+		assert_eq!(older.push(b"554 no\r\n").unwrap().to_string(), "QUIT\r\n");
+		assert_eq!(older.rejected().len(), 1);
+	}
+
+	#[test]
+	fn unexpected_replies() {
+		// A greeting that isn't one
+		let mut strange = client();
+		assert_eq!(
+			strange.push(b"250 hello?\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+		assert_eq!(strange.deferred().len(), 1);
+
+		// Nonsense is treated as something to try again later
+		let mut garbled = client();
+		assert_eq!(
+			garbled.push(b"hello there\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+		assert_eq!(garbled.deferred().len(), 1);
+		assert!(garbled.push(b"221 bye\r\n").is_none());
+		assert!(garbled.should_exit());
+
+		// A rejected MAIL takes everyone with it
+		let mut refused = client();
+		refused.push(b"220 mx.test ready\r\n").unwrap();
+		refused.push(b"250 mx.test\r\n").unwrap();
+		refused.push(b"553 not you\r\n").unwrap();
+		assert_eq!(refused.rejected().len(), 1);
+		assert!(refused.delivered().is_empty());
+	}
+
+	#[test]
+	fn unsolicited_replies() {
+		// The EHLO reply came before we sent EHLO
+		let mut eager = client();
+		assert_eq!(
+			eager
+				.push(b"220 mx.test ready\r\n250 mx.test\r\n")
+				.unwrap()
+				.to_string(),
+			"QUIT\r\n"
+		);
+		assert_eq!(eager.deferred().len(), 1);
+		assert_eq!(
+			eager.failure().unwrap().code,
+			ResponseCode::ServiceNotAvailable
+		);
+
+		// An extra reply to a RCPT
+		let mut chatty = client();
+		chatty.push(b"220 mx.test ready\r\n").unwrap();
+		chatty.push(b"250 mx.test\r\n").unwrap();
+		chatty.push(b"250 ok\r\n").unwrap();
+		assert_eq!(
+			chatty
+				.push(b"250 ok\r\n250 ok again\r\n")
+				.unwrap()
+				.to_string(),
+			"QUIT\r\n"
+		);
+		assert!(chatty.delivered().is_empty());
+		assert_eq!(chatty.deferred().len(), 1);
+		assert!(chatty.push(b"221 bye\r\n").is_none());
+		assert!(chatty.should_exit());
+	}
+
+	#[test]
+	fn delivered_and_stuffed() {
+		let path: super::super::args::Path = "<gen@nyble.dev>".parse().unwrap();
+		let mut data = Message::empty();
+		data.body = b"Subject: dots\r\n\r\n.\r\n..two\r\n".to_vec();
+		let mut client = Client::initiate(ForeignEnvelope::from_parts(
+			ReversePath::Regular(path.clone()),
+			vec![ForeignPath(path)],
+			data,
+		));
+
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		client.push(b"250 mx.test\r\n").unwrap();
+		client.push(b"250 ok\r\n").unwrap();
+		client.push(b"251 will forward\r\n").unwrap();
+		assert!(matches!(
+			client.push(b"354 go ahead\r\n"),
+			Some(Output::Data)
+		));
+		assert!(client.delivered().is_empty());
+
+		client.push(b"250 queued\r\n").unwrap();
+		assert_eq!(client.delivered().len(), 1);
+		assert!(client.deferred().is_empty() && client.rejected().is_empty());
+
+		// Lines can be split across blocks
+		let mut encoder = DataEncoder::default();
+		let mut bytes = encoder.encode(b"Subject: dots\r\n\r\n.\r\n..t");
+		bytes.extend(encoder.encode(b"wo\r"));
+		bytes.extend(encoder.encode(b"\n.end\r\n"));
+		bytes.extend(encoder.finish());
+		assert_eq!(
+			bytes,
+			b"Subject: dots\r\n\r\n..\r\n...two\r\n..end\r\n.\r\n"
+		);
+
+		// 8-bit data goes out exactly as it is
+		let mut encoder = DataEncoder::default();
+		let mut bytes = encoder.encode(b"caf\xe9\r\n.\xff");
+		bytes.extend(encoder.finish());
+		assert_eq!(bytes, b"caf\xe9\r\n..\xff\r\n.\r\n");
+		assert_eq!(DataEncoder::default().finish(), b".\r\n");
+	}
+
+	fn pipelining_client() -> Client {
+		let path = |s: &str| ForeignPath(s.parse().unwrap());
+		let mut client = Client::initiate(ForeignEnvelope::from_parts(
+			"<gen@nyble.dev>".parse().unwrap(),
+			vec![
+				path("<a@mx.test>"),
+				path("<b@mx.test>"),
+				path("<c@mx.test>"),
+			],
+			Message::empty(),
+		));
+
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		assert_eq!(
+			client
+				.push(b"250-mx.test\r\n250 PIPELINING\r\n")
+				.unwrap()
+				.to_string(),
+			"MAIL FROM:<gen@nyble.dev>\r\nRCPT TO:<c@mx.test>\r\nRCPT TO:<b@mx.test>\r\n\
+			RCPT TO:<a@mx.test>\r\nDATA\r\n"
+		);
+
+		client
+	}
+
+	#[test]
+	fn pipelines_envelope() {
+		// Replies can come back one at a time or all together
+		let mut client = pipelining_client();
+		assert!(client.push(b"250 ok\r\n250 ok\r\n").is_none());
+		assert!(client
+			.push(b"550 no such user\r\n451-mailbox\r\n")
+			.is_none());
+		assert!(matches!(
+			client.push(b"451 busy\r\n354 go ahead\r\n"),
+			Some(Output::Data)
+		));
+		assert_eq!(
+			client.push(b"250 queued\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+
+		assert_eq!(client.delivered().len(), 1);
+		assert_eq!(client.delivered()[0].0.to_string(), "<c@mx.test>");
+		assert_eq!(client.rejected()[0].recipient.0.to_string(), "<b@mx.test>");
+		assert_eq!(client.deferred()[0].recipient.0.to_string(), "<a@mx.test>");
+
+		// Nobody accepted, so the server gets an empty message
+		let mut client = pipelining_client();
+		assert_eq!(
+			client
+				.push(b"250 ok\r\n550 no\r\n550 no\r\n550 no\r\n354 go ahead\r\n")
+				.unwrap()
+				.to_string(),
+			".\r\n"
+		);
+		assert_eq!(
+			client
+				.push(b"554 no valid recipients\r\n")
+				.unwrap()
+				.to_string(),
+			"QUIT\r\n"
+		);
+		assert_eq!(client.rejected().len(), 3);
+		assert!(client.failure().is_none());
+	}
+
+	#[test]
+	fn pipelined_mail_refused() {
+		let mut client = pipelining_client();
+		assert_eq!(
+			client
+				.push(b"452 too busy\r\n503 no MAIL\r\n503 no MAIL\r\n503 no MAIL\r\n")
+				.map(|output| output.to_string()),
+			None
+		);
+		assert_eq!(
+			client.push(b"503 no MAIL\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+
+		// Everyone shares the MAIL reply rather than the 503s
+		assert_eq!(client.deferred().len(), 3);
+		assert!(client.rejected().is_empty());
+		assert_eq!(client.failure().unwrap().code.as_code(), 452);
+
+		// A refused DATA takes the accepted recipients with it
+		let mut client = pipelining_client();
+		client.push(b"250 ok\r\n250 ok\r\n250 ok\r\n250 ok\r\n");
+		assert_eq!(
+			client.push(b"451 try later\r\n").unwrap().to_string(),
+			"QUIT\r\n"
+		);
+		assert_eq!(client.deferred().len(), 3);
+	}
+
+	#[test]
+	fn lockstep_without_pipelining() {
+		let mut client = client();
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		assert_eq!(
+			client
+				.push(b"250-mx.test\r\n250 8BITMIME\r\n")
+				.unwrap()
+				.to_string(),
+			"MAIL FROM:<gen@nyble.dev>\r\n"
+		);
+	}
+
+	#[test]
+	fn chunking() {
+		let path = |s: &str| ForeignPath(s.parse().unwrap());
+		let mut envelope = ForeignEnvelope::from_parts(
+			"<gen@nyble.dev>".parse().unwrap(),
+			vec![path("<a@mx.test>"), path("<b@mx.test>")],
+			Message::empty(),
+		);
+		envelope.mail_parameters = " BODY=BINARYMIME".parse().unwrap();
+		envelope.data.body = b".\r\n\x00\xff".to_vec();
+
+		let mut client = Client::initiate(envelope.clone());
+		client.push(b"220 mx.test ready\r\n").unwrap();
+
+		// DATA is left out of the pipeline, since BDAT needs a recipient first
+		assert_eq!(
+			client
+				.push(b"250-mx.test\r\n250-PIPELINING\r\n250-8BITMIME\r\n250-CHUNKING\r\n250 BINARYMIME\r\n")
+				.unwrap()
+				.to_string(),
+			"MAIL FROM:<gen@nyble.dev> BODY=BINARYMIME\r\nRCPT TO:<b@mx.test>\r\nRCPT TO:<a@mx.test>\r\n"
+		);
+
+		// No dot-stuffing, the size says where the chunk ends
+		let bdat = client.push(b"250 ok\r\n550 no such user\r\n250 ok\r\n");
+		assert!(matches!(bdat, Some(Output::Bdat)));
+
+		let quit = client.push(b"250 queued\r\n").unwrap();
+		assert_eq!(quit.to_string(), "QUIT\r\n");
+		assert_eq!(client.delivered().len(), 1);
+		assert_eq!(client.rejected().len(), 1);
+
+		// Binary messages can't go anywhere that can't take them as they are
+		let mut client = Client::initiate(envelope);
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		let quit = client
+			.push(b"250-mx.test\r\n250-8BITMIME\r\n250 CHUNKING\r\n")
+			.unwrap();
+		assert_eq!(quit.to_string(), "QUIT\r\n");
+		assert_eq!(client.rejected().len(), 2);
+	}
+
+	#[test]
+	fn smtputf8_downgrade() {
+		let path = |s: &str| ForeignPath(s.parse().unwrap());
+		let envelope = |recipient: &str| {
+			let mut envelope = ForeignEnvelope::from_parts(
+				"<gen@例子.广告>".parse().unwrap(),
+				vec![path(recipient)],
+				Message::empty(),
+			);
+			envelope.mail_parameters = " SMTPUTF8".parse().unwrap();
+			envelope
+		};
+
+		// A server that supports it gets everything as it is
+		let mut client = Client::initiate(envelope("<用户@例子.广告>"));
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		assert_eq!(
+			client
+				.push(b"250-mx.test\r\n250 SMTPUTF8\r\n")
+				.unwrap()
+				.to_string(),
+			"MAIL FROM:<gen@例子.广告> SMTPUTF8\r\n"
+		);
+
+		// Otherwise domains can be sent as A-labels
+		let mut client = Client::initiate(envelope("<a@例子.广告>"));
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		assert_eq!(
+			client.push(b"250 mx.test\r\n").unwrap().to_string(),
+			"MAIL FROM:<gen@xn--fsqu00a.xn--4rr70v>\r\n"
+		);
+		assert_eq!(
+			client.push(b"250 ok\r\n").unwrap().to_string(),
+			"RCPT TO:<a@xn--fsqu00a.xn--4rr70v>\r\n"
+		);
+
+		// but local parts can't
+		let mut client = Client::initiate(envelope("<用户@例子.广告>"));
+		client.push(b"220 mx.test ready\r\n").unwrap();
+		let quit = client.push(b"250 mx.test\r\n").unwrap();
+		assert_eq!(quit.to_string(), "QUIT\r\n");
+		assert_eq!(
+			client.failure().unwrap().code,
+			ResponseCode::MailboxNameNotAllowed
+		);
+		assert_eq!(client.rejected().len(), 1);
+	}
+}
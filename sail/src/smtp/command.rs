@@ -1,11 +1,17 @@
-use super::args::{Domain, ForwardPath, ParseDomainError, ParsePathError, ReversePath};
+use super::{
+	args::{
+		Domain, ForwardPath, Parameters, ParseDomainError, ParseParameterError, ParsePathError,
+		ReversePath,
+	},
+	Mechanism, ParseMechanismError,
+};
 use thiserror::Error;
 
 pub enum Command {
 	Helo(Domain),
 	Ehlo(Domain),
-	Mail(ReversePath),
-	Rcpt(ForwardPath),
+	Mail(ReversePath, Parameters),
+	Rcpt(ForwardPath, Parameters),
 	Data,
 	Rset,
 	Vrfy(String),
@@ -13,6 +19,15 @@ pub enum Command {
 	Help(String),
 	Noop,
 	Quit,
+	StartTls,
+	/// The mechanism and, optionally, the initial response
+	Auth(Mechanism, Option<String>),
+	/// A chunk of message data, `size` octets long, which follows the command
+	/// line directly (RFC 3030)
+	Bdat {
+		size: usize,
+		last: bool,
+	},
 }
 
 impl std::fmt::Display for Command {
@@ -23,8 +38,8 @@ impl std::fmt::Display for Command {
 			match self {
 				Command::Helo(parameters) => format!("HELO {}", parameters),
 				Command::Ehlo(parameters) => format!("EHLO {}", parameters),
-				Command::Mail(parameters) => format!("MAIL FROM:{}", parameters),
-				Command::Rcpt(parameters) => format!("RCPT TO:{}", parameters),
+				Command::Mail(path, parameters) => format!("MAIL FROM:{}{}", path, parameters),
+				Command::Rcpt(path, parameters) => format!("RCPT TO:{}{}", path, parameters),
 				Command::Data => String::from("DATA"),
 				Command::Rset => String::from("RSET"),
 				Command::Vrfy(parameters) => format!("VRFY {}", parameters),
@@ -32,6 +47,12 @@ impl std::fmt::Display for Command {
 				Command::Help(parameters) => format!("HELP {}", parameters),
 				Command::Noop => String::from("NOOP"),
 				Command::Quit => String::from("QUIT"),
+				Command::StartTls => String::from("STARTTLS"),
+				Command::Auth(mechanism, None) => format!("AUTH {}", mechanism),
+				Command::Auth(mechanism, Some(initial)) =>
+					format!("AUTH {} {}", mechanism, initial),
+				Command::Bdat { size, last: false } => format!("BDAT {}", size),
+				Command::Bdat { size, last: true } => format!("BDAT {} LAST", size),
 			}
 		)
 	}
@@ -53,7 +74,10 @@ impl std::str::FromStr for Command {
 			("MAIL", reverse_path) => {
 				let reverse_path = reverse_path.split_once(':').unwrap_or(("", ""));
 				match (reverse_path.0.to_ascii_uppercase().as_str(), reverse_path.1) {
-					("FROM", reverse_path) => Ok(Command::Mail(reverse_path.trim_end().parse()?)),
+					("FROM", reverse_path) => {
+						let (path, parameters) = Self::split_parameters(reverse_path)?;
+						Ok(Command::Mail(path.parse()?, parameters.parse()?))
+					}
 					_ => Err(ParseCommandError::InvalidCommand),
 				}
 			}
@@ -61,7 +85,10 @@ impl std::str::FromStr for Command {
 			("RCPT", forward_path) => {
 				let forward_path = forward_path.split_once(':').unwrap_or(("", ""));
 				match (forward_path.0.to_ascii_uppercase().as_str(), forward_path.1) {
-					("TO", forward_path) => Ok(Command::Rcpt(forward_path.trim_end().parse()?)),
+					("TO", forward_path) => {
+						let (path, parameters) = Self::split_parameters(forward_path)?;
+						Ok(Command::Rcpt(path.parse()?, parameters.parse()?))
+					}
 					_ => Err(ParseCommandError::InvalidCommand),
 				}
 			}
@@ -73,11 +100,67 @@ impl std::str::FromStr for Command {
 			("HELP", command) => Ok(Command::Help(command.to_owned())),
 			("NOOP", _) => Ok(Command::Noop),
 			("QUIT", "") => Ok(Command::Quit),
+			("STARTTLS", "") => Ok(Command::StartTls),
+			("AUTH", arguments) => match arguments.split_once(' ') {
+				None if arguments.is_empty() => Err(ParseCommandError::InvalidCommand),
+				None => Ok(Command::Auth(arguments.parse()?, None)),
+				Some((mechanism, initial)) => {
+					Ok(Command::Auth(mechanism.parse()?, Some(initial.to_owned())))
+				}
+			},
+			("BDAT", arguments) => {
+				let (size, last) = match arguments.split_once(' ') {
+					None => (arguments, false),
+					Some((size, last)) if last.eq_ignore_ascii_case("LAST") => (size, true),
+					Some(_) => return Err(ParseCommandError::InvalidCommand),
+				};
+
+				// RFC 3030 only allows digits
+				if size.is_empty() || !size.bytes().all(|b| b.is_ascii_digit()) {
+					return Err(ParseCommandError::InvalidCommand);
+				}
+
+				match size.parse() {
+					Ok(size) => Ok(Command::Bdat { size, last }),
+					Err(_) => Err(ParseCommandError::InvalidCommand),
+				}
+			}
 			_ => Err(ParseCommandError::InvalidCommand),
 		}
 	}
 }
 
+impl Command {
+	/// Split the argument of MAIL or RCPT into the path and whatever
+	/// parameters follow it. The path ends at the first closing angle bracket
+	/// that isn't inside a quoted local part.
+	fn split_parameters(argument: &str) -> Result<(&str, &str), ParseCommandError> {
+		let mut quoted = false;
+		let mut escaped = false;
+
+		for (idx, c) in argument.char_indices() {
+			match c {
+				_ if escaped => escaped = false,
+				'\\' if quoted => escaped = true,
+				'"' => quoted = !quoted,
+				'>' if !quoted => {
+					let (path, parameters) = argument.split_at(idx + 1);
+
+					// Parameters must be separated from the path by a space
+					return if parameters.is_empty() || parameters.starts_with(' ') {
+						Ok((path, parameters))
+					} else {
+						Err(ParsePathError::Brackets.into())
+					};
+				}
+				_ => (),
+			}
+		}
+
+		Err(ParsePathError::Brackets.into())
+	}
+}
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum ParseCommandError {
@@ -87,6 +170,10 @@ pub enum ParseCommandError {
 	InvalidPath(#[from] ParsePathError),
 	#[error("invalid domain")]
 	InvalidDomain(#[from] ParseDomainError),
+	#[error("invalid parameter")]
+	InvalidParameter(#[from] ParseParameterError),
+	#[error("unsupported mechanism")]
+	UnsupportedMechanism(#[from] ParseMechanismError),
 }
 
 #[cfg(test)]
@@ -158,6 +245,7 @@ mod test {
 		let rsets = case_modifier("rset");
 		let noops = case_modifier("noop");
 		let quits = case_modifier("quit");
+		let starttlses = case_modifier("starttls");
 
 		for data in datas {
 			Command::from_str(&data).unwrap();
@@ -171,7 +259,116 @@ mod test {
 		for quit in quits {
 			Command::from_str(&quit).unwrap();
 		}
+		for starttls in starttlses {
+			Command::from_str(&starttls).unwrap();
+		}
 	}
+	#[test]
+	fn mail_and_rcpt_parameters() {
+		let command = Command::from_str("MAIL FROM:<a@b> SIZE=1000 BODY=8BITMIME").unwrap();
+		match command {
+			Command::Mail(ReversePath::Regular(path), parameters) => {
+				assert_eq!(path.to_string(), "<a@b>");
+				assert_eq!(
+					parameters.get("size").unwrap().value.as_deref(),
+					Some("1000")
+				);
+				assert_eq!(
+					parameters.get("BODY").unwrap().value.as_deref(),
+					Some("8BITMIME")
+				);
+			}
+			_ => panic!("expected MAIL with a regular path"),
+		}
+
+		// Quoted local parts may contain the closing bracket and spaces
+		let command = Command::from_str("RCPT TO:<\"us> er\"@b> NOTIFY=NEVER XFLAG").unwrap();
+		match command {
+			Command::Rcpt(ForwardPath::Regular(path), parameters) => {
+				assert_eq!(path.to_string(), "<\"us> er\"@b>");
+				assert_eq!(parameters.0.len(), 2);
+				assert_eq!(parameters.get("xflag").unwrap().value, None);
+			}
+			_ => panic!("expected RCPT with a regular path"),
+		}
+
+		assert_eq!(
+			Command::from_str("MAIL FROM:<> SIZE=1 BODY=7BIT")
+				.unwrap()
+				.to_string(),
+			"MAIL FROM:<> SIZE=1 BODY=7BIT"
+		);
+	}
+
+	#[test]
+	fn auth() {
+		assert!(matches!(
+			Command::from_str("AUTH plain").unwrap(),
+			Command::Auth(Mechanism::Plain, None)
+		));
+		assert!(matches!(
+			Command::from_str("AUTH LOGIN Z2Vu").unwrap(),
+			Command::Auth(Mechanism::Login, Some(initial)) if initial == "Z2Vu"
+		));
+		assert!(matches!(
+			Command::from_str("AUTH CRAM-MD5"),
+			Err(ParseCommandError::UnsupportedMechanism(_))
+		));
+		assert!(Command::from_str("AUTH").is_err());
+	}
+
+	#[test]
+	fn bdat() {
+		assert!(matches!(
+			Command::from_str("BDAT 1000").unwrap(),
+			Command::Bdat {
+				size: 1000,
+				last: false
+			}
+		));
+		assert!(matches!(
+			Command::from_str("bdat 0 last").unwrap(),
+			Command::Bdat {
+				size: 0,
+				last: true
+			}
+		));
+		assert_eq!(
+			Command::Bdat {
+				size: 12,
+				last: true
+			}
+			.to_string(),
+			"BDAT 12 LAST"
+		);
+
+		for invalid in [
+			"BDAT",
+			"BDAT -1",
+			"BDAT +1",
+			"BDAT 10 FIRST",
+			"BDAT 10 LAST x",
+		] {
+			assert!(Command::from_str(invalid).is_err(), "passed on {}", invalid);
+		}
+	}
+
+	#[test]
+	fn invalid_parameters() {
+		let invalid = [
+			"MAIL FROM:<a@b>SIZE=1",
+			"MAIL FROM:<a@b> SIZE=",
+			"MAIL FROM:<a@b> -SIZE=1",
+			"MAIL FROM:<a@b> SIZE=1=2",
+			"MAIL FROM:<a@b> SIZE=1 size=2",
+			"RCPT TO:<a@b> NOTIFY=\x01",
+		];
+
+		for command in invalid {
+			assert!(Command::from_str(command).is_err(), "passed on {}", command);
+		}
+	}
+
 	//todo: test invalid commands, invalid parameters, etc
 	//todo: once vrfy and expn and help are implemented, test them.
 }
@@ -0,0 +1,824 @@
+use std::{fmt, str::FromStr, time::SystemTime};
+
+use thiserror::Error;
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+use super::{
+	args::{Domain, ForwardPath, Parameters, Path, ReversePath},
+	Envelope, Message, Response,
+};
+
+/// How much of the message to return in a failure report (RFC 3461 section 4.3)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ret {
+	Full,
+	Hdrs,
+}
+
+impl FromStr for Ret {
+	type Err = ParseDsnError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_uppercase().as_str() {
+			"FULL" => Ok(Ret::Full),
+			"HDRS" => Ok(Ret::Hdrs),
+			_ => Err(ParseDsnError::InvalidRet),
+		}
+	}
+}
+
+impl fmt::Display for Ret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Ret::Full => write!(f, "FULL"),
+			Ret::Hdrs => write!(f, "HDRS"),
+		}
+	}
+}
+
+/// When the sender wants to hear about a recipient (RFC 3461 section 4.1).
+/// All false is NEVER.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Notify {
+	pub success: bool,
+	pub failure: bool,
+	pub delay: bool,
+}
+
+impl Notify {
+	/// What we do when the sender doesn't say. The RFC lets us pick between
+	/// FAILURE and FAILURE,DELAY.
+	pub const DEFAULT: Notify = Notify {
+		success: false,
+		failure: true,
+		delay: false,
+	};
+
+	pub fn is_never(&self) -> bool {
+		!self.success && !self.failure && !self.delay
+	}
+}
+
+impl FromStr for Notify {
+	type Err = ParseDsnError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut notify = Notify {
+			success: false,
+			failure: false,
+			delay: false,
+		};
+
+		if s.eq_ignore_ascii_case("NEVER") {
+			return Ok(notify);
+		}
+
+		for keyword in s.split(',') {
+			let flag = match keyword.to_ascii_uppercase().as_str() {
+				"SUCCESS" => &mut notify.success,
+				"FAILURE" => &mut notify.failure,
+				"DELAY" => &mut notify.delay,
+				_ => return Err(ParseDsnError::InvalidNotify),
+			};
+
+			if *flag {
+				return Err(ParseDsnError::InvalidNotify);
+			}
+			*flag = true;
+		}
+
+		Ok(notify)
+	}
+}
+
+impl fmt::Display for Notify {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.is_never() {
+			return write!(f, "NEVER");
+		}
+
+		let keywords: Vec<&str> = [
+			(self.success, "SUCCESS"),
+			(self.failure, "FAILURE"),
+			(self.delay, "DELAY"),
+		]
+		.into_iter()
+		.filter_map(|(set, keyword)| set.then_some(keyword))
+		.collect();
+
+		write!(f, "{}", keywords.join(","))
+	}
+}
+
+/// The address the sender originally gave for a recipient, from ORCPT
+/// (RFC 3461 section 4.2). The address is kept decoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OriginalRecipient {
+	pub address_type: String,
+	pub address: String,
+}
+
+impl OriginalRecipient {
+	pub fn rfc822(path: &Path) -> Self {
+		Self {
+			address_type: String::from("rfc822"),
+			address: path.address(),
+		}
+	}
+}
+
+impl FromStr for OriginalRecipient {
+	type Err = ParseDsnError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (address_type, address) = s.split_once(';').ok_or(ParseDsnError::InvalidOrcpt)?;
+
+		if address_type.is_empty()
+			|| !address_type
+				.bytes()
+				.all(|b| b.is_ascii_alphanumeric() || b == b'-')
+		{
+			return Err(ParseDsnError::InvalidOrcpt);
+		}
+
+		Ok(Self {
+			address_type: address_type.to_owned(),
+			address: xtext_decode(address).ok_or(ParseDsnError::InvalidOrcpt)?,
+		})
+	}
+}
+
+/// Formats as the parameter value, with the address xtext encoded
+impl fmt::Display for OriginalRecipient {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{};{}", self.address_type, xtext_encode(&self.address))
+	}
+}
+
+/// The DSN parameters of MAIL
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MailDsn {
+	pub ret: Option<Ret>,
+	/// The sender's identifier for this transaction, still xtext encoded
+	pub envid: Option<String>,
+}
+
+impl MailDsn {
+	pub fn from_parameters(parameters: &Parameters) -> Result<Self, ParseDsnError> {
+		let ret = match parameters.get("RET") {
+			None => None,
+			Some(ret) => Some(ret.value.as_deref().unwrap_or_default().parse()?),
+		};
+
+		let envid = match parameters.get("ENVID") {
+			None => None,
+			Some(envid) => {
+				let value = envid.value.clone().unwrap_or_default();
+
+				// At most 100 characters (RFC 3461 section 4.4)
+				match xtext_decode(&value) {
+					Some(decoded) if !decoded.is_empty() && decoded.len() <= 100 => Some(value),
+					_ => return Err(ParseDsnError::InvalidEnvid),
+				}
+			}
+		};
+
+		Ok(Self { ret, envid })
+	}
+}
+
+/// The DSN parameters of RCPT
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RcptDsn {
+	pub notify: Option<Notify>,
+	pub orcpt: Option<OriginalRecipient>,
+}
+
+impl RcptDsn {
+	pub fn from_parameters(parameters: &Parameters) -> Result<Self, ParseDsnError> {
+		let notify = match parameters.get("NOTIFY") {
+			None => None,
+			Some(notify) => Some(notify.value.as_deref().unwrap_or_default().parse()?),
+		};
+
+		let orcpt = match parameters.get("ORCPT") {
+			None => None,
+			Some(orcpt) => Some(orcpt.value.as_deref().unwrap_or_default().parse()?),
+		};
+
+		Ok(Self { notify, orcpt })
+	}
+
+	/// What the sender asked for, or our default if they didn't
+	pub fn notify(&self) -> Notify {
+		self.notify.unwrap_or(Notify::DEFAULT)
+	}
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseDsnError {
+	#[error("RET must be FULL or HDRS")]
+	InvalidRet,
+	#[error("ENVID must be valid xtext of at most 100 characters")]
+	InvalidEnvid,
+	#[error("NOTIFY must be NEVER or a list of SUCCESS, FAILURE, and DELAY")]
+	InvalidNotify,
+	#[error("ORCPT must be an address type, a semicolon, and xtext")]
+	InvalidOrcpt,
+}
+
+/// Decode xtext (RFC 3461 section 4), where anything that isn't printable
+/// ASCII, and `+` and `=`, is written as `+` and two uppercase hex digits
+pub fn xtext_decode(s: &str) -> Option<String> {
+	let mut bytes = vec![];
+	let mut chars = s.bytes();
+
+	while let Some(b) = chars.next() {
+		match b {
+			b'+' => {
+				let hex = [chars.next()?, chars.next()?];
+				if !hex
+					.iter()
+					.all(|h| h.is_ascii_digit() || (b'A'..=b'F').contains(h))
+				{
+					return None;
+				}
+
+				bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+			}
+			b'=' => return None,
+			33..=126 => bytes.push(b),
+			_ => return None,
+		}
+	}
+
+	String::from_utf8(bytes).ok()
+}
+
+pub fn xtext_encode(s: &str) -> String {
+	let mut encoded = String::new();
+
+	for b in s.bytes() {
+		match b {
+			b'+' | b'=' => encoded.push_str(&format!("+{:02X}", b)),
+			33..=126 => encoded.push(b as char),
+			_ => encoded.push_str(&format!("+{:02X}", b)),
+		}
+	}
+
+	encoded
+}
+
+/// A Delivery Status Notification (RFC 3464), telling the sender what
+/// happened to their message.
+#[derive(Clone, Debug)]
+pub struct Dsn {
+	/// Us, the MTA that's writing the report
+	pub reporting_mta: Domain,
+	/// When we received the original message
+	pub arrival: Option<SystemTime>,
+	pub recipients: Vec<RecipientStatus>,
+	/// The message the report is about
+	pub original: Message,
+	/// The sender's ENVID, if they gave one
+	pub envid: Option<String>,
+	/// Whether to include the whole message or only its headers. Only
+	/// failure reports ever include the whole message.
+	pub ret: Ret,
+}
+
+/// What happened to a single recipient
+#[derive(Clone, Debug)]
+pub struct RecipientStatus {
+	pub recipient: Path,
+	pub action: Action,
+	/// An enhanced status code (RFC 3463) like `5.1.1`
+	pub status: String,
+	/// What the remote server said, if we got that far
+	pub diagnostic: Option<String>,
+	/// The sender's ORCPT for this recipient, if they gave one
+	pub original_recipient: Option<OriginalRecipient>,
+}
+
+/// The Action field of a per-recipient DSN block (RFC 3464 section 2.3.3)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+	Failed,
+	Delayed,
+	Delivered,
+	Relayed,
+	Expanded,
+}
+
+impl Action {
+	pub fn keyword(&self) -> &'static str {
+		match self {
+			Action::Failed => "failed",
+			Action::Delayed => "delayed",
+			Action::Delivered => "delivered",
+			Action::Relayed => "relayed",
+			Action::Expanded => "expanded",
+		}
+	}
+}
+
+impl RecipientStatus {
+	/// A status built from the server's reply. The enhanced status code is
+	/// taken from the reply if it has one (RFC 2034), otherwise we use the
+	/// generic code for its class.
+	pub fn from_reply(recipient: Path, action: Action, reply: &Response) -> Self {
+		let text = reply.messages().join(" ");
+		let status = text
+			.split_whitespace()
+			.next()
+			.filter(|word| is_status_code(word))
+			.map(<_>::to_owned)
+			.unwrap_or_else(|| format!("{}.0.0", reply.code.as_code() / 100));
+
+		Self {
+			recipient,
+			action,
+			status,
+			diagnostic: Some(format!("{} {}", reply.code, text).trim_end().to_owned()),
+			original_recipient: None,
+		}
+	}
+}
+
+/// True for `class.subject.detail` where class is 2, 4, or 5 and the others
+/// are one to three digits
+fn is_status_code(s: &str) -> bool {
+	let mut parts = s.split('.');
+
+	let class = parts.next();
+	let numeric = |part: Option<&str>| {
+		part.is_some_and(|p| (1..=3).contains(&p.len()) && p.bytes().all(|b| b.is_ascii_digit()))
+	};
+
+	matches!(class, Some("2") | Some("4") | Some("5"))
+		&& numeric(parts.next())
+		&& numeric(parts.next())
+		&& parts.next().is_none()
+}
+
+impl Dsn {
+	/// A report about `envelope` to its sender, for the recipients that asked
+	/// for one with NOTIFY. The sender's ORCPT, RET, and ENVID are used as
+	/// well. Returns None if nobody wants to hear about it.
+	pub fn report(
+		reporting_mta: Domain,
+		envelope: &Envelope,
+		arrival: SystemTime,
+		statuses: Vec<RecipientStatus>,
+	) -> Option<Envelope> {
+		let mut recipients = vec![];
+
+		for mut status in statuses {
+			let dsn = envelope
+				.forward_paths
+				.iter()
+				.zip(envelope.rcpt_parameters.iter())
+				.find(|(forward, _)| match forward {
+					ForwardPath::Regular(path) => path.to_string() == status.recipient.to_string(),
+					ForwardPath::Postmaster => false,
+				})
+				.and_then(|(_, parameters)| RcptDsn::from_parameters(parameters).ok())
+				.unwrap_or_default();
+
+			let notify = dsn.notify();
+			let wanted = match status.action {
+				Action::Failed => notify.failure,
+				Action::Delayed => notify.delay,
+				Action::Delivered | Action::Relayed | Action::Expanded => notify.success,
+			};
+
+			if wanted {
+				status.original_recipient = dsn.orcpt;
+				recipients.push(status);
+			}
+		}
+
+		if recipients.is_empty() {
+			return None;
+		}
+
+		let mail = MailDsn::from_parameters(&envelope.mail_parameters).unwrap_or_default();
+		let dsn = Dsn {
+			reporting_mta,
+			arrival: Some(arrival),
+			recipients,
+			original: envelope.data.clone(),
+			envid: mail.envid,
+			ret: mail.ret.unwrap_or(Ret::Hdrs),
+		};
+
+		dsn.into_envelope(&envelope.reverse_path)
+	}
+
+	/// The report as mail to the original sender. Returns None if the
+	/// original had a null reverse path, because we never bounce a bounce
+	/// (RFC 5321 section 4.5.5).
+	pub fn into_envelope(self, reverse_path: &ReversePath) -> Option<Envelope> {
+		let sender = match reverse_path {
+			ReversePath::Null => return None,
+			ReversePath::Regular(path) => path.clone(),
+		};
+
+		let mut envelope = Envelope::new(ReversePath::Null);
+		envelope.add_recipient(ForwardPath::Regular(sender.clone()));
+		envelope.data = self.to_message(&sender, OffsetDateTime::now_utc());
+
+		Some(envelope)
+	}
+
+	/// Build the multipart/report message (RFC 6522) addressed to `to`
+	pub fn to_message(&self, to: &Path, date: OffsetDateTime) -> Message {
+		let boundary = format!("{}/{}", date.unix_timestamp_nanos(), self.reporting_mta);
+
+		let mut message = Message::empty();
+		message.headers.push(
+			"From",
+			format!(
+				"Mail Delivery System <MAILER-DAEMON@{}>",
+				self.reporting_mta
+			),
+		);
+		message.headers.push("To", to.to_string());
+		message.headers.push("Subject", self.subject());
+		message.headers.push("Date", date.format(&Rfc2822).unwrap());
+		message.headers.push("Auto-Submitted", "auto-replied");
+		message.headers.push("MIME-Version", "1.0");
+		message.headers.push(
+			"Content-Type",
+			format!(
+				"multipart/report; report-type=delivery-status; boundary=\"{}\"",
+				boundary
+			),
+		);
+
+		let mut body = String::new();
+		body.push_str("This is a MIME-encapsulated message.\r\n\r\n");
+
+		// The part for humans. Addresses and diagnostics can be UTF-8 with
+		// SMTPUTF8, and then it's sent as is (RFC 6532 section 3.5)
+		let text = self.human_readable();
+		body.push_str(&format!("--{}\r\n", boundary));
+		body.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+		if !text.is_ascii() {
+			body.push_str("Content-Transfer-Encoding: 8bit\r\n");
+		}
+		body.push_str("\r\n");
+		body.push_str(&text);
+
+		// The part for machines
+		body.push_str(&format!("\r\n--{}\r\n", boundary));
+		body.push_str("Content-Type: message/delivery-status\r\n\r\n");
+		body.push_str(&self.delivery_status());
+
+		body.push_str(&format!("\r\n--{}\r\n", boundary));
+
+		// The original goes back exactly as it came in
+		let mut body = body.into_bytes();
+		if self.ret == Ret::Full && self.has_failures() {
+			body.extend_from_slice(b"Content-Type: message/rfc822\r\n\r\n");
+			body.extend_from_slice(&self.original.to_bytes());
+		} else {
+			body.extend_from_slice(b"Content-Type: text/rfc822-headers\r\n\r\n");
+			body.extend_from_slice(&self.original_headers());
+			body.extend_from_slice(b"\r\n");
+		}
+
+		body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+		message.body = body;
+		message
+	}
+
+	fn has_failures(&self) -> bool {
+		self.recipients.iter().any(|r| r.action == Action::Failed)
+	}
+
+	fn subject(&self) -> &'static str {
+		if self.has_failures() {
+			"Undelivered Mail Returned to Sender"
+		} else if self.recipients.iter().any(|r| r.action == Action::Delayed) {
+			"Delivery Status Notification (Delay)"
+		} else {
+			"Successful Mail Delivery Report"
+		}
+	}
+
+	fn human_readable(&self) -> String {
+		let summary = if self.has_failures() {
+			"Your message could not be delivered to one or more recipients."
+		} else {
+			"This is a report on your message, as you asked for."
+		};
+		let mut text = format!(
+			"This is the mail system at {}.\r\n\r\n{}\r\n\r\n",
+			self.reporting_mta, summary
+		);
+
+		for recipient in &self.recipients {
+			let what = match recipient.action {
+				Action::Failed => "failed",
+				Action::Delayed => "delayed, we will keep trying",
+				Action::Delivered => "delivered",
+				Action::Relayed => "relayed to a server that won't report on it",
+				Action::Expanded => "delivered to an alias",
+			};
+			text.push_str(&format!(
+				"<{}>: {}\r\n",
+				recipient.recipient.address(),
+				what
+			));
+
+			if let Some(diagnostic) = &recipient.diagnostic {
+				text.push_str(&format!("    {}\r\n", diagnostic));
+			}
+		}
+
+		text
+	}
+
+	/// The message/delivery-status part: a per-message block followed by a
+	/// block for each recipient
+	fn delivery_status(&self) -> String {
+		let mut status = String::new();
+		if let Some(envid) = &self.envid {
+			status.push_str(&format!("Original-Envelope-Id: {}\r\n", envid));
+		}
+		status.push_str(&format!("Reporting-MTA: dns; {}\r\n", self.reporting_mta));
+		if let Some(arrival) = self.arrival {
+			let arrival = OffsetDateTime::from(arrival);
+			status.push_str(&format!(
+				"Arrival-Date: {}\r\n",
+				arrival.format(&Rfc2822).unwrap()
+			));
+		}
+
+		for recipient in &self.recipients {
+			status.push_str("\r\n");
+			if let Some(original) = &recipient.original_recipient {
+				status.push_str(&format!(
+					"Original-Recipient: {}; {}\r\n",
+					original.address_type, original.address
+				));
+			}
+			status.push_str(&format!(
+				"Final-Recipient: rfc822; {}\r\n",
+				recipient.recipient.address()
+			));
+			status.push_str(&format!("Action: {}\r\n", recipient.action.keyword()));
+			status.push_str(&format!("Status: {}\r\n", recipient.status));
+
+			if let Some(diagnostic) = &recipient.diagnostic {
+				status.push_str(&format!("Diagnostic-Code: smtp; {}\r\n", diagnostic));
+			}
+		}
+
+		status
+	}
+
+	/// Everything up to the blank line that ends the header section. Unless
+	/// the message has been parsed, the header section is still in the body.
+	fn original_headers(&self) -> Vec<u8> {
+		if !self.original.headers.is_empty() {
+			return self.original.headers.to_bytes();
+		}
+
+		let raw = &self.original.body[..];
+		match raw.windows(4).position(|window| window == b"\r\n\r\n") {
+			Some(end) => raw[..end + 2].to_vec(),
+			None => raw.to_vec(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::smtp::ResponseCode;
+
+	fn report() -> Dsn {
+		let mut original = Message::empty();
+		original.body = b"Subject: hi\r\nFrom: gen@nyble.dev\r\n\r\nhello\r\n".to_vec();
+
+		let mut reply =
+			Response::with_message(ResponseCode::PermanentMailFail, "5.1.1 no such user");
+		reply.push("try again never");
+
+		Dsn {
+			reporting_mta: "nyble.dev".parse().unwrap(),
+			arrival: Some(SystemTime::UNIX_EPOCH),
+			recipients: vec![
+				RecipientStatus::from_reply("<a@mx.test>".parse().unwrap(), Action::Failed, &reply),
+				RecipientStatus::from_reply(
+					"<b@mx.test>".parse().unwrap(),
+					Action::Failed,
+					&Response::with_message(ResponseCode::TransactionFail, "go away"),
+				),
+			],
+			original,
+			envid: None,
+			ret: Ret::Hdrs,
+		}
+	}
+
+	#[test]
+	fn status_codes() {
+		let dsn = report();
+
+		assert_eq!(dsn.recipients[0].status, "5.1.1");
+		assert_eq!(
+			dsn.recipients[0].diagnostic.as_deref(),
+			Some("550 5.1.1 no such user try again never")
+		);
+		assert_eq!(dsn.recipients[1].status, "5.0.0");
+
+		assert!(is_status_code("4.7.0"));
+		assert!(is_status_code("5.1.10"));
+		assert!(!is_status_code("3.1.1"));
+		assert!(!is_status_code("5.1"));
+		assert!(!is_status_code("5.a.1"));
+	}
+
+	#[test]
+	fn report_message() {
+		let sender: Path = "<gen@nyble.dev>".parse().unwrap();
+		let envelope = report()
+			.into_envelope(&ReversePath::Regular(sender))
+			.unwrap();
+
+		assert!(matches!(envelope.reverse_path, ReversePath::Null));
+		assert_eq!(envelope.forward_paths.len(), 1);
+
+		let message = envelope.data.to_string();
+		assert!(message.contains("Content-Type: multipart/report; report-type=delivery-status"));
+		assert!(message.contains("To: <gen@nyble.dev>\r\n"));
+		assert!(message.contains("Content-Type: text/plain; charset=utf-8\r\n\r\n"));
+		assert!(message.contains(
+			"Content-Type: message/delivery-status\r\n\r\nReporting-MTA: dns; nyble.dev\r\n"
+		));
+		assert!(message.contains(
+			"Final-Recipient: rfc822; a@mx.test\r\n\
+			Action: failed\r\n\
+			Status: 5.1.1\r\n\
+			Diagnostic-Code: smtp; 550 5.1.1 no such user try again never\r\n"
+		));
+		assert!(message.contains(
+			"Content-Type: text/rfc822-headers\r\n\r\nSubject: hi\r\nFrom: gen@nyble.dev\r\n\r\n"
+		));
+		assert!(!message.contains("hello"));
+
+		// Internationalized addresses go in the text as they are
+		let mut dsn = report();
+		dsn.recipients[0].recipient = "<用户@例子.广告>".parse().unwrap();
+		let message = dsn
+			.into_envelope(&"<gen@nyble.dev>".parse().unwrap())
+			.unwrap()
+			.data
+			.to_string();
+		assert!(message.contains(
+			"Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n"
+		));
+		assert!(message.contains("用户@例子.广告"));
+
+		// Never bounce a bounce
+		assert!(report().into_envelope(&ReversePath::Null).is_none());
+	}
+
+	#[test]
+	fn parameters() {
+		let mail: Parameters = " RET=hdrs ENVID=QQ+2B314".parse().unwrap();
+		assert_eq!(
+			MailDsn::from_parameters(&mail).unwrap(),
+			MailDsn {
+				ret: Some(Ret::Hdrs),
+				envid: Some(String::from("QQ+2B314")),
+			}
+		);
+		assert_eq!(
+			MailDsn::from_parameters(&" RET=BODY".parse().unwrap()),
+			Err(ParseDsnError::InvalidRet)
+		);
+		assert_eq!(
+			MailDsn::from_parameters(&" ENVID=a+zz".parse().unwrap()),
+			Err(ParseDsnError::InvalidEnvid)
+		);
+
+		let rcpt: Parameters = " NOTIFY=SUCCESS,DELAY ORCPT=rfc822;Gen+2Bdsn@nyble.dev"
+			.parse()
+			.unwrap();
+		let rcpt = RcptDsn::from_parameters(&rcpt).unwrap();
+		let notify = rcpt.notify();
+		assert!(notify.success && notify.delay && !notify.failure);
+		assert_eq!(notify.to_string(), "SUCCESS,DELAY");
+
+		let orcpt = rcpt.orcpt.unwrap();
+		assert_eq!(orcpt.address, "Gen+dsn@nyble.dev");
+		assert_eq!(orcpt.to_string(), "rfc822;Gen+2Bdsn@nyble.dev");
+
+		assert!("NEVER".parse::<Notify>().unwrap().is_never());
+		assert_eq!(RcptDsn::default().notify(), Notify::DEFAULT);
+		assert!("NEVER,SUCCESS".parse::<Notify>().is_err());
+		assert!("FAILURE,FAILURE".parse::<Notify>().is_err());
+		assert!("rfc822".parse::<OriginalRecipient>().is_err());
+	}
+
+	#[test]
+	fn xtext() {
+		assert_eq!(xtext_encode("a+b=c d"), "a+2Bb+3Dc+20d");
+		assert_eq!(xtext_decode("a+2Bb+3Dc+20d").unwrap(), "a+b=c d");
+		assert_eq!(xtext_decode("a+2b"), None);
+		assert_eq!(xtext_decode("a=b"), None);
+		assert_eq!(xtext_decode("a+2"), None);
+	}
+
+	#[test]
+	fn envid_orcpt_and_ret() {
+		let mut dsn = report();
+		dsn.envid = Some(String::from("QQ314"));
+		dsn.ret = Ret::Full;
+		dsn.recipients[0].original_recipient = Some("rfc822;A@mx.test".parse().unwrap());
+
+		let sender: Path = "<gen@nyble.dev>".parse().unwrap();
+		let message = dsn
+			.to_message(&sender, OffsetDateTime::UNIX_EPOCH)
+			.to_string();
+
+		assert!(message.contains("\r\n\r\nOriginal-Envelope-Id: QQ314\r\nReporting-MTA"));
+		assert!(message.contains(
+			"Original-Recipient: rfc822; A@mx.test\r\nFinal-Recipient: rfc822; a@mx.test\r\n"
+		));
+		assert!(message.contains("Content-Type: message/rfc822\r\n\r\nSubject: hi"));
+		assert!(message.contains("hello"));
+
+		// Only failures return the whole message
+		dsn.recipients
+			.iter_mut()
+			.for_each(|r| r.action = Action::Delayed);
+		let message = dsn
+			.to_message(&sender, OffsetDateTime::UNIX_EPOCH)
+			.to_string();
+		assert!(message.contains("Subject: Delivery Status Notification (Delay)"));
+		assert!(!message.contains("hello"));
+	}
+
+	#[test]
+	fn only_who_asked() {
+		let mut envelope = Envelope::new("<gen@nyble.dev>".parse().unwrap());
+		envelope.mail_parameters = " RET=FULL ENVID=QQ314".parse().unwrap();
+		envelope.add_recipient_with(
+			"<a@mx.test>".parse().unwrap(),
+			" NOTIFY=SUCCESS ORCPT=rfc822;alias@nyble.dev"
+				.parse()
+				.unwrap(),
+		);
+		envelope.add_recipient_with("<b@mx.test>".parse().unwrap(), Parameters::new());
+		envelope.add_recipient_with(
+			"<c@mx.test>".parse().unwrap(),
+			" NOTIFY=NEVER".parse().unwrap(),
+		);
+
+		let status = |to: &str, action| RecipientStatus {
+			recipient: to.parse().unwrap(),
+			action,
+			status: String::from("2.0.0"),
+			diagnostic: None,
+			original_recipient: None,
+		};
+		let report = |statuses| {
+			Dsn::report(
+				"nyble.dev".parse().unwrap(),
+				&envelope,
+				SystemTime::UNIX_EPOCH,
+				statuses,
+			)
+			.map(|envelope| envelope.data.to_string())
+		};
+
+		// Only a asked to hear about success
+		let delivered = report(vec![
+			status("<a@mx.test>", Action::Delivered),
+			status("<b@mx.test>", Action::Delivered),
+			status("<c@mx.test>", Action::Delivered),
+		])
+		.unwrap();
+		assert!(delivered.contains("Original-Envelope-Id: QQ314\r\n"));
+		assert!(delivered.contains("Original-Recipient: rfc822; alias@nyble.dev\r\n"));
+		assert!(!delivered.contains("b@mx.test"));
+
+		// b gets the default, failures only, and c never hears anything
+		let failed = report(vec![
+			status("<b@mx.test>", Action::Failed),
+			status("<c@mx.test>", Action::Failed),
+		])
+		.unwrap();
+		assert!(failed.contains("Final-Recipient: rfc822; b@mx.test"));
+		assert!(!failed.contains("c@mx.test"));
+
+		assert!(report(vec![status("<c@mx.test>", Action::Failed)]).is_none());
+		assert!(report(vec![status("<b@mx.test>", Action::Delayed)]).is_none());
+	}
+}
@@ -0,0 +1,256 @@
+use core::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::Response;
+
+/// An SMTP service extension that can be advertised in the reply to EHLO.
+/// See RFC 5321 section 2.2 for how extensions are negotiated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Extension {
+	/// RFC 1870, message size declaration
+	Size,
+	/// RFC 6152, 8bit-MIME transport
+	EightBitMime,
+	/// RFC 2920, command pipelining
+	Pipelining,
+	/// RFC 2034, enhanced error codes
+	EnhancedStatusCodes,
+	/// RFC 6531, internationalized email
+	SmtpUtf8,
+	/// RFC 3207, secure SMTP over TLS
+	StartTls,
+	/// RFC 4954, authentication
+	Auth,
+	/// RFC 3461, delivery status notifications
+	Dsn,
+	/// RFC 3030, transmission of large and binary MIME messages
+	Chunking,
+	/// RFC 3030, binary messages. Only usable with CHUNKING.
+	BinaryMime,
+}
+
+impl Extension {
+	/// The EHLO keyword that advertises this extension
+	pub fn keyword(&self) -> &'static str {
+		match self {
+			Extension::Size => "SIZE",
+			Extension::EightBitMime => "8BITMIME",
+			Extension::Pipelining => "PIPELINING",
+			Extension::EnhancedStatusCodes => "ENHANCEDSTATUSCODES",
+			Extension::SmtpUtf8 => "SMTPUTF8",
+			Extension::StartTls => "STARTTLS",
+			Extension::Auth => "AUTH",
+			Extension::Dsn => "DSN",
+			Extension::Chunking => "CHUNKING",
+			Extension::BinaryMime => "BINARYMIME",
+		}
+	}
+
+	/// The extension that introduces the given command verb, if any. Verbs
+	/// from RFC 5321 itself are not part of any extension.
+	pub fn from_command(verb: &str) -> Option<Self> {
+		match verb.to_ascii_uppercase().as_str() {
+			"STARTTLS" => Some(Extension::StartTls),
+			"AUTH" => Some(Extension::Auth),
+			"BDAT" => Some(Extension::Chunking),
+			_ => None,
+		}
+	}
+
+	/// The extension that introduces the given MAIL parameter keyword, if any.
+	pub fn from_mail_parameter(keyword: &str) -> Option<Self> {
+		match keyword.to_ascii_uppercase().as_str() {
+			"SIZE" => Some(Extension::Size),
+			"BODY" => Some(Extension::EightBitMime),
+			"SMTPUTF8" => Some(Extension::SmtpUtf8),
+			"AUTH" => Some(Extension::Auth),
+			"RET" | "ENVID" => Some(Extension::Dsn),
+			_ => None,
+		}
+	}
+
+	/// The extension a message needs for the given BODY parameter value. 7BIT
+	/// needs nothing, and values we don't know are None too.
+	pub fn from_body(value: &str) -> Option<Self> {
+		match value.to_ascii_uppercase().as_str() {
+			"8BITMIME" => Some(Extension::EightBitMime),
+			"BINARYMIME" => Some(Extension::BinaryMime),
+			_ => None,
+		}
+	}
+
+	/// The extension that introduces the given RCPT parameter keyword, if any.
+	pub fn from_rcpt_parameter(keyword: &str) -> Option<Self> {
+		match keyword.to_ascii_uppercase().as_str() {
+			"NOTIFY" | "ORCPT" => Some(Extension::Dsn),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for Extension {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.keyword())
+	}
+}
+
+impl FromStr for Extension {
+	type Err = ParseExtensionError;
+
+	/// Parses the keyword of an EHLO line, ignoring any parameters after it
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let keyword = s.split_once(' ').map(|(kw, _)| kw).unwrap_or(s);
+
+		match keyword.to_ascii_uppercase().as_str() {
+			"SIZE" => Ok(Extension::Size),
+			"8BITMIME" => Ok(Extension::EightBitMime),
+			"PIPELINING" => Ok(Extension::Pipelining),
+			"ENHANCEDSTATUSCODES" => Ok(Extension::EnhancedStatusCodes),
+			"SMTPUTF8" => Ok(Extension::SmtpUtf8),
+			"STARTTLS" => Ok(Extension::StartTls),
+			"AUTH" => Ok(Extension::Auth),
+			"DSN" => Ok(Extension::Dsn),
+			"CHUNKING" => Ok(Extension::Chunking),
+			"BINARYMIME" => Ok(Extension::BinaryMime),
+			_ => Err(ParseExtensionError::UnknownExtension(keyword.to_owned())),
+		}
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum ParseExtensionError {
+	#[error("'{0}' is not a known extension")]
+	UnknownExtension(String),
+}
+
+/// The set of extensions enabled for a session. Extensions are kept in the
+/// order they were enabled so that EHLO replies are stable.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Extensions {
+	enabled: Vec<Extension>,
+}
+
+impl Extensions {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The extensions a server advertised in its reply to EHLO. The first line
+	/// is the greeting and keywords we don't know are skipped.
+	pub fn from_ehlo(response: &Response) -> Self {
+		response
+			.messages()
+			.iter()
+			.skip(1)
+			.filter_map(|line| line.parse().ok())
+			.collect()
+	}
+
+	/// Enable an extension. Enabling an extension twice does nothing.
+	pub fn enable(&mut self, extension: Extension) {
+		if !self.contains(extension) {
+			self.enabled.push(extension);
+		}
+	}
+
+	pub fn disable(&mut self, extension: Extension) {
+		self.enabled.retain(|ext| *ext != extension);
+	}
+
+	pub fn with(mut self, extension: Extension) -> Self {
+		self.enable(extension);
+		self
+	}
+
+	pub fn contains(&self, extension: Extension) -> bool {
+		self.enabled.contains(&extension)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.enabled.is_empty()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = Extension> + '_ {
+		self.enabled.iter().copied()
+	}
+}
+
+impl FromIterator<Extension> for Extensions {
+	fn from_iter<T: IntoIterator<Item = Extension>>(iter: T) -> Self {
+		let mut extensions = Extensions::new();
+		for extension in iter {
+			extensions.enable(extension);
+		}
+		extensions
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn keyword_roundtrip() {
+		let all = [
+			Extension::Size,
+			Extension::EightBitMime,
+			Extension::Pipelining,
+			Extension::EnhancedStatusCodes,
+			Extension::SmtpUtf8,
+			Extension::StartTls,
+			Extension::Auth,
+			Extension::Dsn,
+			Extension::Chunking,
+			Extension::BinaryMime,
+		];
+
+		for extension in all {
+			assert_eq!(Extension::from_str(extension.keyword()).unwrap(), extension);
+			assert_eq!(
+				Extension::from_str(&extension.keyword().to_ascii_lowercase()).unwrap(),
+				extension
+			);
+		}
+	}
+
+	#[test]
+	fn keyword_with_parameters() {
+		assert_eq!(
+			Extension::from_str("SIZE 10240000").unwrap(),
+			Extension::Size
+		);
+		assert_eq!(
+			Extension::from_str("AUTH PLAIN LOGIN").unwrap(),
+			Extension::Auth
+		);
+		assert!(Extension::from_str("XCLIENT NAME ADDR").is_err());
+	}
+
+	#[test]
+	fn from_ehlo_reply() {
+		let response: Response =
+			"250-foo.com greets bar.com\r\n250-8BITMIME\r\n250-SIZE\r\n250-DSN\r\n250-XEXPS\r\n250 HELP"
+				.parse()
+				.unwrap();
+
+		assert_eq!(
+			Extensions::from_ehlo(&response).iter().collect::<Vec<_>>(),
+			vec![Extension::EightBitMime, Extension::Size, Extension::Dsn]
+		);
+	}
+
+	#[test]
+	fn enable_is_idempotent() {
+		let extensions = Extensions::new()
+			.with(Extension::Size)
+			.with(Extension::Pipelining)
+			.with(Extension::Size);
+
+		assert_eq!(
+			extensions.iter().collect::<Vec<_>>(),
+			vec![Extension::Size, Extension::Pipelining]
+		);
+	}
+}
@@ -4,23 +4,31 @@ use std::str::FromStr;
 use thiserror::Error;
 use time::{format_description::well_known::Rfc2822, OffsetDateTime};
 
-use super::args::{ForeignPath, ForwardPath, ReversePath};
+use super::args::{ForeignPath, ForwardPath, Parameters, ReversePath};
+use crate::imf::{
+	address_list, mailbox_list, message_ids, Address, Headers, Mailbox, MessageId, ParseHeaderError,
+};
 
 #[derive(Clone, Debug, Default)]
 pub struct Message {
-	pub headers: Vec<(String, String)>,
-	pub body: String,
+	pub headers: Headers,
+	/// The message as it was sent to us. It isn't necessarily text, with
+	/// 8BITMIME or BINARYMIME it can be anything. Until the message has been
+	/// parsed, this holds the header section too.
+	pub body: Vec<u8>,
 }
 
 impl Message {
 	pub fn new(date: OffsetDateTime, sender: ReversePath, body: String) -> Self {
-		let headers = vec![
-			(String::from("From"), sender.to_string()),
-			(String::from("Date"), date.format(&Rfc2822).unwrap()),
-		];
+		let mut headers = Headers::new();
+		headers.push("From", sender.to_string());
+		headers.push("Date", date.format(&Rfc2822).unwrap());
 
 		//TODO: break the body at 80
-		Self { headers, body }
+		Self {
+			headers,
+			body: body.into_bytes(),
+		}
 	}
 
 	pub fn new_now(sender: ReversePath, body: String) -> Self {
@@ -33,66 +41,127 @@ impl Message {
 
 	pub fn empty() -> Self {
 		Message {
-			headers: vec![],
-			body: String::new(),
+			headers: Headers::new(),
+			body: vec![],
 		}
 	}
-}
 
-impl FromStr for Message {
-	type Err = ParseMessageError;
+	/// Split a message into its header fields and body (RFC 5322 section
+	/// 2.1). The fields are kept as they were written, so [Message::to_bytes]
+	/// gives back what was parsed.
+	pub fn parse(bytes: &[u8]) -> Result<Self, ParseMessageError> {
+		let (headers, body) = Headers::parse(bytes)?;
 
-	fn from_str(s: &str) -> Result<Self, Self::Err> {
-		let mut lines = s.lines();
+		Ok(Self {
+			headers,
+			body: bytes[body..].to_vec(),
+		})
+	}
 
-		let mut ret = Message::empty();
+	/// The message exactly as it should be written out or sent
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = self.headers.to_bytes();
 
-		// Just findin' the headers
-		for line in &mut lines {
-			if line.is_empty() {
-				// Empty line indicates the beginning of the body, we're done looking for headers
-				break;
-			}
+		if !self.headers.is_empty() {
+			bytes.extend_from_slice(b"\r\n");
+		}
+
+		bytes.extend_from_slice(&self.body);
+		bytes
+	}
+
+	/// Whether the header section is plain ASCII. If it isn't, the message
+	/// needs SMTPUTF8 (RFC 6532).
+	pub fn has_ascii_headers(&self) -> bool {
+		let bytes = self.to_bytes();
+		let mut rest = &bytes[..];
 
-			//TODO: Not unwrap, that's for sure
-			if line.starts_with([' ', '\t']) {
-				// This is a folded line, unfold
-				if let Some((_, body)) = ret.headers.last_mut() {
-					body.push(' ');
-					body.push_str(line.trim_start());
-				} else {
-					return Err(ParseMessageError::MalformedHeaders);
-				}
+		while let Some(end) = line_end(rest) {
+			let line = &rest[..end];
+			if line == b"\r\n" {
+				return true;
 			}
 
-			match line.split_once(':') {
-				None => return Err(ParseMessageError::MalformedHeaders),
-				Some((field, body)) => ret.headers.push((field.to_owned(), body.to_owned())),
+			if !line.is_ascii() {
+				return false;
 			}
+			rest = &rest[end..];
 		}
 
-		ret.body = lines.collect::<Vec<&str>>().join("\r\n");
+		rest.is_ascii()
+	}
+
+	/// When the message was written (RFC 5322 section 3.6.1)
+	pub fn date(&self) -> Option<OffsetDateTime> {
+		let date = self.headers.value("Date")?;
+		OffsetDateTime::parse(&date, &Rfc2822).ok()
+	}
+
+	/// Who wrote the message (section 3.6.2)
+	pub fn from(&self) -> Option<Vec<Mailbox>> {
+		mailbox_list(&self.headers.value("From")?).ok()
+	}
 
-		Ok(ret)
+	/// Who sent the message, if that isn't who wrote it
+	pub fn sender(&self) -> Option<Mailbox> {
+		self.headers.value("Sender")?.parse().ok()
+	}
+
+	/// The primary recipients (section 3.6.3)
+	pub fn to(&self) -> Option<Vec<Address>> {
+		address_list(&self.headers.value("To")?).ok()
+	}
+
+	pub fn cc(&self) -> Option<Vec<Address>> {
+		address_list(&self.headers.value("Cc")?).ok()
+	}
+
+	/// The message's unique identifier (section 3.6.4)
+	pub fn message_id(&self) -> Option<MessageId> {
+		self.headers.value("Message-ID")?.parse().ok()
+	}
+
+	/// The messages this one replies to
+	pub fn in_reply_to(&self) -> Option<Vec<MessageId>> {
+		message_ids(&self.headers.value("In-Reply-To")?).ok()
+	}
+
+	/// The thread this message is in, oldest first
+	pub fn references(&self) -> Option<Vec<MessageId>> {
+		message_ids(&self.headers.value("References")?).ok()
 	}
 }
 
+/// Where the first line in `bytes` ends, just past its CRLF
+pub(crate) fn line_end(bytes: &[u8]) -> Option<usize> {
+	bytes
+		.windows(2)
+		.position(|pair| pair == b"\r\n")
+		.map(|start| start + 2)
+}
+
+impl FromStr for Message {
+	type Err = ParseMessageError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::parse(s.as_bytes())
+	}
+}
+
+/// The message as text, for logs. Use [Message::to_bytes] for anything that
+/// has to be exact.
 impl fmt::Display for Message {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		//TOOD: Conform to the RFC and max line length 80 col
 
-		for (field, body) in &self.headers {
-			write!(f, "{}:{}", field, body)?;
-		}
-
-		write!(f, "{}", self.body)
+		write!(f, "{}", String::from_utf8_lossy(&self.to_bytes()))
 	}
 }
 
-#[derive(Clone, Copy, Debug, Error)]
+#[derive(Debug, Error)]
 pub enum ParseMessageError {
-	#[error("The messages headers were malformed")]
-	MalformedHeaders,
+	#[error("The messages headers were malformed: {0}")]
+	MalformedHeaders(#[from] ParseHeaderError),
 }
 
 #[derive(Default, Clone, Debug)]
@@ -100,6 +169,13 @@ pub struct Envelope {
 	pub reverse_path: ReversePath,
 	pub forward_paths: Vec<ForwardPath>,
 	pub data: Message,
+	/// Parameters given with the MAIL command
+	pub mail_parameters: Parameters,
+	/// Parameters given with each RCPT command, in the same order as
+	/// `forward_paths`
+	pub rcpt_parameters: Vec<Parameters>,
+	/// Who the client authenticated as with AUTH, if they did
+	pub authenticated: Option<String>,
 }
 
 impl Envelope {
@@ -108,76 +184,30 @@ impl Envelope {
 			reverse_path: reverse,
 			forward_paths: vec![],
 			data: Message::empty(),
+			mail_parameters: Parameters::new(),
+			rcpt_parameters: vec![],
+			authenticated: None,
 		}
 	}
 
 	pub fn add_recipient(&mut self, forward_path: ForwardPath) {
-		self.forward_paths.push(forward_path)
+		self.add_recipient_with(forward_path, Parameters::new())
 	}
 
-	pub fn into_parts(self) -> (ReversePath, Vec<ForwardPath>, Message) {
-		let Envelope {
-			reverse_path,
-			forward_paths,
-			data,
-		} = self;
-		(reverse_path, forward_paths, data)
-	}
-
-	/*pub fn into_undeliverable<S: Into<String>>(
-		self,
-		primary_host: Domain,
-		reason: S,
-	) -> Option<Self> {
-		match self.reverse_path {
-			ReversePath::Null => None,
-			ReversePath::Regular(reverse) => {
-				Some(Self::undeliverable(primary_host, reverse, reason.into()))
-			}
-		}
+	pub fn add_recipient_with(&mut self, forward_path: ForwardPath, parameters: Parameters) {
+		self.forward_paths.push(forward_path);
+		self.rcpt_parameters.push(parameters);
 	}
 
-	pub fn undeliverable<S: Into<String>>(
-		primary_host: Domain,
-		reverse_path: Path,
-		reason: S,
-	) -> Self {
-		Self {
-			reverse_path: ReversePath::Null,
-			forward_paths: vec![ForwardPath::Regular(reverse_path)],
-			data: Message::new(
-				SystemTime::now(),
-				ReversePath::Regular(format!("postmaster@{}", primary_host).parse().unwrap()),
-				reason.into(),
-			),
-		}
-	}*/
-
-	pub fn push<S: AsRef<str>>(&mut self, line: S) {
-		self.data.body.push_str(line.as_ref());
+	/// Whether the message was sent as BODY=BINARYMIME
+	pub fn is_binary(&self) -> bool {
+		is_binary(&self.mail_parameters)
 	}
 
-	/// Take in a String and remove leading periods from lines. This function
-	/// does not expect to receive the final ".\r\n" that ends the DATA command,
-	/// but will strip it if it's found.
-	pub fn raw_data(&mut self, raw_data: &str) {
-		// Remove the final \r\n so we don't get an empty string ending our vector
-		let mut lines: Vec<&str> = raw_data.trim_end_matches("\r\n").split("\r\n").collect();
-
-		if lines.ends_with(&["."]) {
-			lines.pop();
-		}
-
-		for line in lines {
-			if line.starts_with('.') {
-				//transparency to allow clients to send \r\n.\r\n without breaking SMTP
-				self.push(line.strip_prefix('.').unwrap())
-			} else {
-				self.push(line)
-			}
-
-			self.push("\r\n");
-		}
+	/// Whether the client sent MAIL with SMTPUTF8, so the addresses and
+	/// header fields can have UTF-8 in them (RFC 6531)
+	pub fn is_utf8(&self) -> bool {
+		self.mail_parameters.get("SMTPUTF8").is_some()
 	}
 }
 
@@ -186,6 +216,10 @@ pub struct ForeignEnvelope {
 	pub reverse_path: ReversePath,
 	pub forward_paths: Vec<ForeignPath>,
 	pub data: Message,
+	/// Parameters from the MAIL command that we received the message with
+	pub mail_parameters: Parameters,
+	/// Parameters from each RCPT, in the same order as `forward_paths`
+	pub rcpt_parameters: Vec<Parameters>,
 }
 
 impl ForeignEnvelope {
@@ -195,11 +229,64 @@ impl ForeignEnvelope {
 		data: Message,
 	) -> Self {
 		Self {
+			rcpt_parameters: vec![Parameters::new(); forward_paths.len()],
 			reverse_path,
 			forward_paths,
 			data,
+			mail_parameters: Parameters::new(),
 		}
 	}
+
+	pub fn add_recipient_with(&mut self, forward_path: ForeignPath, parameters: Parameters) {
+		self.forward_paths.push(forward_path);
+		self.rcpt_parameters.push(parameters);
+	}
+
+	/// Each recipient with the parameters it was given
+	pub fn recipients(&self) -> impl Iterator<Item = (&ForeignPath, &Parameters)> {
+		self.forward_paths.iter().zip(self.rcpt_parameters.iter())
+	}
+
+	/// The parameters a recipient was given, if it's one of ours
+	pub fn parameters_for(&self, path: &ForeignPath) -> Option<&Parameters> {
+		let address = path.0.to_string();
+
+		self.recipients()
+			.find(|(forward, _)| forward.0.to_string() == address)
+			.map(|(_, parameters)| parameters)
+	}
+
+	/// Whether the message was received as BODY=BINARYMIME, so it can only be
+	/// relayed with BDAT (RFC 3030 section 3)
+	pub fn is_binary(&self) -> bool {
+		is_binary(&self.mail_parameters)
+	}
+
+	/// Whether the message was received with SMTPUTF8, so it can only be
+	/// relayed as it is to servers that support it too
+	pub fn is_utf8(&self) -> bool {
+		self.mail_parameters.get("SMTPUTF8").is_some()
+	}
+
+	/// Keep only the recipients for which `keep` is true
+	pub fn retain_recipients<F: FnMut(&ForeignPath) -> bool>(&mut self, mut keep: F) {
+		let (forward_paths, rcpt_parameters) = self
+			.forward_paths
+			.drain(..)
+			.zip(self.rcpt_parameters.drain(..))
+			.filter(|(path, _)| keep(path))
+			.unzip();
+
+		self.forward_paths = forward_paths;
+		self.rcpt_parameters = rcpt_parameters;
+	}
+}
+
+fn is_binary(parameters: &Parameters) -> bool {
+	parameters
+		.get("BODY")
+		.and_then(|body| body.value.as_deref())
+		.is_some_and(|value| value.eq_ignore_ascii_case("BINARYMIME"))
 }
 
 impl Default for ForeignEnvelope {
@@ -208,20 +295,69 @@ impl Default for ForeignEnvelope {
 			reverse_path: ReversePath::Null,
 			forward_paths: vec![],
 			data: Message::default(),
+			mail_parameters: Parameters::new(),
+			rcpt_parameters: vec![],
 		}
 	}
 }
 
 impl From<ForeignEnvelope> for Envelope {
 	fn from(other: ForeignEnvelope) -> Self {
-		Self {
-			reverse_path: other.reverse_path,
-			forward_paths: other
-				.forward_paths
-				.into_iter()
-				.map(|fpath| fpath.into())
-				.collect(),
-			data: other.data,
+		let mut envelope = Self::new(other.reverse_path);
+		envelope.data = other.data;
+		envelope.mail_parameters = other.mail_parameters;
+
+		for (fpath, parameters) in other.forward_paths.into_iter().zip(other.rcpt_parameters) {
+			envelope.add_recipient_with(fpath.into(), parameters);
 		}
+
+		envelope
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const MESSAGE: &[u8] =
+		b"Received: from mx.test\r\n\tby nyble.dev; Fri, 21 Nov 1997 09:55:06 -0600\r\n\
+		From: \"Gen\" <gen@nyble.dev>\r\n\
+		Sender: list@nyble.dev\r\n\
+		To: friends: a@b.example, c@d.example;, e@f.example\r\n\
+		cc: Someone (with a comment) <s@nyble.dev>\r\n\
+		Date: Fri, 21 Nov 1997 09:55:06 -0600\r\n\
+		Message-ID: <1234@nyble.dev>\r\n\
+		In-Reply-To: <1233@nyble.dev>\r\n\
+		References: <1232@nyble.dev>\r\n <1233@nyble.dev>\r\n\
+		\r\n\
+		Body: not a header\r\n";
+
+	#[test]
+	fn round_trip() {
+		let message = Message::parse(MESSAGE).unwrap();
+		assert_eq!(message.headers.len(), 9);
+		assert_eq!(message.body, b"Body: not a header\r\n");
+		assert_eq!(message.to_bytes(), MESSAGE);
+
+		assert!(Message::parse(b"no colon\r\n\r\n").is_err());
+	}
+
+	#[test]
+	fn accessors() {
+		let message = Message::parse(MESSAGE).unwrap();
+
+		assert_eq!(message.date().unwrap().unix_timestamp(), 880127706);
+		assert_eq!(message.from().unwrap()[0].name.as_deref(), Some("Gen"));
+		assert_eq!(message.sender().unwrap().address(), "list@nyble.dev");
+		assert_eq!(message.to().unwrap().len(), 2);
+		assert_eq!(
+			message.cc().unwrap()[0].to_string(),
+			"Someone <s@nyble.dev>"
+		);
+		assert_eq!(message.message_id().unwrap().left, "1234");
+		assert_eq!(message.in_reply_to().unwrap().len(), 1);
+		assert_eq!(message.references().unwrap().len(), 2);
+
+		assert!(Message::empty().date().is_none());
 	}
 }
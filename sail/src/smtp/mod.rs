@@ -1,12 +1,20 @@
 pub mod args;
+mod auth;
 mod client;
 mod command;
+mod dsn;
+mod extension;
 mod message;
 mod response;
 mod server;
 
-pub use client::Client;
+pub use auth::{Credentials, Mechanism, ParseMechanismError, SaslError};
+pub use client::{Client, DataEncoder, Output, RecipientFailure};
 pub use command::Command;
+pub use dsn::{
+	Action, Dsn, MailDsn, Notify, OriginalRecipient, ParseDsnError, RcptDsn, RecipientStatus, Ret,
+};
+pub use extension::{Extension, Extensions, ParseExtensionError};
 pub use message::*;
 pub use response::{Response, ResponseCode};
 pub use server::Server;
@@ -22,31 +30,35 @@ mod test {
 		use super::{
 			super::net,
 			args::{Domain, ForeignPath, Path, ReversePath},
-			Envelope, ForeignEnvelope, Message,
+			ForeignEnvelope, Message,
 		};
 		let path = Path::from_str(&format!("<{}>", var("TRIGGER_EMAIL").unwrap())).unwrap();
 		let forward_paths = vec![ForeignPath(path.clone())];
 		let reverse_path = ReversePath::Regular(path);
 		let data = "".to_string();
 
-		let message = ForeignEnvelope {
+		let message = ForeignEnvelope::from_parts(
+			reverse_path.clone(),
 			forward_paths,
-			reverse_path: reverse_path.clone(),
-			data: Message::new_now(reverse_path, data),
-		};
+			Message::new_now(reverse_path, data),
+		);
+		let mut data = std::io::Cursor::new(message.data.to_bytes());
 		// let (_, rx) = tokio::sync::watch::channel(false);
+		let resolver = net::dns::HickoryResolver::new().unwrap();
 		let future = net::relay(
+			&resolver,
+			&net::Route::Mx,
 			Domain::from_str("oracle.nove.dev").unwrap(),
-			message, /*, rx*/
+			message,
+			&mut data, /*, rx*/
 		);
 
-		let undeliverable: Option<Envelope> = tokio::runtime::Builder::new_current_thread()
+		let result = tokio::runtime::Builder::new_current_thread()
 			.enable_all()
 			.build()
 			.unwrap()
 			.block_on(future);
 
-		dbg!(&undeliverable);
-		assert!(undeliverable.is_none())
+		assert!(result.is_ok(), "relay failed: {:?}", result.err());
 	}
 }
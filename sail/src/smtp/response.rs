@@ -4,6 +4,7 @@ use std::{cmp::Ordering, num::ParseIntError};
 use thiserror::Error;
 
 /// A Response from an SMTP transaction.
+#[derive(Clone, Debug)]
 pub struct Response {
 	pub code: ResponseCode,
 	messages: Vec<String>,
@@ -36,6 +37,11 @@ impl Response {
 		self.code
 	}
 
+	/// The text of each line of the response, without the reply code
+	pub fn messages(&self) -> &[String] {
+		&self.messages
+	}
+
 	/// Overriding that of [std::fmt::Display]. Includes a trailing `\r\n`
 	#[allow(clippy::inherent_to_string_shadow_display)]
 	pub fn to_string(&self) -> String {
@@ -140,10 +146,11 @@ pub enum ParseResponseError {
 
 #[derive(Clone, Copy, Debug)]
 pub enum ResponseCode {
-	UnrecognizedCommand,   // 500
-	InvalidParameters,     // 501
-	CommandNotImplemented, // 502
-	BadCommandSequence,    // 503
+	UnrecognizedCommand,     // 500
+	InvalidParameters,       // 501
+	CommandNotImplemented,   // 502
+	BadCommandSequence,      // 503
+	ParameterNotImplemented, // 504
 
 	SystemStatus,   // 211
 	HelpMessage,    // 214
@@ -169,6 +176,11 @@ pub enum ResponseCode {
 	StartMailInput,  // 354
 	TransactionFail, // 554
 
+	AuthSucceeded, // 235 (RFC 4954)
+	AuthContinue,  // 334 (RFC 4954, server challenge)
+	AuthRequired,  // 530 (RFC 4954)
+	AuthInvalid,   // 535 (RFC 4954, authentication credentials invalid)
+
 	UnknownPositiveCompletion(u16), // 2xx
 	UnknownPositiveWaiting(u16),    // 3xx
 	UnknownNegativeTemporary(u16),  // 4xx
@@ -188,6 +200,7 @@ impl ResponseCode {
 			501 => Some(ResponseCode::InvalidParameters),
 			502 => Some(ResponseCode::CommandNotImplemented),
 			503 => Some(ResponseCode::BadCommandSequence),
+			504 => Some(ResponseCode::ParameterNotImplemented),
 
 			211 => Some(ResponseCode::SystemStatus),
 			214 => Some(ResponseCode::HelpMessage),
@@ -212,6 +225,11 @@ impl ResponseCode {
 
 			354 => Some(ResponseCode::StartMailInput),
 			554 => Some(ResponseCode::TransactionFail),
+
+			235 => Some(ResponseCode::AuthSucceeded),
+			334 => Some(ResponseCode::AuthContinue),
+			530 => Some(ResponseCode::AuthRequired),
+			535 => Some(ResponseCode::AuthInvalid),
 			_ => None,
 		};
 
@@ -230,10 +248,11 @@ impl ResponseCode {
 
 	pub fn as_code(self) -> u16 {
 		match self {
-			ResponseCode::UnrecognizedCommand => 550,
+			ResponseCode::UnrecognizedCommand => 500,
 			ResponseCode::InvalidParameters => 501,
 			ResponseCode::CommandNotImplemented => 502,
 			ResponseCode::BadCommandSequence => 503,
+			ResponseCode::ParameterNotImplemented => 504,
 
 			ResponseCode::SystemStatus => 211,
 			ResponseCode::HelpMessage => 214,
@@ -259,6 +278,11 @@ impl ResponseCode {
 			ResponseCode::StartMailInput => 354,
 			ResponseCode::TransactionFail => 554,
 
+			ResponseCode::AuthSucceeded => 235,
+			ResponseCode::AuthContinue => 334,
+			ResponseCode::AuthRequired => 530,
+			ResponseCode::AuthInvalid => 535,
+
 			// Should these enums carry the value they were created from with
 			// them so we can convert back to a number losslessly?
 			ResponseCode::UnknownPositiveCompletion(code) => code,
@@ -279,6 +303,21 @@ impl ResponseCode {
 
 		first == 2 || first == 3
 	}
+
+	/// A 2xx reply. The command worked.
+	pub fn is_completion(&self) -> bool {
+		self.as_code() / 100 == 2
+	}
+
+	/// A 4xx reply. The same command might work if we try again later.
+	pub fn is_transient(&self) -> bool {
+		self.as_code() / 100 == 4
+	}
+
+	/// A 5xx reply. Trying again won't help.
+	pub fn is_permanent(&self) -> bool {
+		self.as_code() / 100 == 5
+	}
 }
 
 impl std::str::FromStr for ResponseCode {
@@ -328,6 +367,15 @@ mod test {
 		);
 	}
 
+	#[test]
+	fn response_code_round_trip() {
+		// UnrecognizedCommand used to go out as 550, which reads back as a
+		// mailbox error
+		for code in [500, 501, 502, 503, 504, 550, 552, 554] {
+			assert_eq!(ResponseCode::from_code(code).unwrap().as_code(), code);
+		}
+	}
+
 	#[test]
 	fn response_as_string_multiline() {
 		let mut resp = Response::with_message(ResponseCode::Okay, "line1");
@@ -1,15 +1,52 @@
-use crate::policy::Policy;
+use std::io::Write;
+
+use crate::policy::{MessageSink, Policy, Rejection};
 
 use super::{
-	args::{Domain, ForwardPath, ReversePath},
-	Command, Envelope, Response, ResponseCode,
+	args::{Domain, ForwardPath, Parameters, ReversePath},
+	auth::{Exchange, Step},
+	message::line_end,
+	Command, Envelope, Extension, Extensions, MailDsn, Mechanism, RcptDsn, Response, ResponseCode,
 };
 
+/// The longest line we'll take outside of message data, CRLF included. RFC
+/// 5321 section 4.5.3.1.4 allows 512 octets for a command line, but
+/// extension parameters and AUTH responses can make them longer.
+const MAX_LINE_LENGTH: usize = 4096;
+
 pub struct Server {
 	policy: Box<dyn Policy>,
 	state: State,
-	command: String,
+	/// What the client has sent that we haven't handled yet. Pipelining
+	/// clients can send several commands at once (RFC 2920).
+	input: Vec<u8>,
+	/// Whether we're throwing away a line that went over [MAX_LINE_LENGTH]
+	/// until its CRLF turns up
+	discarding_line: bool,
+	/// Where the data of the message being received goes
+	sink: Option<Box<dyn MessageSink>>,
+	/// Octets of message data received so far
+	data_size: usize,
+	/// Whether the next message data starts a new line, where dot-stuffing
+	/// and the end of the data can be
+	line_start: bool,
+	/// Why the message being received will be refused, like it being over
+	/// the size limit. The rest of its data is thrown away.
+	data_failure: Option<Response>,
+	/// The BDAT chunk being read, if any
+	chunk: Option<Chunk>,
 	message: Envelope,
+	/// Extensions in effect for this session. Empty until the client sends
+	/// EHLO, and cleared again if it falls back to HELO.
+	extensions: Extensions,
+	/// Whether the session is running over TLS
+	secure: bool,
+	/// Whether the client must AUTH before it may send MAIL
+	require_auth: bool,
+	/// Who the client has authenticated as
+	authenticated: Option<String>,
+	/// A SASL exchange waiting on a reply from the client
+	exchange: Option<Exchange>,
 }
 
 impl Server {
@@ -24,74 +61,298 @@ impl Server {
 		let this = Self {
 			policy,
 			state: State::Initiated,
-			command: Default::default(),
+			input: Default::default(),
+			discarding_line: false,
+			sink: None,
+			data_size: 0,
+			line_start: true,
+			data_failure: None,
+			chunk: None,
 			message: Default::default(),
+			extensions: Default::default(),
+			secure: false,
+			require_auth: false,
+			authenticated: None,
+			exchange: None,
 		};
 
 		(this, response)
 	}
 
-	pub fn push(&mut self, line: &str) -> Option<Response> {
-		self.command.push_str(line);
+	/// Give the server what the client sent. Every complete line is handled
+	/// in order, and the replies come back in the same order.
+	pub fn push(&mut self, input: &[u8]) -> Vec<Response> {
+		self.input.extend_from_slice(input);
 
-		// Return early if it's not a line
-		if !self.command.ends_with("\r\n") {
-			return None;
+		let mut responses = vec![];
+		loop {
+			// Anything sent after STARTTLS but before the handshake must be
+			// thrown away (RFC 3207 section 4.2)
+			if self.should_start_tls() || self.should_exit() {
+				break;
+			}
+
+			// A chunk is raw octets, even if it's empty, so it's read before
+			// anything is taken as a command
+			if self.chunk.is_some() {
+				match self.loading_chunk() {
+					Some(response) => responses.push(response),
+					None => break,
+				}
+				continue;
+			}
+
+			if self.state == State::LoadingData {
+				match self.loading_data() {
+					Some(response) => responses.push(response),
+					None => break,
+				}
+				continue;
+			}
+
+			let Some(end) = line_end(&self.input) else {
+				self.overlong_line();
+				break;
+			};
+
+			let line: Vec<u8> = self.input.drain(..end).collect();
+			if self.discarding_line || line.len() > MAX_LINE_LENGTH {
+				self.discarding_line = false;
+				self.exchange = None;
+				responses.push(Self::line_too_long());
+				continue;
+			}
+
+			let response = if let Some(exchange) = self.exchange.take() {
+				// Anything that isn't text can't be base64 either, and will fail
+				Some(self.continue_auth(exchange, &String::from_utf8_lossy(&line)))
+			} else {
+				match std::str::from_utf8(&line) {
+					Ok(line) => self.run_command(line),
+					Err(_) => Some(Self::syntax_error()),
+				}
+			};
+
+			responses.extend(response);
 		}
 
-		if self.state == State::LoadingData {
-			self.loading_data()
-		} else {
-			let resp = self.run_command();
-			self.command.clear();
+		responses
+	}
 
-			Some(resp)
+	/// Throw away what we have of a line that's already too long, so a
+	/// client can't make us buffer without end. The CR is kept in case its
+	/// LF is in the next push.
+	fn overlong_line(&mut self) {
+		if self.input.len() <= MAX_LINE_LENGTH {
+			return;
 		}
+
+		self.discarding_line = true;
+		let keep = match self.input.last() {
+			Some(b'\r') => 1,
+			_ => 0,
+		};
+		self.input.drain(..self.input.len() - keep);
 	}
 
 	pub fn should_exit(&self) -> bool {
 		self.state == State::Exit
 	}
 
+	/// The extensions negotiated with the client in this session
+	pub fn extensions(&self) -> &Extensions {
+		&self.extensions
+	}
+
+	/// True once we've agreed to STARTTLS. The caller should perform the TLS
+	/// handshake and then call [Server::tls_started].
+	pub fn should_start_tls(&self) -> bool {
+		self.state == State::StartingTls
+	}
+
+	/// Tell the server the connection is now encrypted. As per RFC 3207
+	/// section 4.2, everything we knew about the client is discarded and the
+	/// session starts over, so the client must send EHLO again.
+	pub fn tls_started(&mut self) {
+		self.secure = true;
+		self.state = State::Initiated;
+		self.input.clear();
+		self.sink = None;
+		self.chunk = None;
+		self.message = Envelope::default();
+		self.extensions = Extensions::default();
+		self.exchange = None;
+	}
+
+	pub fn is_secure(&self) -> bool {
+		self.secure
+	}
+
+	/// Refuse MAIL until the client has authenticated. For submission
+	/// listeners (RFC 6409 section 4.3)
+	pub fn require_auth(&mut self, required: bool) {
+		self.require_auth = required;
+	}
+
+	/// Who the client has authenticated as, if anyone
+	pub fn authenticated(&self) -> Option<&str> {
+		self.authenticated.as_deref()
+	}
+
+	/// Pass as much of the input as we can on to the sink, without waiting
+	/// for whole lines. Returns the reply once the data is complete.
 	fn loading_data(&mut self) -> Option<Response> {
-		if self.command.ends_with("\r\n.\r\n") {
-			self.message.raw_data(&self.command);
+		let input = std::mem::take(&mut self.input);
+		let mut rest = &input[..];
 
-			self.command.clear();
+		let response = loop {
+			if self.line_start && rest.starts_with(b".") {
+				if rest.starts_with(b".\r\n") {
+					rest = &rest[3..];
+					break Some(self.got_data());
+				}
 
-			// Data is complete
-			Some(self.got_data())
-		} else {
-			None
+				// We can't tell yet if this is the end of the data
+				if b".\r\n".starts_with(rest) {
+					break None;
+				}
+
+				// Transparency (RFC 5321 section 4.5.2)
+				rest = &rest[1..];
+			}
+
+			let (end, line_start) = match line_end(rest) {
+				Some(end) => (end, true),
+				// Hold back a CR that might be the start of a line ending
+				None if rest.ends_with(b"\r") => (rest.len() - 1, false),
+				None => (rest.len(), false),
+			};
+
+			if end == 0 {
+				break None;
+			}
+
+			self.write_data(&rest[..end]);
+			self.line_start = line_start;
+			rest = &rest[end..];
+		};
+
+		self.input = rest.to_vec();
+		response
+	}
+
+	/// Pass as much of the current BDAT chunk as we have on to the sink.
+	/// Returns the reply once all of it is in.
+	fn loading_chunk(&mut self) -> Option<Response> {
+		let chunk = self.chunk.as_mut()?;
+		let available = chunk.remaining.min(self.input.len());
+		chunk.remaining -= available;
+
+		let data: Vec<u8> = self.input.drain(..available).collect();
+		if self.chunk.as_ref()?.refused.is_none() {
+			self.write_data(&data);
+		}
+
+		if self.chunk.as_ref()?.remaining > 0 {
+			return None;
+		}
+
+		let chunk = self.chunk.take()?;
+		let response = match (chunk.refused, self.data_failure.take()) {
+			(Some(refused), _) => refused,
+			(None, failure) if chunk.last => {
+				self.data_failure = failure;
+				self.got_data()
+			}
+			// The transaction is over as soon as a chunk fails, rather than
+			// waiting for the last one (RFC 3030 section 2)
+			(None, Some(failure)) => {
+				self.rset();
+				failure
+			}
+			(None, None) => Response::with_message(
+				ResponseCode::Okay,
+				format!("{} octets received", chunk.size),
+			),
+		};
+
+		Some(response)
+	}
+
+	fn write_data(&mut self, data: &[u8]) {
+		self.data_size += data.len();
+		if self.data_failure.is_some() {
+			return;
+		}
+
+		if self
+			.policy
+			.max_message_size()
+			.is_some_and(|max| self.data_size > max)
+		{
+			self.sink = None;
+			self.data_failure = Some(Self::message_too_large());
+			return;
+		}
+
+		if let Some(sink) = &mut self.sink {
+			if let Err(e) = sink.write_all(data) {
+				eprintln!("Failed to write message data: {}", e);
+				self.sink = None;
+				self.data_failure = Some(Self::processing_error());
+			}
 		}
 	}
 
 	fn got_data(&mut self) -> Response {
 		//TODO: Fail here if the mail data fails verification as per RFC 5322
 
-		let response = self.policy.message_received(self.message.clone());
+		let response = match (self.data_failure.take(), self.sink.take()) {
+			(None, Some(sink)) => self.policy.message_received(self.message.clone(), sink),
+			(Some(failure), _) => failure,
+			(None, None) => Self::processing_error(),
+		};
+
 		self.rset();
 		response
 	}
 
-	fn run_command(&mut self) -> Response {
-		let command = self.command.trim_end().parse();
+	/// Returns None when the reply has to wait, like for the data of a BDAT
+	/// chunk.
+	fn run_command(&mut self, line: &str) -> Option<Response> {
+		let line = line.trim_end();
 
-		match command {
+		// Commands that belong to an extension are only recognized if that
+		// extension was advertised in our reply to EHLO.
+		let verb = line.split_once(' ').map(|(verb, _)| verb).unwrap_or(line);
+		if let Some(extension) = Extension::from_command(verb) {
+			if !self.extensions.contains(extension) {
+				return Some(Self::syntax_error());
+			}
+		}
+
+		let command = line.parse();
+
+		let response = match command {
 			Ok(command) => match command {
 				Command::Helo(client_domain) => self.helo(&client_domain),
 				Command::Ehlo(client_domain) => self.ehlo(&client_domain),
-				Command::Mail(reverse_path) => self.mail(&reverse_path),
-				Command::Rcpt(forward_path) => self.rcpt(&forward_path),
+				Command::Mail(reverse_path, parameters) => self.mail(&reverse_path, parameters),
+				Command::Rcpt(forward_path, parameters) => self.rcpt(&forward_path, parameters),
 				Command::Data => self.data(),
 				Command::Rset => self.rset(),
-				Command::Vrfy(_) => todo!(),
-				Command::Expn(_) => Self::not_implemented(),
+				Command::Vrfy(_) | Command::Expn(_) => Self::not_implemented(),
 				Command::Help(_) => {
 					Response::with_message(ResponseCode::HelpMessage, "Please review RFC 5321")
 				}
 				Command::Noop => Response::with_message(ResponseCode::Okay, "Okay"),
 				Command::Quit => self.quit(),
+				Command::StartTls => self.starttls(),
+				Command::Auth(mechanism, initial) => self.auth(mechanism, initial),
+				Command::Bdat { size, last } => {
+					self.bdat(size, last);
+					return None;
+				}
 			},
 			Err(err) => match err {
 				super::command::ParseCommandError::InvalidCommand => Self::syntax_error(),
@@ -102,8 +363,17 @@ impl Server {
 					ResponseCode::InvalidParameters,
 					format!("Bad domain: {}", err),
 				),
+				super::command::ParseCommandError::InvalidParameter(err) => Response::with_message(
+					ResponseCode::InvalidParameters,
+					format!("Bad parameter: {}", err),
+				),
+				super::command::ParseCommandError::UnsupportedMechanism(err) => {
+					Response::with_message(ResponseCode::ParameterNotImplemented, err.to_string())
+				}
 			},
-		}
+		};
+
+		Some(response)
 	}
 
 	fn helo(&mut self, client_domain: &Domain) -> Response {
@@ -111,6 +381,7 @@ impl Server {
 		match self.state {
 			State::Initiated => {
 				self.state = State::Greeted;
+				self.extensions = Extensions::default();
 
 				Response::with_message(
 					ResponseCode::Okay,
@@ -133,6 +404,15 @@ impl Server {
 		// an invalid EHLO is to break the spec.
 		self.rset();
 		self.state = State::Greeted;
+		self.extensions = self.policy.extensions();
+		if self.secure {
+			// RFC 3207 section 4.2: STARTTLS MUST NOT be advertised again
+			self.extensions.disable(Extension::StartTls);
+		} else {
+			// PLAIN and LOGIN send the password in the clear, so we only
+			// offer them over TLS
+			self.extensions.disable(Extension::Auth);
+		}
 
 		let mut resp = Response::with_message(
 			ResponseCode::Okay,
@@ -142,23 +422,150 @@ impl Server {
 				client_domain
 			),
 		);
-		resp.push("Help");
+		for extension in self.extensions.iter() {
+			resp.push(&self.ehlo_line(extension));
+		}
+		resp.push("HELP");
 		resp
 	}
 
+	/// The line advertising an extension in the reply to EHLO, including any
+	/// parameters it takes.
+	fn ehlo_line(&self, extension: Extension) -> String {
+		match (extension, self.policy.max_message_size()) {
+			(Extension::Size, Some(max)) => format!("{} {}", extension, max),
+			(Extension::Auth, _) => {
+				format!("{} {} {}", extension, Mechanism::Plain, Mechanism::Login)
+			}
+			_ => extension.keyword().to_owned(),
+		}
+	}
+
 	fn data(&mut self) -> Response {
-		if self.state == State::GotForwardPath {
-			self.state = State::LoadingData;
-			Response::with_message(ResponseCode::StartMailInput, "Start mail input")
-		} else {
-			Self::bad_command()
+		// Binary messages can't be sent with DATA (RFC 3030 section 3)
+		if self.state != State::GotForwardPath || self.message.is_binary() {
+			return Self::bad_command();
+		}
+
+		match self.open_sink() {
+			Ok(()) => {
+				self.line_start = true;
+				self.state = State::LoadingData;
+				Response::with_message(ResponseCode::StartMailInput, "Start mail input")
+			}
+			Err(response) => response,
 		}
 	}
 
-	fn mail(&mut self, reverse_path: &ReversePath) -> Response {
+	/// Start reading a chunk of `size` octets. Its reply is sent once it's all
+	/// in. Chunks we refuse are still read, so they aren't taken as commands.
+	fn bdat(&mut self, size: usize, last: bool) {
+		let refused = match self.state {
+			State::ReceivingChunks => None,
+			State::GotForwardPath => match self.open_sink() {
+				Ok(()) => {
+					self.state = State::ReceivingChunks;
+					None
+				}
+				Err(response) => Some(response),
+			},
+			_ => Some(Self::bad_command()),
+		};
+
+		self.chunk = Some(Chunk {
+			size,
+			remaining: size,
+			last,
+			refused,
+		});
+	}
+
+	/// Ask the policy for somewhere to put the message data
+	fn open_sink(&mut self) -> Result<(), Response> {
+		match self.policy.message_sink() {
+			Ok(sink) => {
+				self.sink = Some(sink);
+				self.data_size = 0;
+				self.data_failure = None;
+				Ok(())
+			}
+			Err(e) => {
+				eprintln!("Failed to make somewhere to put message data: {}", e);
+				Err(Self::processing_error())
+			}
+		}
+	}
+
+	fn mail(&mut self, reverse_path: &ReversePath, parameters: Parameters) -> Response {
 		if self.state == State::Greeted {
+			if self.require_auth && self.authenticated.is_none() {
+				return Response::with_message(
+					ResponseCode::AuthRequired,
+					"Authentication required",
+				);
+			}
+
+			if let Some(unsupported) = parameters
+				.iter()
+				.find(|param| !self.supports(Extension::from_mail_parameter(&param.keyword)))
+			{
+				return Self::parameter_not_recognized(&unsupported.keyword);
+			}
+
+			if let Err(e) = MailDsn::from_parameters(&parameters) {
+				return Response::with_message(ResponseCode::InvalidParameters, e.to_string());
+			}
+
+			if let Some(body) = parameters.get("BODY") {
+				let supported = match body.value.as_deref() {
+					Some(value) if value.eq_ignore_ascii_case("7BIT") => true,
+					Some(value) => self.supports(Extension::from_body(value)),
+					None => false,
+				};
+
+				if !supported {
+					return Response::with_message(
+						ResponseCode::InvalidParameters,
+						"Unsupported BODY value",
+					);
+				}
+			}
+
+			if let Some(smtputf8) = parameters.get("SMTPUTF8") {
+				if smtputf8.value.is_some() {
+					return Response::with_message(
+						ResponseCode::InvalidParameters,
+						"SMTPUTF8 takes no value",
+					);
+				}
+			} else if !reverse_path.is_ascii() {
+				return Self::needs_smtputf8();
+			}
+
+			if let Some(size) = parameters.get("SIZE") {
+				let declared = match size.value.as_deref().map(str::parse::<usize>) {
+					Some(Ok(declared)) => declared,
+					_ => {
+						return Response::with_message(
+							ResponseCode::InvalidParameters,
+							"SIZE requires a numeric value",
+						)
+					}
+				};
+
+				if self
+					.policy
+					.max_message_size()
+					.is_some_and(|max| declared > max)
+				{
+					return Self::message_too_large();
+				}
+			}
+
 			self.state = State::GotReversePath;
 			self.message.reverse_path = reverse_path.to_owned();
+			self.message.mail_parameters = parameters;
+			self.message.authenticated = self.authenticated.clone();
 
 			Response::with_message(ResponseCode::Okay, "Okay")
 		} else {
@@ -166,15 +573,34 @@ impl Server {
 		}
 	}
 
-	fn rcpt(&mut self, forward_path: &ForwardPath) -> Response {
+	fn rcpt(&mut self, forward_path: &ForwardPath, parameters: Parameters) -> Response {
 		if self.state == State::GotReversePath || self.state == State::GotForwardPath {
+			if let Some(unsupported) = parameters
+				.iter()
+				.find(|param| !self.supports(Extension::from_rcpt_parameter(&param.keyword)))
+			{
+				return Self::parameter_not_recognized(&unsupported.keyword);
+			}
+
+			if let Err(e) = RcptDsn::from_parameters(&parameters) {
+				return Response::with_message(ResponseCode::InvalidParameters, e.to_string());
+			}
+
+			if !self.message.is_utf8() && !forward_path.is_ascii() {
+				return Self::needs_smtputf8();
+			}
+
 			match forward_path {
-				ForwardPath::Postmaster => self.add_rcpt(forward_path),
+				ForwardPath::Postmaster => self.add_rcpt(forward_path, parameters),
 				ForwardPath::Regular(path) => {
-					if self.policy.path_is_valid(path) {
-						self.add_rcpt(forward_path)
-					} else {
-						Self::bad_command() //todo: correct responses
+					let checked = match &self.authenticated {
+						Some(identity) => self.policy.check_authenticated_path(path, identity),
+						None => self.policy.check_path(path),
+					};
+
+					match checked {
+						Ok(()) => self.add_rcpt(forward_path, parameters),
+						Err(rejection) => Self::rejected(rejection),
 					}
 				}
 			}
@@ -183,15 +609,73 @@ impl Server {
 		}
 	}
 
-	fn add_rcpt(&mut self, forward_path: &ForwardPath) -> Response {
+	fn add_rcpt(&mut self, forward_path: &ForwardPath, parameters: Parameters) -> Response {
 		self.state = State::GotForwardPath;
-		self.message.forward_paths.push(forward_path.to_owned());
+		self.message
+			.add_recipient_with(forward_path.to_owned(), parameters);
 
 		Response::with_message(ResponseCode::Okay, "Okay")
 	}
 
+	fn starttls(&mut self) -> Response {
+		if self.state == State::Greeted {
+			self.state = State::StartingTls;
+			Response::with_message(ResponseCode::ServiceReady, "Ready to start TLS")
+		} else {
+			Self::bad_command()
+		}
+	}
+
+	fn auth(&mut self, mechanism: Mechanism, initial: Option<String>) -> Response {
+		// RFC 4954 section 4: once per session, and not during a transaction
+		if self.state != State::Greeted || self.authenticated.is_some() {
+			return Self::bad_command();
+		}
+
+		let mut exchange = Exchange::new(mechanism);
+		let step = match initial {
+			None => exchange.first_challenge(),
+			Some(initial) => exchange.respond(&initial),
+		};
+
+		self.auth_step(exchange, step)
+	}
+
+	fn continue_auth(&mut self, mut exchange: Exchange, line: &str) -> Response {
+		let step = exchange.respond(line.trim_end());
+		self.auth_step(exchange, step)
+	}
+
+	fn auth_step(&mut self, exchange: Exchange, step: Step) -> Response {
+		match step {
+			Step::Challenge(challenge) => {
+				self.exchange = Some(exchange);
+				Response::with_message(ResponseCode::AuthContinue, challenge)
+			}
+			Step::Done(credentials) => {
+				let verified = self.policy.credentials().is_some_and(|backend| {
+					backend.verify(&credentials.username, &credentials.password)
+				});
+
+				if verified {
+					self.authenticated = Some(credentials.username);
+					Response::with_message(ResponseCode::AuthSucceeded, "Authentication successful")
+				} else {
+					Response::with_message(
+						ResponseCode::AuthInvalid,
+						"Authentication credentials invalid",
+					)
+				}
+			}
+			Step::Failed(err) => {
+				Response::with_message(ResponseCode::InvalidParameters, err.to_string())
+			}
+		}
+	}
+
 	fn rset(&mut self) -> Response {
 		self.message = Envelope::default();
+		self.sink = None;
 
 		self.state = match self.state {
 			State::Initiated => State::Initiated,
@@ -210,6 +694,47 @@ impl Server {
 		)
 	}
 
+	/// Whether a parameter belonging to `extension` may be used in this
+	/// session. Parameters that belong to no extension we know are never
+	/// supported.
+	fn supports(&self, extension: Option<Extension>) -> bool {
+		extension.is_some_and(|ext| self.extensions.contains(ext))
+	}
+
+	fn parameter_not_recognized(keyword: &str) -> Response {
+		Response::with_message(
+			ResponseCode::MailRcptParametersError,
+			format!("{} parameter not recognized", keyword),
+		)
+	}
+
+	/// For UTF-8 addresses in a transaction that didn't ask for SMTPUTF8
+	/// (RFC 6531 section 3.5)
+	fn needs_smtputf8() -> Response {
+		Response::with_message(
+			ResponseCode::MailboxNameNotAllowed,
+			"Non-ASCII addresses need SMTPUTF8",
+		)
+	}
+
+	fn message_too_large() -> Response {
+		Response::with_message(
+			ResponseCode::ExceededStorageAllocation,
+			"Message exceeds fixed maximum message size",
+		)
+	}
+
+	fn processing_error() -> Response {
+		Response::with_message(
+			ResponseCode::ProcessingError,
+			"Local error in processing, try again later",
+		)
+	}
+
+	fn line_too_long() -> Response {
+		Response::with_message(ResponseCode::UnrecognizedCommand, "Line too long")
+	}
+
 	fn not_implemented() -> Response {
 		Response::with_message(
 			ResponseCode::CommandNotImplemented,
@@ -217,6 +742,23 @@ impl Server {
 		)
 	}
 
+	/// The reply to a recipient the policy wouldn't take. The enhanced
+	/// status codes are from RFC 3463.
+	fn rejected(rejection: Rejection) -> Response {
+		match rejection {
+			Rejection::NoSuchUser => {
+				Response::with_message(ResponseCode::PermanentMailFail, "5.1.1 No such user here")
+			}
+			Rejection::MailboxFull => Response::with_message(
+				ResponseCode::InsufficientStorage,
+				"4.2.2 Mailbox full, try again later",
+			),
+			Rejection::RelayDenied => {
+				Response::with_message(ResponseCode::PermanentMailFail, "5.7.1 Relaying denied")
+			}
+		}
+	}
+
 	fn bad_command() -> Response {
 		Response::with_message(ResponseCode::BadCommandSequence, "bad sequence of commands")
 	}
@@ -226,6 +768,15 @@ impl Server {
 	}
 }
 
+/// A BDAT chunk we've only had part of so far
+struct Chunk {
+	size: usize,
+	remaining: usize,
+	last: bool,
+	/// What to reply with if the chunk is being thrown away
+	refused: Option<Response>,
+}
+
 #[derive(PartialEq)]
 enum State {
 	Initiated,
@@ -233,11 +784,622 @@ enum State {
 	GotReversePath,
 	GotForwardPath,
 	LoadingData,
+	/// At least one BDAT chunk is in, but not the last
+	ReceivingChunks,
+	StartingTls,
 	Exit,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for State {
 	fn default() -> Self {
 		Self::Initiated
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::{policy::CredentialBackend, smtp::args::Path, smtp::Credentials};
+
+	struct TestCredentials;
+
+	impl CredentialBackend for TestCredentials {
+		fn verify(&self, username: &str, password: &str) -> bool {
+			username == "gen" && password == "hunter2"
+		}
+	}
+
+	#[derive(Default)]
+	struct TestPolicy {
+		extensions: Extensions,
+		max_message_size: Option<usize>,
+		/// The data of the last message we were given
+		received: Arc<Mutex<Option<Vec<u8>>>>,
+	}
+
+	impl Policy for TestPolicy {
+		fn primary_host(&self) -> Domain {
+			"sail.test".parse().unwrap()
+		}
+
+		fn check_path(&self, path: &Path) -> Result<(), Rejection> {
+			match path.local_part.to_string().as_str() {
+				"nobody" => Err(Rejection::NoSuchUser),
+				"full" => Err(Rejection::MailboxFull),
+				_ => Ok(()),
+			}
+		}
+
+		fn extensions(&self) -> Extensions {
+			self.extensions.clone()
+		}
+
+		fn max_message_size(&self) -> Option<usize> {
+			self.max_message_size
+		}
+
+		fn credentials(&self) -> Option<&dyn CredentialBackend> {
+			Some(&TestCredentials)
+		}
+
+		fn message_received(
+			&mut self,
+			_message: Envelope,
+			mut data: Box<dyn MessageSink>,
+		) -> Response {
+			*self.received.lock().unwrap() = Some(data.message().unwrap().body);
+			Response::new(ResponseCode::Okay)
+		}
+	}
+
+	fn server(extensions: Extensions) -> Server {
+		Server::initiate(Box::new(TestPolicy {
+			extensions,
+			..Default::default()
+		}))
+		.0
+	}
+
+	#[test]
+	fn ehlo_advertises_extensions() {
+		let mut server = server(
+			Extensions::new()
+				.with(Extension::Pipelining)
+				.with(Extension::EightBitMime),
+		);
+
+		let response = server.push(b"EHLO client.test\r\n").pop().unwrap();
+		assert_eq!(
+			response.to_string(),
+			"250-sail.test (sail) greets client.test\r\n250-PIPELINING\r\n250-8BITMIME\r\n250 HELP\r\n"
+		);
+	}
+
+	#[test]
+	fn pipelined_commands() {
+		let mut server = server(Extensions::new().with(Extension::Pipelining));
+		server.push(b"EHLO client.test\r\n");
+
+		// One reply per command, in the order they were sent
+		let codes = |responses: Vec<Response>| -> Vec<u16> {
+			responses
+				.iter()
+				.map(|response| response.code.as_code())
+				.collect()
+		};
+		assert_eq!(
+			codes(server.push(b"MAIL FROM:<a@b>\r\nRCPT TO:<c@d>\r\nRCPT TO:<e@f")),
+			vec![250, 250]
+		);
+		assert_eq!(codes(server.push(b">\r\nDATA\r\n")), vec![250, 354]);
+
+		// The end of the data and whatever follows it
+		assert_eq!(
+			codes(server.push(b"Subject: hi\r\n\r\n.\r\nQUIT\r\nNOOP\r\n")),
+			vec![250, 221]
+		);
+		assert!(server.should_exit());
+		assert!(server.push(b"NOOP\r\n").is_empty());
+	}
+
+	#[test]
+	fn binary_safe_data() {
+		let received = Arc::new(Mutex::new(None));
+		let mut server = Server::initiate(Box::new(TestPolicy {
+			received: received.clone(),
+			..Default::default()
+		}))
+		.0;
+
+		server.push(b"HELO client.test\r\nMAIL FROM:<a@b>\r\nRCPT TO:<c@d>\r\nDATA\r\n");
+
+		// Latin-1, then UTF-8 split between reads, as are the line endings
+		// and the end of the data
+		assert!(server.push(b"caf\xe9\r").is_empty());
+		assert!(server.push(b"\n..dot\r\n\xe2\x82").is_empty());
+		assert!(server.push(b"\xac\r\n.").is_empty());
+		assert_eq!(server.push(b"\r\n").pop().unwrap().code, ResponseCode::Okay);
+
+		let data = received.lock().unwrap().take().unwrap();
+		assert_eq!(data, b"caf\xe9\r\n.dot\r\n\xe2\x82\xac\r\n");
+
+		// Commands still have to be text
+		assert_eq!(
+			server.push(b"MAIL FROM:<\xff@b>\r\n").pop().unwrap().code,
+			ResponseCode::UnrecognizedCommand
+		);
+	}
+
+	#[test]
+	fn chunked_data() {
+		let received = Arc::new(Mutex::new(None));
+		let mut server = Server::initiate(Box::new(TestPolicy {
+			extensions: Extensions::new()
+				.with(Extension::EightBitMime)
+				.with(Extension::Chunking)
+				.with(Extension::BinaryMime),
+			received: received.clone(),
+			..Default::default()
+		}))
+		.0;
+
+		server.push(b"EHLO client.test\r\nMAIL FROM:<a@b> BODY=BINARYMIME\r\nRCPT TO:<c@d>\r\n");
+		assert_eq!(
+			server.push(b"DATA\r\n").pop().unwrap().code,
+			ResponseCode::BadCommandSequence
+		);
+
+		// Chunks are taken as they are, dots and line endings included, even
+		// when they're split between reads
+		assert!(server.push(b"BDAT 8\r\n.\r\n\x00").is_empty());
+		assert_eq!(
+			server.push(b"\xffab\r").pop().unwrap().code,
+			ResponseCode::Okay
+		);
+		let responses = server.push(b"BDAT 0 LAST\r\nNOOP\r\n");
+		assert_eq!(responses.len(), 2);
+
+		let data = received.lock().unwrap().take().unwrap();
+		assert_eq!(data, b".\r\n\x00\xffab\r");
+	}
+
+	#[test]
+	fn refused_chunk_is_consumed() {
+		let mut server = server(Extensions::new().with(Extension::Chunking));
+		server.push(b"EHLO client.test\r\n");
+
+		// Without a transaction the chunk can't be taken, but it mustn't be
+		// mistaken for commands either
+		let responses = server.push(b"BDAT 6 LAST\r\nQUIT\r\nNOOP\r\n");
+		assert_eq!(responses.len(), 2);
+		assert_eq!(responses[0].code, ResponseCode::BadCommandSequence);
+		assert_eq!(responses[1].code, ResponseCode::Okay);
+		assert!(!server.should_exit());
+	}
+
+	#[test]
+	fn smtputf8() {
+		let mut server = server(Extensions::new().with(Extension::SmtpUtf8));
+		server.push(b"EHLO client.test\r\n");
+
+		let utf8 =
+			|server: &mut Server, line: &str| server.push(line.as_bytes()).pop().unwrap().code;
+		assert_eq!(
+			utf8(&mut server, "MAIL FROM:<用户@例子.广告>\r\n"),
+			ResponseCode::MailboxNameNotAllowed
+		);
+		assert_eq!(utf8(&mut server, "MAIL FROM:<a@b>\r\n"), ResponseCode::Okay);
+		assert_eq!(
+			utf8(&mut server, "RCPT TO:<用户@例子.广告>\r\n"),
+			ResponseCode::MailboxNameNotAllowed
+		);
+
+		server.push(b"RSET\r\n");
+		assert_eq!(
+			utf8(&mut server, "MAIL FROM:<用户@例子.广告> SMTPUTF8\r\n"),
+			ResponseCode::Okay
+		);
+		assert_eq!(
+			utf8(&mut server, "RCPT TO:<用户@例子.广告>\r\n"),
+			ResponseCode::Okay
+		);
+	}
+
+	#[test]
+	fn nothing_survives_starttls() {
+		let mut server = server(Extensions::new().with(Extension::StartTls));
+		server.push(b"EHLO client.test\r\n");
+
+		// Commands sent along with STARTTLS would otherwise run over TLS
+		assert_eq!(server.push(b"STARTTLS\r\nMAIL FROM:<a@b>\r\n").len(), 1);
+		server.tls_started();
+		server.push(b"EHLO client.test\r\n");
+		assert_eq!(
+			server.push(b"RCPT TO:<c@d>\r\n").pop().unwrap().code,
+			ResponseCode::BadCommandSequence
+		);
+	}
+
+	#[test]
+	fn line_too_long() {
+		let mut server = server(Extensions::new());
+		server.push(b"EHLO client.test\r\n");
+
+		// All at once
+		let mut long = b"NOOP ".to_vec();
+		long.resize(MAX_LINE_LENGTH + 10, b'a');
+		long.extend_from_slice(b"\r\nNOOP\r\n");
+		let responses = server.push(&long);
+		assert_eq!(responses.len(), 2);
+		assert_eq!(responses[0].to_string(), "500 Line too long\r\n");
+		assert_eq!(responses[1].code, ResponseCode::Okay);
+
+		// A bit at a time, which mustn't be kept
+		for _ in 0..10 {
+			assert!(server.push(&[b'a'; 1000]).is_empty());
+		}
+		assert!(server.input.len() <= MAX_LINE_LENGTH);
+		assert!(server.push(b"aaaa\r").is_empty());
+		let responses = server.push(b"\nNOOP\r\n");
+		assert_eq!(responses.len(), 2);
+		assert_eq!(responses[0].to_string(), "500 Line too long\r\n");
+		assert_eq!(responses[1].code, ResponseCode::Okay);
+	}
+
+	#[test]
+	fn vrfy_not_implemented() {
+		let mut server = server(Extensions::new());
+
+		server.push(b"EHLO client.test\r\n");
+		assert_eq!(
+			server.push(b"VRFY postmaster\r\n").pop().unwrap().code,
+			ResponseCode::CommandNotImplemented
+		);
+	}
+
+	#[test]
+	fn rejected_recipients() {
+		let mut server = server(Extensions::new());
+		server.push(b"EHLO client.test\r\n");
+		server.push(b"MAIL FROM:<a@b>\r\n");
+
+		assert_eq!(
+			server
+				.push(b"RCPT TO:<nobody@sail.test>\r\n")
+				.pop()
+				.unwrap()
+				.to_string(),
+			"550 5.1.1 No such user here\r\n"
+		);
+		assert_eq!(
+			server
+				.push(b"RCPT TO:<full@sail.test>\r\n")
+				.pop()
+				.unwrap()
+				.to_string(),
+			"452 4.2.2 Mailbox full, try again later\r\n"
+		);
+
+		// The transaction carries on for everyone else
+		assert_eq!(
+			server
+				.push(b"RCPT TO:<gen@sail.test>\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::Okay
+		);
+	}
+
+	#[test]
+	fn unadvertised_extension_command_is_unrecognized() {
+		let mut server = server(Extensions::new().with(Extension::Chunking));
+
+		// Extensions don't apply to HELO sessions
+		server.push(b"HELO client.test\r\n").pop().unwrap();
+		assert_eq!(
+			server.push(b"BDAT 10 LAST\r\n").pop().unwrap().code,
+			ResponseCode::UnrecognizedCommand
+		);
+		assert_eq!(
+			server.push(b"STARTTLS\r\n").pop().unwrap().code,
+			ResponseCode::UnrecognizedCommand
+		);
+	}
+
+	#[test]
+	fn parameters_require_extension() {
+		let mut server = server(Extensions::new().with(Extension::EightBitMime));
+		server.push(b"EHLO client.test\r\n").pop().unwrap();
+
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> SIZE=100\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::MailRcptParametersError
+		);
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> XUNKNOWN\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::MailRcptParametersError
+		);
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> BODY=8BITMIME\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::Okay
+		);
+		assert_eq!(
+			server
+				.push(b"RCPT TO:<c@d> NOTIFY=NEVER\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::MailRcptParametersError
+		);
+		assert_eq!(
+			server.push(b"RCPT TO:<c@d>\r\n").pop().unwrap().code,
+			ResponseCode::Okay
+		);
+
+		assert!(server.message.mail_parameters.contains("BODY"));
+		assert_eq!(server.message.rcpt_parameters, vec![Parameters::new()]);
+	}
+
+	#[test]
+	fn dsn_parameters() {
+		let mut server = server(Extensions::new().with(Extension::Dsn));
+		let ehlo = server.push(b"EHLO client.test\r\n").pop().unwrap();
+		assert!(ehlo.to_string().contains("250-DSN\r\n"));
+
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> RET=ALL\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::InvalidParameters
+		);
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> RET=HDRS ENVID=QQ314159\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::Okay
+		);
+		assert_eq!(
+			server
+				.push(b"RCPT TO:<c@d> NOTIFY=NEVER,DELAY\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::InvalidParameters
+		);
+		assert_eq!(
+			server
+				.push(b"RCPT TO:<c@d> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;c@d\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::Okay
+		);
+
+		assert!(server.message.mail_parameters.contains("ENVID"));
+		assert!(server.message.rcpt_parameters[0].contains("ORCPT"));
+	}
+
+	#[test]
+	fn size_limit() {
+		let mut server = Server::initiate(Box::new(TestPolicy {
+			extensions: Extensions::new().with(Extension::Size),
+			max_message_size: Some(64),
+			..Default::default()
+		}))
+		.0;
+
+		let ehlo = server.push(b"EHLO client.test\r\n").pop().unwrap();
+		assert!(ehlo.to_string().contains("250-SIZE 64\r\n"));
+
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> SIZE=65\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::ExceededStorageAllocation
+		);
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> SIZE=big\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::InvalidParameters
+		);
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<a@b> SIZE=10\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::Okay
+		);
+		server.push(b"RCPT TO:<c@d>\r\n").pop().unwrap();
+		server.push(b"DATA\r\n").pop().unwrap();
+
+		// The client lied about the size, so we cut it off while it's sending
+		for _ in 0..10 {
+			assert!(server.push(b"0123456789abcdef\r\n").is_empty());
+			assert!(server.input.is_empty());
+		}
+		assert!(server.sink.is_none());
+		assert_eq!(
+			server.push(b".\r\n").pop().unwrap().code,
+			ResponseCode::ExceededStorageAllocation
+		);
+
+		// and the next transaction isn't affected
+		server.push(b"MAIL FROM:<a@b>\r\n").pop().unwrap();
+		server.push(b"RCPT TO:<c@d>\r\n").pop().unwrap();
+		server.push(b"DATA\r\n").pop().unwrap();
+		assert!(server.push(b"small\r\n").is_empty());
+		assert_eq!(
+			server.push(b".\r\n").pop().unwrap().code,
+			ResponseCode::Okay
+		);
+	}
+
+	#[test]
+	fn starttls_resets_session() {
+		let mut server = server(
+			Extensions::new()
+				.with(Extension::StartTls)
+				.with(Extension::Size),
+		);
+
+		assert!(server
+			.push(b"EHLO client.test\r\n")
+			.pop()
+			.unwrap()
+			.to_string()
+			.contains("250-STARTTLS\r\n"));
+		assert_eq!(
+			server.push(b"STARTTLS\r\n").pop().unwrap().code,
+			ResponseCode::ServiceReady
+		);
+		assert!(server.should_start_tls());
+
+		server.tls_started();
+		assert!(!server.should_start_tls());
+		assert!(server.is_secure());
+
+		// The client has to greet us again, and we won't offer TLS twice
+		assert_eq!(
+			server.push(b"MAIL FROM:<a@b>\r\n").pop().unwrap().code,
+			ResponseCode::BadCommandSequence
+		);
+		let ehlo = server
+			.push(b"EHLO client.test\r\n")
+			.pop()
+			.unwrap()
+			.to_string();
+		assert!(!ehlo.contains("STARTTLS"));
+		assert!(ehlo.contains("SIZE"));
+		assert_eq!(
+			server.push(b"STARTTLS\r\n").pop().unwrap().code,
+			ResponseCode::UnrecognizedCommand
+		);
+	}
+
+	#[test]
+	fn auth_only_over_tls() {
+		let mut server = server(Extensions::new().with(Extension::Auth));
+
+		let ehlo = server
+			.push(b"EHLO client.test\r\n")
+			.pop()
+			.unwrap()
+			.to_string();
+		assert!(!ehlo.contains("AUTH"));
+		assert_eq!(
+			server.push(b"AUTH PLAIN\r\n").pop().unwrap().code,
+			ResponseCode::UnrecognizedCommand
+		);
+
+		server.tls_started();
+		let ehlo = server
+			.push(b"EHLO client.test\r\n")
+			.pop()
+			.unwrap()
+			.to_string();
+		assert!(ehlo.contains("250-AUTH PLAIN LOGIN\r\n"));
+	}
+
+	#[test]
+	fn auth_plain_and_login() {
+		let credentials = Credentials {
+			username: String::from("gen"),
+			password: String::from("hunter2"),
+		};
+		let mut server = server(Extensions::new().with(Extension::Auth));
+		server.require_auth(true);
+		server.tls_started();
+		server.push(b"EHLO client.test\r\n").pop().unwrap();
+
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<gen@nyble.dev>\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::AuthRequired
+		);
+
+		// Wrong password, then the right one with a separate response line
+		assert_eq!(
+			server.push(b"AUTH LOGIN Z2Vu\r\n").pop().unwrap().code,
+			ResponseCode::AuthContinue
+		);
+		assert_eq!(
+			server.push(b"aHVudGVyMw==\r\n").pop().unwrap().code,
+			ResponseCode::AuthInvalid
+		);
+		assert_eq!(
+			server.push(b"AUTH PLAIN\r\n").pop().unwrap().to_string(),
+			"334 \r\n"
+		);
+		assert_eq!(
+			server
+				.push(format!("{}\r\n", credentials.plain_response()).as_bytes())
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::AuthSucceeded
+		);
+		assert_eq!(server.authenticated(), Some("gen"));
+
+		// Only once per session
+		assert_eq!(
+			server.push(b"AUTH PLAIN\r\n").pop().unwrap().code,
+			ResponseCode::BadCommandSequence
+		);
+
+		assert_eq!(
+			server
+				.push(b"MAIL FROM:<gen@nyble.dev>\r\n")
+				.pop()
+				.unwrap()
+				.code,
+			ResponseCode::Okay
+		);
+		assert_eq!(server.message.authenticated.as_deref(), Some("gen"));
+	}
+
+	#[test]
+	fn auth_cancelled() {
+		let mut server = server(Extensions::new().with(Extension::Auth));
+		server.tls_started();
+		server.push(b"EHLO client.test\r\n").pop().unwrap();
+
+		server.push(b"AUTH LOGIN\r\n").pop().unwrap();
+		assert_eq!(
+			server.push(b"*\r\n").pop().unwrap().code,
+			ResponseCode::InvalidParameters
+		);
+		assert_eq!(
+			server.push(b"NOOP\r\n").pop().unwrap().code,
+			ResponseCode::Okay
+		);
+	}
+}
@@ -0,0 +1,318 @@
+use std::{collections::HashMap, path::Path as FsPath, str::FromStr};
+
+use sail::smtp::args::{Domain, LocalPart, Path};
+use thiserror::Error;
+
+/// Virtual alias and forwarding tables. Read from a file in the spirit of
+/// aliases(5), where each line maps an address to one or more targets:
+/// ```text
+/// # exact aliases, and one-to-many distribution lists
+/// info@a.example: gen@b.example
+/// team@a.example: gen@b.example, dev@nove.dev
+/// # anything else at a.example goes to the local user ops
+/// @a.example: ops
+/// ```
+/// Targets without a domain are local users on our primary hostname. Any
+/// domain with an alias that isn't one of our hostnames is a virtual domain,
+/// and we only accept mail for addresses there that have an alias.
+#[derive(Clone, Debug, Default)]
+pub struct AliasMap {
+	/// Keyed by lowercase `local@domain`
+	exact: HashMap<String, Vec<Target>>,
+	/// Keyed by lowercase domain
+	catch_all: HashMap<String, Vec<Target>>,
+}
+
+#[derive(Clone, Debug)]
+enum Target {
+	/// A bare user name, delivered on our primary hostname
+	Local(String),
+	Address(Path),
+}
+
+/// How many aliases deep we'll go before assuming something is wrong
+const MAX_DEPTH: usize = 16;
+
+impl AliasMap {
+	pub fn open<P: AsRef<FsPath>>(path: P) -> Result<Self, ParseAliasError> {
+		std::fs::read_to_string(path)?.parse()
+	}
+
+	/// True if there are aliases for this domain, whether it's local or not
+	pub fn has_domain(&self, domain: &Domain) -> bool {
+		let domain = domain.to_string().to_lowercase();
+
+		self.catch_all.contains_key(&domain)
+			|| self
+				.exact
+				.keys()
+				.any(|key| key.rsplit_once('@').is_some_and(|(_, d)| d == domain))
+	}
+
+	/// Expand an address into the mailboxes it should be delivered to.
+	/// Addresses without an alias are returned as they are, unless they're
+	/// in a virtual domain, in which case there is nobody to deliver to.
+	pub fn resolve(&self, path: &Path, hostnames: &[Domain]) -> Result<Vec<Path>, AliasError> {
+		let mut resolved = vec![];
+		self.expand(path, hostnames, &mut vec![], &mut resolved)?;
+
+		Ok(resolved)
+	}
+
+	fn expand(
+		&self,
+		path: &Path,
+		hostnames: &[Domain],
+		parents: &mut Vec<String>,
+		resolved: &mut Vec<Path>,
+	) -> Result<(), AliasError> {
+		let key = Self::key(path);
+
+		if parents.contains(&key) {
+			return Err(AliasError::Loop(path.to_string()));
+		}
+		if parents.len() >= MAX_DEPTH {
+			return Err(AliasError::TooDeep(path.to_string()));
+		}
+
+		let domain = path.domain.to_string().to_lowercase();
+		let targets = match self.exact.get(&key).or_else(|| self.catch_all.get(&domain)) {
+			Some(targets) => targets,
+			None => {
+				if Self::is_local(&path.domain, hostnames) || !self.has_domain(&path.domain) {
+					if !resolved.iter().any(|p| Self::key(p) == key) {
+						resolved.push(path.clone());
+					}
+					return Ok(());
+				} else {
+					return Err(AliasError::NoSuchAddress(path.to_string()));
+				}
+			}
+		};
+
+		parents.push(key.clone());
+		for target in targets {
+			let target = match target {
+				Target::Address(target) => target.clone(),
+				Target::Local(user) => {
+					let primary = hostnames
+						.first()
+						.ok_or_else(|| AliasError::NoHostname(user.clone()))?;
+
+					format!("<{}@{}>", user, primary)
+						.parse()
+						.map_err(|_| AliasError::NoHostname(user.clone()))?
+				}
+			};
+
+			// An alias that includes itself delivers to the real mailbox too
+			if Self::key(&target) == key {
+				if !resolved.iter().any(|p| Self::key(p) == key) {
+					resolved.push(target);
+				}
+				continue;
+			}
+
+			self.expand(&target, hostnames, parents, resolved)?;
+		}
+		parents.pop();
+
+		Ok(())
+	}
+
+	fn is_local(domain: &Domain, hostnames: &[Domain]) -> bool {
+		let domain = domain.to_string();
+		hostnames
+			.iter()
+			.any(|host| host.to_string().eq_ignore_ascii_case(&domain))
+	}
+
+	fn key(path: &Path) -> String {
+		format!("{}@{}", path.local_part, path.domain).to_lowercase()
+	}
+}
+
+impl FromStr for AliasMap {
+	type Err = ParseAliasError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut map = AliasMap::default();
+
+		for (idx, line) in s.lines().enumerate() {
+			let line_number = idx + 1;
+			let line = line.trim();
+
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let (source, targets) = line
+				.split_once(':')
+				.ok_or(ParseAliasError::MissingColon(line_number))?;
+
+			let targets = targets
+				.split(',')
+				.map(str::trim)
+				.filter(|target| !target.is_empty())
+				.map(|target| {
+					if target.contains('@') {
+						format!("<{}>", target).parse().ok().map(Target::Address)
+					} else {
+						target
+							.parse::<LocalPart>()
+							.ok()
+							.map(|_| Target::Local(target.to_owned()))
+					}
+				})
+				.collect::<Option<Vec<Target>>>()
+				.ok_or(ParseAliasError::InvalidTarget(line_number))?;
+
+			if targets.is_empty() {
+				return Err(ParseAliasError::NoTargets(line_number));
+			}
+
+			let source = source.trim();
+			match source.strip_prefix('@') {
+				Some(domain) => {
+					let domain: Domain = domain
+						.parse()
+						.map_err(|_| ParseAliasError::InvalidSource(line_number))?;
+					map.catch_all
+						.insert(domain.to_string().to_lowercase(), targets);
+				}
+				None => {
+					let path: Path = format!("<{}>", source)
+						.parse()
+						.map_err(|_| ParseAliasError::InvalidSource(line_number))?;
+					map.exact.insert(Self::key(&path), targets);
+				}
+			}
+		}
+
+		Ok(map)
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum ParseAliasError {
+	#[error("failed to read alias file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("line {0} has no colon between the alias and its targets")]
+	MissingColon(usize),
+	#[error("line {0} has an invalid alias")]
+	InvalidSource(usize),
+	#[error("line {0} has an invalid target")]
+	InvalidTarget(usize),
+	#[error("line {0} has no targets")]
+	NoTargets(usize),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AliasError {
+	#[error("alias loop detected at {0}")]
+	Loop(String),
+	#[error("aliases nested too deeply at {0}")]
+	TooDeep(String),
+	#[error("{0} is in a virtual domain but has no alias")]
+	NoSuchAddress(String),
+	#[error("no hostname to deliver {0} to")]
+	NoHostname(String),
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn path(s: &str) -> Path {
+		format!("<{}>", s).parse().unwrap()
+	}
+
+	fn resolve(map: &AliasMap, s: &str) -> Result<Vec<String>, AliasError> {
+		let hostnames = vec![Domain::FQDN("b.example".into())];
+
+		map.resolve(&path(s), &hostnames)
+			.map(|paths| paths.iter().map(|p| p.to_string()).collect())
+	}
+
+	#[test]
+	fn aliases() {
+		let map: AliasMap = "# comment\n\
+			info@a.example: gen@b.example\n\
+			team@A.example: gen@b.example, dev@nove.dev, info@a.example\n\
+			@a.example: ops\n\
+			gen+lists@b.example: gen@b.example, archive@b.example\n"
+			.parse()
+			.unwrap();
+
+		assert_eq!(
+			resolve(&map, "INFO@a.example").unwrap(),
+			vec!["<gen@b.example>"]
+		);
+		assert_eq!(
+			resolve(&map, "team@a.example").unwrap(),
+			vec!["<gen@b.example>", "<dev@nove.dev>"]
+		);
+		assert_eq!(
+			resolve(&map, "sales@a.example").unwrap(),
+			vec!["<ops@b.example>"]
+		);
+
+		// No alias, so delivered as is
+		assert_eq!(
+			resolve(&map, "dev@b.example").unwrap(),
+			vec!["<dev@b.example>"]
+		);
+		assert_eq!(
+			resolve(&map, "x@elsewhere.example").unwrap(),
+			vec!["<x@elsewhere.example>"]
+		);
+
+		assert!(map.has_domain(&Domain::FQDN("a.example".into())));
+		assert!(!map.has_domain(&Domain::FQDN("nove.dev".into())));
+	}
+
+	#[test]
+	fn virtual_domain_without_catch_all() {
+		let map: AliasMap = "info@a.example: gen@b.example".parse().unwrap();
+
+		assert!(matches!(
+			resolve(&map, "sales@a.example"),
+			Err(AliasError::NoSuchAddress(_))
+		));
+	}
+
+	#[test]
+	fn loops() {
+		let map: AliasMap = "a@b.example: b@b.example\n\
+			b@b.example: c@b.example\n\
+			c@b.example: a@b.example\n\
+			self@b.example: self@b.example, a2@b.example\n"
+			.parse()
+			.unwrap();
+
+		assert!(matches!(
+			resolve(&map, "a@b.example"),
+			Err(AliasError::Loop(_))
+		));
+		assert_eq!(
+			resolve(&map, "self@b.example").unwrap(),
+			vec!["<self@b.example>", "<a2@b.example>"]
+		);
+	}
+
+	#[test]
+	fn parse_errors() {
+		assert!(matches!(
+			"info@a.example gen@b.example".parse::<AliasMap>(),
+			Err(ParseAliasError::MissingColon(1))
+		));
+		assert!(matches!(
+			"info@a.example:".parse::<AliasMap>(),
+			Err(ParseAliasError::NoTargets(1))
+		));
+		assert!(matches!(
+			"@-bad-.example: ops".parse::<AliasMap>(),
+			Err(ParseAliasError::InvalidSource(1))
+		));
+	}
+}
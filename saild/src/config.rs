@@ -2,18 +2,43 @@ use std::{
 	net::{IpAddr, SocketAddr},
 	path::PathBuf,
 	str::FromStr,
+	time::Duration,
 };
 
-use confindent::Confindent;
+use confindent::{Confindent, Value};
 use getopts::Options;
-use sail::smtp::args::{Domain, ForwardPath};
+use sail::{
+	net::{Relayhost, Route},
+	smtp::{
+		args::{Domain, ForwardPath},
+		Credentials,
+	},
+};
 use thiserror::Error;
 
+use crate::queue::{Retry, Transports};
+
 pub struct Config {
-	pub address: IpAddr,
-	pub port: u16,
+	pub listeners: Vec<Listener>,
 	pub maildir: MaildirTemplate,
+	/// Where outbound mail waits until it's delivered. Defaults to `spool`
+	pub spool: PathBuf,
+	/// When to retry mail that couldn't be delivered, and when to give up
+	pub retry: Retry,
+	/// Where foreign mail is sent. MX records are used unless there's a
+	/// Relayhost or a Transport for the domain.
+	pub transports: Transports,
 	pub hostnames: Vec<Domain>,
+	/// The largest message we'll accept, in octets. Unlimited if not set.
+	pub max_message_size: Option<usize>,
+	/// Paths to the PEM certificate chain and private key used for TLS. If
+	/// these are set, STARTTLS is offered.
+	pub tls_certificate: Option<PathBuf>,
+	pub tls_key: Option<PathBuf>,
+	/// A passwd-style user file. See [crate::userdb::FlatFile]
+	pub users: Option<PathBuf>,
+	/// Alias and virtual domain table. See [crate::alias::AliasMap]
+	pub aliases: Option<PathBuf>,
 }
 
 #[allow(clippy::or_fun_call)]
@@ -23,10 +48,6 @@ impl Config {
 		println!("{}", opts.usage(&brief));
 	}
 
-	pub fn socket_address(&self) -> SocketAddr {
-		SocketAddr::new(self.address, self.port)
-	}
-
 	pub fn get() -> Option<Self> {
 		let args: Vec<String> = std::env::args().collect();
 
@@ -41,7 +62,7 @@ impl Config {
 		opts.optopt(
 			"p",
 			"port",
-			"The port Sail will listen on\nDefault: 25",
+			"The port Sail will listen on when no Listeners are configured\nDefault: 25",
 			"PORT",
 		);
 		opts.optopt(
@@ -107,13 +128,29 @@ impl Config {
 			}
 		};
 
-		let port_string = find_value("port").unwrap_or("25".into());
-		let port = match port_string.parse() {
-			Ok(p) => p,
-			Err(_e) => {
-				eprintln!("Failed to parse '{}' as a port", port_string);
-				return None;
+		let listeners = if config.has_child("Listener") {
+			let mut listeners = vec![];
+			for value in config.children("Listener") {
+				listeners.push(Listener::from_value(value, address)?);
 			}
+
+			listeners
+		} else {
+			let port_string = find_value("port").unwrap_or("25".into());
+			let port = match port_string.parse() {
+				Ok(p) => p,
+				Err(_e) => {
+					eprintln!("Failed to parse '{}' as a port", port_string);
+					return None;
+				}
+			};
+
+			vec![Listener {
+				address,
+				port,
+				mode: ListenerMode::Mta,
+				require_auth: false,
+			}]
 		};
 
 		let maildir = match config.child_value("Maildir").unwrap().parse() {
@@ -148,15 +185,300 @@ impl Config {
 			}
 		};
 
+		let max_message_size = match config.child_value("MaxMessageSize") {
+			None => None,
+			Some(size) => match size.parse() {
+				Ok(size) => Some(size),
+				Err(_e) => {
+					eprintln!("Failed to parse '{}' as a message size", size);
+					return None;
+				}
+			},
+		};
+
+		let mut retry = Retry::default();
+
+		if let Some(intervals) = config.child_value("RetryIntervals") {
+			let mut parsed = vec![];
+			for interval in intervals.split(',') {
+				match parse_duration(interval.trim()) {
+					Some(interval) => parsed.push(interval),
+					None => {
+						eprintln!("Failed to parse '{}' as a retry interval", interval.trim());
+						return None;
+					}
+				}
+			}
+
+			retry.intervals = parsed;
+		}
+
+		if let Some(lifetime) = config.child_value("QueueLifetime") {
+			match parse_duration(lifetime) {
+				Some(lifetime) => retry.lifetime = lifetime,
+				None => {
+					eprintln!("Failed to parse '{}' as a queue lifetime", lifetime);
+					return None;
+				}
+			}
+		}
+
+		let mut transports = Transports::default();
+
+		if let Some(value) = config.child("Relayhost") {
+			transports.default = route_from_value(value)?;
+		}
+
+		for value in config.children("Transport") {
+			let domain: Domain = match value.parse() {
+				Ok(domain) => domain,
+				Err(_e) => {
+					eprintln!(
+						"Failed to parse '{}' as a Transport domain",
+						value.value().unwrap_or_default()
+					);
+					return None;
+				}
+			};
+
+			let route = match value.child("Relayhost") {
+				Some(relayhost) => route_from_value(relayhost)?,
+				None => {
+					eprintln!("Transport {} needs a Relayhost", domain);
+					return None;
+				}
+			};
+
+			transports
+				.domains
+				.insert(domain.to_string().to_lowercase(), route);
+		}
+
+		let tls_certificate = config.child_value("TlsCertificate").map(PathBuf::from);
+		let tls_key = config.child_value("TlsKey").map(PathBuf::from);
+
+		if tls_certificate.is_some() != tls_key.is_some() {
+			eprintln!("TlsCertificate and TlsKey must be set together");
+			return None;
+		}
+
+		if tls_certificate.is_none()
+			&& listeners
+				.iter()
+				.any(|listener| listener.mode == ListenerMode::Submissions)
+		{
+			eprintln!("A submissions Listener needs TlsCertificate and TlsKey");
+			return None;
+		}
+
+		let users = config.child_value("Users").map(PathBuf::from);
+		let aliases = config.child_value("Aliases").map(PathBuf::from);
+		let spool = PathBuf::from(config.child_value("Spool").unwrap_or("spool"));
+
 		Some(Self {
-			address,
-			port,
+			listeners,
 			maildir,
+			spool,
+			retry,
+			transports,
 			hostnames,
+			max_message_size,
+			tls_certificate,
+			tls_key,
+			users,
+			aliases,
 		})
 	}
 }
 
+/// Parse a duration like `30s`, `5m`, `2h`, or `5d`
+fn parse_duration(s: &str) -> Option<Duration> {
+	let unit = match s.chars().last()? {
+		's' => 1,
+		'm' => 60,
+		'h' => 60 * 60,
+		'd' => 24 * 60 * 60,
+		_ => return None,
+	};
+
+	let count: u64 = s[..s.len() - 1].parse().ok()?;
+	Some(Duration::from_secs(count * unit))
+}
+
+/// Read a Relayhost, for all foreign mail or under a Transport. A value of
+/// `direct` means to use MX records like usual. RequireTls defaults to yes
+/// when there's a Username and Password.
+/// ```text
+/// Relayhost smtp.example.net:587
+///     Username gen
+///     Password hunter2
+///     RequireTls yes
+/// ```
+fn route_from_value(value: &Value) -> Option<Route> {
+	let host = value.value().unwrap_or_default();
+	if host.eq_ignore_ascii_case("direct") {
+		return Some(Route::Mx);
+	}
+
+	let mut relayhost: Relayhost = match host.parse() {
+		Ok(relayhost) => relayhost,
+		Err(e) => {
+			eprintln!("Could not parse Relayhost '{}': {}", host, e);
+			return None;
+		}
+	};
+
+	relayhost.credentials = match (value.child_value("Username"), value.child_value("Password")) {
+		(Some(username), Some(password)) => Some(Credentials {
+			username: username.to_owned(),
+			password: password.to_owned(),
+		}),
+		(None, None) => None,
+		_ => {
+			eprintln!("Relayhost {} needs both a Username and Password", host);
+			return None;
+		}
+	};
+
+	relayhost.require_tls = match value.child_value("RequireTls") {
+		None => relayhost.credentials.is_some(),
+		Some("yes") => true,
+		Some("no") if relayhost.credentials.is_some() => {
+			eprintln!("Relayhost {} has a password, so it must use TLS", host);
+			return None;
+		}
+		Some("no") => false,
+		Some(other) => {
+			eprintln!("RequireTls should be yes or no, not '{}'", other);
+			return None;
+		}
+	};
+
+	Some(Route::Relayhost(relayhost))
+}
+
+/// A socket saild accepts connections on, and what kind of service it offers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listener {
+	pub address: IpAddr,
+	pub port: u16,
+	pub mode: ListenerMode,
+	/// Whether clients must AUTH before sending mail
+	pub require_auth: bool,
+}
+
+impl Listener {
+	pub fn socket_address(&self) -> SocketAddr {
+		SocketAddr::new(self.address, self.port)
+	}
+
+	/// Read a listener from the config. They look like this, where Address
+	/// defaults to ListenAddress, and Port and RequireAuth default to what's
+	/// usual for the mode:
+	/// ```text
+	/// Listener submission
+	///     Address 0.0.0.0
+	///     Port 587
+	///     RequireAuth yes
+	/// ```
+	fn from_value(value: &Value, default_address: IpAddr) -> Option<Self> {
+		let mode: ListenerMode = match value.parse() {
+			Ok(mode) => mode,
+			Err(e) => {
+				eprintln!("Could not parse Listener: {}", e);
+				return None;
+			}
+		};
+
+		let address = match value.child_value("Address") {
+			None => default_address,
+			Some(addr) => match addr.parse() {
+				Ok(addr) => addr,
+				Err(_e) => {
+					eprintln!("Failed to parse '{}' as an IP Address", addr);
+					return None;
+				}
+			},
+		};
+
+		let port = match value.child_value("Port") {
+			None => mode.default_port(),
+			Some(port) => match port.parse() {
+				Ok(port) => port,
+				Err(_e) => {
+					eprintln!("Failed to parse '{}' as a port", port);
+					return None;
+				}
+			},
+		};
+
+		let require_auth = match value.child_value("RequireAuth") {
+			None => mode.requires_auth(),
+			Some("yes") => true,
+			Some("no") => false,
+			Some(other) => {
+				eprintln!("RequireAuth should be yes or no, not '{}'", other);
+				return None;
+			}
+		};
+
+		Some(Self {
+			address,
+			port,
+			mode,
+			require_auth,
+		})
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListenerMode {
+	/// Plain SMTP for receiving mail from other servers (RFC 5321)
+	Mta,
+	/// Message submission, usually on port 587 (RFC 6409)
+	Submission,
+	/// Message submission over implicit TLS, usually on port 465 (RFC 8314)
+	Submissions,
+}
+
+impl ListenerMode {
+	pub fn default_port(&self) -> u16 {
+		match self {
+			ListenerMode::Mta => 25,
+			ListenerMode::Submission => 587,
+			ListenerMode::Submissions => 465,
+		}
+	}
+
+	/// RFC 6409 section 4.3 says submission needs authentication. Other
+	/// servers relaying to us on port 25 won't have an account.
+	pub fn requires_auth(&self) -> bool {
+		match self {
+			ListenerMode::Mta => false,
+			ListenerMode::Submission | ListenerMode::Submissions => true,
+		}
+	}
+}
+
+impl FromStr for ListenerMode {
+	type Err = ParseListenerModeError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"mta" => Ok(ListenerMode::Mta),
+			"submission" => Ok(ListenerMode::Submission),
+			"submissions" => Ok(ListenerMode::Submissions),
+			_ => Err(ParseListenerModeError::UnrecognizedMode(s.into())),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ParseListenerModeError {
+	#[error("'{0}' is not a listener mode. Try mta, submission, or submissions")]
+	UnrecognizedMode(String),
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct MaildirTemplate {
 	tokens: Vec<TemplateToken>,
@@ -341,6 +663,17 @@ pub enum ParseModifiersError {
 mod test {
 	use super::*;
 
+	#[test]
+	fn durations() {
+		assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+		assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+		assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+		assert_eq!(parse_duration("5d"), Some(Duration::from_secs(432_000)));
+		assert_eq!(parse_duration("5"), None);
+		assert_eq!(parse_duration("h"), None);
+		assert_eq!(parse_duration("5w"), None);
+	}
+
 	#[test]
 	fn template_parse() {
 		let tp = MaildirTemplate::from_str(
@@ -367,6 +700,74 @@ mod test {
 		)
 	}
 
+	#[test]
+	fn listeners() {
+		let conf: Confindent =
+			"Listener mta\nListener Submission\n\tAddress ::1\nListener submissions\n\tPort 4650\n\tRequireAuth no"
+				.parse()
+				.unwrap();
+		let default: IpAddr = "127.0.0.1".parse().unwrap();
+
+		let listeners: Vec<Listener> = conf
+			.children("Listener")
+			.into_iter()
+			.map(|value| Listener::from_value(value, default).unwrap())
+			.collect();
+
+		assert_eq!(
+			listeners,
+			vec![
+				Listener {
+					address: default,
+					port: 25,
+					mode: ListenerMode::Mta,
+					require_auth: false,
+				},
+				Listener {
+					address: "::1".parse().unwrap(),
+					port: 587,
+					mode: ListenerMode::Submission,
+					require_auth: true,
+				},
+				Listener {
+					address: default,
+					port: 4650,
+					mode: ListenerMode::Submissions,
+					require_auth: false,
+				},
+			]
+		);
+
+		let bad: Confindent = "Listener lmtp".parse().unwrap();
+		assert!(Listener::from_value(bad.child("Listener").unwrap(), default).is_none());
+	}
+
+	#[test]
+	fn relayhosts() {
+		let conf: Confindent = "Relayhost smtp.example.net:587\n\tUsername gen\n\tPassword hunter2\nTransport nyble.dev\n\tRelayhost direct"
+			.parse()
+			.unwrap();
+
+		let relayhost = match route_from_value(conf.child("Relayhost").unwrap()) {
+			Some(Route::Relayhost(relayhost)) => relayhost,
+			other => panic!("expected a relayhost, got {:?}", other),
+		};
+		assert_eq!(relayhost.port, 587);
+		assert_eq!(relayhost.credentials.unwrap().username, "gen");
+		assert!(relayhost.require_tls);
+
+		let transport = conf.child("Transport").unwrap();
+		assert_eq!(
+			route_from_value(transport.child("Relayhost").unwrap()),
+			Some(Route::Mx)
+		);
+
+		let bad: Confindent = "Relayhost smtp.example.net\n\tUsername gen"
+			.parse()
+			.unwrap();
+		assert!(route_from_value(bad.child("Relayhost").unwrap()).is_none());
+	}
+
 	#[test]
 	fn maildir_as_path() {
 		let mdtpl: MaildirTemplate =
@@ -1,6 +1,37 @@
-use std::path::PathBuf;
+use std::{
+	fs::{File, OpenOptions},
+	io::{self, Read, Seek, Write},
+	path::PathBuf,
+	time::SystemTime,
+};
 
-struct MailCache {
+use rand::Rng;
+use sail::{
+	policy::MessageSink,
+	smtp::{
+		args::{Domain, ForeignPath, ForwardPath, Parameters, Path, ReversePath},
+		Envelope, ForeignEnvelope, Message,
+	},
+};
+use thiserror::Error;
+
+/// An on-disk spool for outbound mail. Entries are written to `tmp` and then
+/// renamed into `queue`, so anything in `queue` is complete. An entry stays
+/// there until it's delivered or bounced.
+///
+/// Delivery reports wait in `reports` the same way until they've been handed
+/// to local delivery or queued in turn.
+///
+/// The message an entry is about is in `messages` under the same id, and is
+/// there before the entry is. Messages are received into `tmp` and linked
+/// into place, so they're never copied or read into memory whole.
+const QUEUE: &str = "queue";
+const REPORTS: &str = "reports";
+const MESSAGES: &str = "messages";
+const TMP: &str = "tmp";
+
+#[derive(Clone, Debug)]
+pub struct MailCache {
 	cache_base: PathBuf,
 }
 
@@ -10,4 +41,526 @@ impl MailCache {
 			cache_base: cache.into(),
 		}
 	}
+
+	pub fn create_directories(&self) -> io::Result<()> {
+		std::fs::create_dir_all(self.cache_base.join(TMP))?;
+		std::fs::create_dir_all(self.cache_base.join(QUEUE))?;
+		std::fs::create_dir_all(self.cache_base.join(REPORTS))?;
+		std::fs::create_dir_all(self.cache_base.join(MESSAGES))
+	}
+
+	/// Write mail to the spool, with the message in `data`, returning the id
+	/// it was queued under. Once this returns the mail will survive a
+	/// restart.
+	pub fn spool(&self, mail: &QueuedMail, data: &mut dyn MessageSink) -> io::Result<String> {
+		self.spool_in(QUEUE, mail, data)
+	}
+
+	/// Write a delivery report to the spool, returning its id. Like with
+	/// [MailCache::spool], it survives a restart once this returns.
+	pub fn spool_report(&self, report: &QueuedMail) -> io::Result<String> {
+		let mut data = report.envelope.data.to_bytes();
+		self.spool_in(REPORTS, report, &mut data)
+	}
+
+	fn spool_in(
+		&self,
+		directory: &str,
+		mail: &QueuedMail,
+		data: &mut dyn MessageSink,
+	) -> io::Result<String> {
+		self.create_directories()?;
+
+		let id = Self::get_unique_name();
+		self.save_message(&id, data)?;
+		if let Err(e) = self.write(directory, &id, mail) {
+			let _ = std::fs::remove_file(self.path(MESSAGES, &id));
+			return Err(e);
+		}
+
+		Ok(id)
+	}
+
+	/// Put the message in `data` where the entry `id` will look for it. If
+	/// it's already on disk it's linked rather than copied.
+	fn save_message(&self, id: &str, data: &mut dyn MessageSink) -> io::Result<()> {
+		let path = self.path(MESSAGES, id);
+		match data.path() {
+			Some(received) => std::fs::hard_link(received, &path)?,
+			None => {
+				let tmp_path = self.path(TMP, id);
+				let mut tmp = OpenOptions::new()
+					.write(true)
+					.create_new(true)
+					.open(&tmp_path)?;
+				io::copy(&mut data.reader()?, &mut tmp)?;
+				std::fs::rename(tmp_path, &path)?;
+			}
+		}
+
+		File::open(path)?.sync_all()
+	}
+
+	/// Somewhere in `tmp` to receive a message into. It's removed once it's
+	/// dropped, and anything left by an unclean exit goes with
+	/// [MailCache::clean_up].
+	pub fn receive(&self) -> io::Result<IncomingMessage> {
+		self.create_directories()?;
+
+		let path = self.path(TMP, &Self::get_unique_name());
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(&path)?;
+
+		Ok(IncomingMessage { file, path })
+	}
+
+	/// Remove what an unclean exit left behind: anything in `tmp`, and
+	/// messages without an entry. Nothing can be being spooled while this
+	/// runs.
+	pub fn clean_up(&self) -> io::Result<()> {
+		for id in self.ids(TMP)? {
+			std::fs::remove_file(self.path(TMP, &id))?;
+		}
+
+		for id in self.ids(MESSAGES)? {
+			if !self.path(QUEUE, &id).exists() && !self.path(REPORTS, &id).exists() {
+				std::fs::remove_file(self.path(MESSAGES, &id))?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Replace an entry that's already in the queue, like after a failed
+	/// delivery attempt
+	pub fn update(&self, id: &str, mail: &QueuedMail) -> io::Result<()> {
+		self.write(QUEUE, id, mail)
+	}
+
+	fn write(&self, directory: &str, id: &str, mail: &QueuedMail) -> io::Result<()> {
+		let tmp_path = self.path(TMP, id);
+
+		{
+			let mut tmp = OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(&tmp_path)?;
+			tmp.write_all(&mail.to_bytes())?;
+			tmp.sync_all()?;
+		}
+
+		std::fs::rename(tmp_path, self.path(directory, id))
+	}
+
+	/// Everything in the queue, oldest first. Entries we can't read are
+	/// reported and left where they are for someone to look at.
+	pub fn entries(&self) -> io::Result<Vec<(String, QueuedMail)>> {
+		let mut entries = vec![];
+		for id in self.ids(QUEUE)? {
+			match self.read(&id) {
+				Ok(mail) => entries.push((id, mail)),
+				Err(e) => eprintln!("Skipping queue entry {}: {}", id, e),
+			}
+		}
+
+		Ok(entries)
+	}
+
+	/// The ids of the reports waiting to be delivered, oldest first
+	pub fn reports(&self) -> io::Result<Vec<String>> {
+		self.ids(REPORTS)
+	}
+
+	fn ids(&self, directory: &str) -> io::Result<Vec<String>> {
+		let directory = self.cache_base.join(directory);
+		if !directory.exists() {
+			return Ok(vec![]);
+		}
+
+		let mut ids = vec![];
+		for entry in std::fs::read_dir(directory)? {
+			ids.push(entry?.file_name().to_string_lossy().into_owned());
+		}
+		ids.sort();
+
+		Ok(ids)
+	}
+
+	/// A queue entry. Only the header section of its message is read; the
+	/// rest is streamed from [MailCache::open_message] when it's sent.
+	pub fn read(&self, id: &str) -> Result<QueuedMail, ParseQueuedMailError> {
+		let mut mail = QueuedMail::from_bytes(&std::fs::read(self.path(QUEUE, id))?)?;
+		mail.envelope.data = self.open_message(id)?.header_section()?;
+		Ok(mail)
+	}
+
+	/// A spooled report, with all of its message
+	pub fn read_report(&self, id: &str) -> Result<QueuedMail, ParseQueuedMailError> {
+		let mut report = QueuedMail::from_bytes(&std::fs::read(self.path(REPORTS, id))?)?;
+		report.envelope.data = self.open_message(id)?.message()?;
+		Ok(report)
+	}
+
+	/// The message an entry is about, to be read from the start
+	pub fn open_message(&self, id: &str) -> io::Result<File> {
+		File::open(self.path(MESSAGES, id))
+	}
+
+	/// Take an entry out of the queue after it's been delivered or bounced
+	pub fn remove(&self, id: &str) -> io::Result<()> {
+		std::fs::remove_file(self.path(QUEUE, id))?;
+		std::fs::remove_file(self.path(MESSAGES, id))
+	}
+
+	/// Take a report out of the spool once it's been delivered or queued
+	pub fn remove_report(&self, id: &str) -> io::Result<()> {
+		std::fs::remove_file(self.path(REPORTS, id))?;
+		std::fs::remove_file(self.path(MESSAGES, id))
+	}
+
+	fn path(&self, directory: &str, id: &str) -> PathBuf {
+		self.cache_base.join(directory).join(id)
+	}
+
+	// Names sort by when they were queued
+	fn get_unique_name() -> String {
+		let time = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.expect("SystemTime unwrap failed! Is your system clock before the unix epoch?");
+		let random: u32 = rand::rng().random();
+
+		format!("{:020}.{:08x}", time.as_nanos(), random)
+	}
+}
+
+/// A message being received into the spool. Its file is removed when it's
+/// dropped, so only what's been linked into `messages` is kept.
+#[derive(Debug)]
+pub struct IncomingMessage {
+	file: File,
+	path: PathBuf,
+}
+
+impl Write for IncomingMessage {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.file.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+impl MessageSink for IncomingMessage {
+	fn reader(&mut self) -> io::Result<Box<dyn Read + '_>> {
+		self.file.rewind()?;
+		Ok(Box::new(&mut self.file))
+	}
+
+	fn path(&self) -> Option<&std::path::Path> {
+		Some(&self.path)
+	}
+}
+
+impl Drop for IncomingMessage {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+/// Mail waiting to be relayed to a single domain. On disk it's a few header
+/// lines describing the envelope. The message is kept apart from it, see
+/// [MailCache].
+#[derive(Clone, Debug)]
+pub struct QueuedMail {
+	pub domain: Domain,
+	/// Once it's been spooled, the data only holds the header section
+	pub envelope: ForeignEnvelope,
+	/// When the mail entered the queue, in seconds since the unix epoch
+	pub queued: u64,
+	/// How many times we've tried and failed to deliver it
+	pub attempts: u32,
+	/// When to try next, in seconds since the unix epoch
+	pub next_attempt: u64,
+}
+
+impl QueuedMail {
+	/// Mail that's just been queued and should be sent right away
+	pub fn new(domain: Domain, envelope: ForeignEnvelope) -> Self {
+		let now = unix_now();
+
+		Self {
+			domain,
+			envelope,
+			queued: now,
+			attempts: 0,
+			next_attempt: now,
+		}
+	}
+
+	/// A delivery report to be spooled. Reports go to the sender of the mail
+	/// they're about, so None if there's nobody to send this one to.
+	pub fn from_report(report: Envelope) -> Option<Self> {
+		let mut envelope = ForeignEnvelope::from_parts(report.reverse_path, vec![], report.data);
+		envelope.mail_parameters = report.mail_parameters;
+		for (forward, parameters) in report.forward_paths.into_iter().zip(report.rcpt_parameters) {
+			if let ForwardPath::Regular(path) = forward {
+				envelope.add_recipient_with(ForeignPath(path), parameters);
+			}
+		}
+
+		let domain = envelope.forward_paths.first()?.0.domain.clone();
+		Some(Self::new(domain, envelope))
+	}
+
+	/// The entry as it's written to disk, without the message
+	pub fn to_bytes(&self) -> Vec<u8> {
+		self.to_string().into_bytes()
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseQueuedMailError> {
+		let head = std::str::from_utf8(bytes)?;
+		Self::from_envelope_lines(head.trim_end_matches("\r\n"))
+	}
+}
+
+/// The current time in seconds since the unix epoch
+pub fn unix_now() -> u64 {
+	SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.expect("SystemTime unwrap failed! Is your system clock before the unix epoch?")
+		.as_secs()
+}
+
+/// The envelope lines, without the message
+impl std::fmt::Display for QueuedMail {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Domain: {}\r\n", self.domain)?;
+		write!(f, "Queued: {}\r\n", self.queued)?;
+		write!(f, "Attempts: {}\r\n", self.attempts)?;
+		write!(f, "Next-Attempt: {}\r\n", self.next_attempt)?;
+		write!(f, "Reverse-Path: {}\r\n", self.envelope.reverse_path)?;
+		if !self.envelope.mail_parameters.is_empty() {
+			write!(f, "Mail-Parameters:{}\r\n", self.envelope.mail_parameters)?;
+		}
+		// Parameters belong to the Forward-Path before them
+		for (forward, parameters) in self.envelope.recipients() {
+			write!(f, "Forward-Path: {}\r\n", forward.0)?;
+			if !parameters.is_empty() {
+				write!(f, "Rcpt-Parameters:{}\r\n", parameters)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl QueuedMail {
+	fn from_envelope_lines(head: &str) -> Result<Self, ParseQueuedMailError> {
+		let mut domain = None;
+		let mut queued = None;
+		let mut attempts = 0;
+		let mut next_attempt = 0;
+		let mut reverse = None;
+		let mut mail_parameters = Parameters::new();
+		let mut forwards: Vec<(ForeignPath, Parameters)> = vec![];
+
+		for line in head.split("\r\n") {
+			let (field, value) = line
+				.split_once(": ")
+				.ok_or_else(|| ParseQueuedMailError::InvalidLine(line.to_owned()))?;
+			let invalid = || ParseQueuedMailError::InvalidLine(line.to_owned());
+
+			match field {
+				"Domain" => domain = Some(value.parse::<Domain>().map_err(|_| invalid())?),
+				"Queued" => queued = Some(value.parse().map_err(|_| invalid())?),
+				"Attempts" => attempts = value.parse().map_err(|_| invalid())?,
+				"Next-Attempt" => next_attempt = value.parse().map_err(|_| invalid())?,
+				"Reverse-Path" => {
+					reverse = Some(value.parse::<ReversePath>().map_err(|_| invalid())?)
+				}
+				"Mail-Parameters" => mail_parameters = value.parse().map_err(|_| invalid())?,
+				"Forward-Path" => forwards.push((
+					ForeignPath(value.parse::<Path>().map_err(|_| invalid())?),
+					Parameters::new(),
+				)),
+				"Rcpt-Parameters" => match forwards.last_mut() {
+					Some((_, parameters)) => *parameters = value.parse().map_err(|_| invalid())?,
+					None => return Err(invalid()),
+				},
+				_ => return Err(invalid()),
+			}
+		}
+
+		let mut envelope = ForeignEnvelope::from_parts(
+			reverse.ok_or(ParseQueuedMailError::MissingField("Reverse-Path"))?,
+			vec![],
+			Message::empty(),
+		);
+		envelope.mail_parameters = mail_parameters;
+		for (forward, parameters) in forwards {
+			envelope.add_recipient_with(forward, parameters);
+		}
+
+		Ok(Self {
+			domain: domain.ok_or(ParseQueuedMailError::MissingField("Domain"))?,
+			envelope,
+			queued: queued.ok_or(ParseQueuedMailError::MissingField("Queued"))?,
+			attempts,
+			next_attempt,
+		})
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum ParseQueuedMailError {
+	#[error("failed to read queue entry: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("the envelope is not valid UTF-8: {0}")]
+	Utf8(#[from] std::str::Utf8Error),
+	#[error("the envelope is missing {0}")]
+	MissingField(&'static str),
+	#[error("invalid envelope line: {0}")]
+	InvalidLine(String),
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn spool_round_trip() {
+		let dir = std::env::temp_dir().join(format!("saild-spool-{}", std::process::id()));
+		let cache = MailCache::new(&dir);
+
+		let mut data = b"Subject: hi\r\n\r\nh\xe9llo\r\n\r\nthere\r\n".to_vec();
+		let mut envelope = ForeignEnvelope::from_parts(
+			"<gen@nove.dev>".parse().unwrap(),
+			vec![],
+			Message::empty(),
+		);
+		envelope.mail_parameters = " RET=HDRS ENVID=QQ314".parse().unwrap();
+		envelope.add_recipient_with(
+			ForeignPath("<a@b.example>".parse().unwrap()),
+			" NOTIFY=SUCCESS,FAILURE".parse().unwrap(),
+		);
+		envelope.add_recipient_with(
+			ForeignPath("<c@b.example>".parse().unwrap()),
+			Parameters::new(),
+		);
+		let mut mail = QueuedMail::new("b.example".parse().unwrap(), envelope);
+
+		let first = cache.spool(&mail, &mut data).unwrap();
+		let second = cache.spool(&mail, &mut data).unwrap();
+
+		let entries = cache.entries().unwrap();
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].0, first);
+		assert_eq!(entries[1].0, second);
+		assert_eq!(entries[0].1.to_bytes(), mail.to_bytes());
+		assert_eq!(entries[0].1.envelope.forward_paths.len(), 2);
+		assert_eq!(
+			entries[0].1.envelope.rcpt_parameters,
+			vec![
+				" NOTIFY=SUCCESS,FAILURE".parse().unwrap(),
+				Parameters::new()
+			]
+		);
+		assert!(entries[0].1.envelope.mail_parameters.contains("ENVID"));
+
+		// Only the header section is read back, the rest stays on disk
+		assert_eq!(entries[0].1.envelope.data.body, b"Subject: hi\r\n\r\n");
+		assert_eq!(
+			cache.open_message(&second).unwrap().message().unwrap().body,
+			data
+		);
+
+		mail.attempts = 3;
+		mail.next_attempt += 1800;
+		cache.update(&first, &mail).unwrap();
+		let updated = cache.read(&first).unwrap();
+		assert_eq!(updated.attempts, 3);
+		assert_eq!(updated.next_attempt, mail.next_attempt);
+		assert_eq!(updated.queued, mail.queued);
+
+		cache.remove(&first).unwrap();
+		cache.remove(&second).unwrap();
+		assert!(cache.entries().unwrap().is_empty());
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn reports_are_spooled() {
+		let dir = std::env::temp_dir().join(format!("saild-reports-{}", std::process::id()));
+		let cache = MailCache::new(&dir);
+
+		let mut report = Envelope::new(ReversePath::Null);
+		report.add_recipient(ForwardPath::Regular("<gen@nove.dev>".parse().unwrap()));
+		report.add_recipient(ForwardPath::Postmaster);
+		report.data.body = b"Subject: Delivery Status Notification\r\n\r\nsorry\r\n".to_vec();
+
+		let report = QueuedMail::from_report(report).unwrap();
+		assert_eq!(report.envelope.forward_paths.len(), 1);
+		let id = cache.spool_report(&report).unwrap();
+
+		// Reports aren't queue entries
+		assert!(cache.entries().unwrap().is_empty());
+		assert_eq!(cache.reports().unwrap(), vec![id.clone()]);
+		let spooled = cache.read_report(&id).unwrap();
+		assert_eq!(spooled.to_bytes(), report.to_bytes());
+		assert_eq!(
+			spooled.envelope.data.to_bytes(),
+			report.envelope.data.to_bytes()
+		);
+
+		cache.remove_report(&id).unwrap();
+		assert!(cache.reports().unwrap().is_empty());
+		assert!(QueuedMail::from_report(Envelope::new(ReversePath::Null)).is_none());
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn received_messages_are_linked() {
+		let dir = std::env::temp_dir().join(format!("saild-receive-{}", std::process::id()));
+		let cache = MailCache::new(&dir);
+		let mail = QueuedMail::new(
+			"b.example".parse().unwrap(),
+			ForeignEnvelope::from_parts(
+				"<gen@nove.dev>".parse().unwrap(),
+				vec![ForeignPath("<a@b.example>".parse().unwrap())],
+				Message::empty(),
+			),
+		);
+
+		let mut incoming = cache.receive().unwrap();
+		incoming.write_all(b"Subject: hi\r\n\r\n\xff\r\n").unwrap();
+		assert_eq!(
+			incoming.message().unwrap().body,
+			b"Subject: hi\r\n\r\n\xff\r\n"
+		);
+
+		let id = cache.spool(&mail, &mut incoming).unwrap();
+		drop(incoming);
+		assert_eq!(std::fs::read_dir(dir.join(TMP)).unwrap().count(), 0);
+		assert_eq!(
+			cache.open_message(&id).unwrap().message().unwrap().body,
+			b"Subject: hi\r\n\r\n\xff\r\n"
+		);
+
+		// A crash can leave a message without its entry, or a half-written file
+		std::fs::write(dir.join(TMP).join("partial"), b"Subj").unwrap();
+		std::fs::write(dir.join(MESSAGES).join("orphan"), b"").unwrap();
+		cache.clean_up().unwrap();
+		assert_eq!(std::fs::read_dir(dir.join(TMP)).unwrap().count(), 0);
+		assert_eq!(cache.ids(MESSAGES).unwrap(), vec![id.clone()]);
+
+		cache.remove(&id).unwrap();
+		assert!(cache.ids(MESSAGES).unwrap().is_empty());
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
 }
@@ -1,8 +1,12 @@
-use std::{fs::OpenOptions, io::Write, path::PathBuf, time::SystemTime};
+use std::{
+	fs::OpenOptions,
+	io::{self, Read},
+	path::PathBuf,
+	time::SystemTime,
+};
 
 use gethostname::gethostname;
 use rand::Rng;
-use sail::smtp::Message;
 
 pub struct Maildir {
 	maildir: PathBuf,
@@ -35,8 +39,26 @@ impl Maildir {
 		}
 	}
 
+	/// The total size, in octets, of the messages in `new` and `cur`
+	pub fn size(&self) -> std::io::Result<u64> {
+		let mut size = 0;
+
+		for sub in ["new", "cur"] {
+			let dir = self.maildir.join(sub);
+			if !dir.exists() {
+				continue;
+			}
+
+			for entry in std::fs::read_dir(dir)? {
+				size += entry?.metadata()?.len();
+			}
+		}
+
+		Ok(size)
+	}
+
 	//TODO: Don't unwrap in here. Keep trying until we get a unique name, but these should be truly unique.
-	pub fn save(&self, message: Message) -> std::io::Result<()> {
+	pub fn save(&self, message: &mut dyn Read) -> std::io::Result<()> {
 		let unique_name = Self::get_unique_name();
 		let mut tmp_path = self.maildir.clone();
 		tmp_path.push("tmp");
@@ -50,9 +72,8 @@ impl Maildir {
 			let mut tmp = OpenOptions::new()
 				.write(true)
 				.create_new(true)
-				.open(&tmp_path)
-				.expect("Failed to open unique file for writing!");
-			tmp.write_all(message.to_string().as_bytes())?
+				.open(&tmp_path)?;
+			io::copy(message, &mut tmp)?;
 		}
 		std::fs::rename(tmp_path, new_path)
 	}
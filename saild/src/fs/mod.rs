@@ -1,4 +1,5 @@
 mod mailcache;
 mod maildir;
 
+pub use mailcache::{unix_now, IncomingMessage, MailCache, ParseQueuedMailError, QueuedMail};
 pub use maildir::Maildir;
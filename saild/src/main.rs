@@ -1,13 +1,26 @@
+mod alias;
 mod config;
 pub mod fs;
 mod net;
 mod policy;
+mod queue;
+mod tls;
+mod userdb;
 
+use alias::AliasMap;
 use config::Config;
+use fs::MailCache;
 use policy::ServerPolicy;
+use queue::Queue;
+use sail::{
+	net::dns::HickoryResolver,
+	policy::{CredentialBackend, Policy},
+	smtp::args::Domain,
+};
 
 use std::sync::Arc;
 use tokio::net::TcpListener;
+use userdb::{FlatFile, UserDatabase};
 
 #[tokio::main]
 async fn main() {
@@ -16,15 +29,114 @@ async fn main() {
 		None => return,
 	};
 
-	let listener = TcpListener::bind(binconf.socket_address()).await.unwrap();
+	let tls = match (&binconf.tls_certificate, &binconf.tls_key) {
+		(Some(cert), Some(key)) => match tls::acceptor(cert, key) {
+			Ok(acceptor) => Some(acceptor),
+			Err(e) => {
+				eprintln!("Failed to load TLS certificate: {}", e);
+				return;
+			}
+		},
+		_ => None,
+	};
+
+	let users = match &binconf.users {
+		None => None,
+		Some(path) => match FlatFile::open(path) {
+			Ok(users) => Some(Arc::new(users)),
+			Err(e) => {
+				eprintln!("Failed to load users from {}: {}", path.display(), e);
+				return;
+			}
+		},
+	};
+
+	let aliases = match &binconf.aliases {
+		None => AliasMap::default(),
+		Some(path) => match AliasMap::open(path) {
+			Ok(aliases) => aliases,
+			Err(e) => {
+				eprintln!("Failed to load aliases from {}: {}", path.display(), e);
+				return;
+			}
+		},
+	};
+
+	let hostname = binconf
+		.hostnames
+		.first()
+		.cloned()
+		.unwrap_or(Domain::FQDN("localhost".to_owned()));
+	let resolver = match HickoryResolver::new() {
+		Ok(resolver) => Arc::new(resolver),
+		Err(e) => {
+			eprintln!("Failed to set up DNS: {}", e);
+			return;
+		}
+	};
+
+	let (queue, mut reports) = Queue::new(
+		MailCache::new(&binconf.spool),
+		binconf.retry,
+		binconf.transports,
+		resolver,
+		hostname,
+	);
 
 	let policy = ServerPolicy {
 		hostnames: binconf.hostnames,
 		relays: vec![],
-		users: vec![],
+		users: users.clone().map(|users| users as Arc<dyn UserDatabase>),
 		maildir: binconf.maildir,
+		max_message_size: binconf.max_message_size,
+		starttls: tls.is_some(),
+		credentials: users.map(|users| users as Arc<dyn CredentialBackend>),
+		aliases,
+		queue: queue.clone(),
 	};
 
+	// Reports are delivered just like mail we've received. They stay spooled
+	// until that works, and one that can't be delivered is tried again later.
+	let mut report_policy = policy.clone();
+	let report_queue = queue.clone();
+	tokio::spawn(async move {
+		while let Some(id) = reports.recv().await {
+			let report = match report_queue.read_report(&id) {
+				Ok(report) => report,
+				Err(e) => {
+					eprintln!("Skipping report {}: {}", id, e);
+					continue;
+				}
+			};
+
+			let data = Box::new(report.data.to_bytes());
+			let response = report_policy.message_received(report, data);
+			match response.code.is_completion() {
+				true => report_queue.report_delivered(&id),
+				false => {
+					eprintln!(
+						"Failed to deliver report {}, will try again: {}",
+						id, response
+					);
+					report_queue.retry_report(id);
+				}
+			}
+		}
+	});
+
+	match queue.resume() {
+		Ok(0) => (),
+		Ok(count) => println!("Resuming delivery of {} queued messages", count),
+		Err(e) => {
+			eprintln!(
+				"Failed to read the spool at {}: {}",
+				binconf.spool.display(),
+				e
+			);
+			return;
+		}
+	}
+
 	let (tx, rx) = tokio::sync::watch::channel(false);
 
 	// make the arc before we move sail into receive_messages. Ideally we'd do
@@ -32,7 +144,24 @@ async fn main() {
 	// architected for that
 	let dynconf = Arc::new(policy.clone());
 
-	let listen_task = tokio::spawn(crate::net::listen(listener, dynconf, rx));
+	let mut listen_tasks = vec![];
+	for listener in &binconf.listeners {
+		let socket = match TcpListener::bind(listener.socket_address()).await {
+			Ok(socket) => socket,
+			Err(e) => {
+				eprintln!("Failed to listen on {}: {}", listener.socket_address(), e);
+				return;
+			}
+		};
+
+		listen_tasks.push(tokio::spawn(crate::net::listen(
+			socket,
+			listener.clone(),
+			dynconf.clone(),
+			tls.clone(),
+			rx.clone(),
+		)));
+	}
 	let signal_listener = tokio::spawn(async {
 		use tokio::signal::unix::{signal, SignalKind};
 		let mut a = (
@@ -65,6 +194,8 @@ async fn main() {
 		signal_listener.await;
 		println!("\nReceived shutdown signal, beginning graceful shutdown...");
 		tx.send(true);
-		listen_task.await;
+		for listen_task in listen_tasks {
+			listen_task.await;
+		}
 	}
 }
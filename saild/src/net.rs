@@ -1,29 +1,91 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use sail::smtp::Server;
+use sail::smtp::{Response, Server};
 use tokio::{
-	io::{self, AsyncReadExt, AsyncWriteExt},
+	io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 	net::{TcpListener, TcpStream},
 	sync::watch,
+	time::timeout,
 };
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
-use crate::policy::ServerPolicy;
+use crate::{
+	config::{Listener, ListenerMode},
+	policy::ServerPolicy,
+};
 
 //runs as long as the user remains connected
 // handles low-level tcp read and write nonsense, passes strings back and forth with the business logic in transaction.
 async fn serve(
 	mut stream: TcpStream,
+	listener: Listener,
 	config: Arc<ServerPolicy>,
+	tls: Option<TlsAcceptor>,
 	mut rx: watch::Receiver<bool>,
 ) -> io::Result<()> {
 	let (mut transaction, inital_response) = Server::initiate(Box::new(config.as_ref().clone()));
+	transaction.require_auth(listener.require_auth);
+
+	if listener.mode == ListenerMode::Submissions {
+		// Implicit TLS. The handshake happens before we say anything at all
+		let acceptor = match tls {
+			Some(acceptor) => acceptor,
+			None => return Ok(()),
+		};
+
+		let mut stream = start_tls(&acceptor, stream).await?;
+		transaction.tls_started();
+
+		stream
+			.write_all(inital_response.to_string().as_bytes())
+			.await?;
+
+		return converse(&mut stream, &mut transaction, &mut rx).await;
+	}
+
 	stream
 		.write_all(inital_response.to_string().as_bytes())
 		.await?;
 
+	converse(&mut stream, &mut transaction, &mut rx).await?;
+
+	if transaction.should_start_tls() {
+		// The policy only offers STARTTLS if we have an acceptor
+		let acceptor = match tls {
+			Some(acceptor) => acceptor,
+			None => return Ok(()),
+		};
+
+		let mut stream = start_tls(&acceptor, stream).await?;
+		transaction.tls_started();
+
+		converse(&mut stream, &mut transaction, &mut rx).await?;
+	}
+
+	Ok(())
+}
+
+/// How long a client gets to finish the TLS handshake, the same as it gets
+/// for a command (RFC 5321 section 4.5.3.2.7)
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Do the TLS handshake, giving up on a client that stops answering
+async fn start_tls(acceptor: &TlsAcceptor, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+	timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+		.await
+		.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the TLS handshake timed out"))?
+}
+
+// Pass data between the client and the transaction until the client leaves or
+// wants to start TLS
+async fn converse<S: AsyncRead + AsyncWrite + Unpin>(
+	stream: &mut S,
+	transaction: &mut Server,
+	rx: &mut watch::Receiver<bool>,
+) -> io::Result<()> {
 	let mut buf = vec![0; 1024];
 
-	while !transaction.should_exit() {
+	while !transaction.should_exit() && !transaction.should_start_tls() {
 		#[allow(unused_must_use)]
 		let read = tokio::select! {
 			Ok(read) = stream.read(&mut buf) => read,
@@ -40,10 +102,12 @@ async fn serve(
 			return Ok(());
 		}
 
-		let response = transaction.push(String::from_utf8_lossy(&buf[..read]).as_ref());
+		let responses = transaction.push(&buf[..read]);
 
-		if let Some(response) = response {
-			stream.write_all(response.to_string().as_bytes()).await?;
+		// Pipelined commands get all their replies in one write
+		let replies: String = responses.iter().map(Response::to_string).collect();
+		if !replies.is_empty() {
+			stream.write_all(replies.as_bytes()).await?;
 		}
 	}
 
@@ -52,18 +116,239 @@ async fn serve(
 
 //waits for new connections, dispatches new task to handle each new inbound connection
 pub async fn listen(
-	listener: TcpListener,
+	socket: TcpListener,
+	listener: Listener,
 	config: Arc<ServerPolicy>,
+	tls: Option<TlsAcceptor>,
 	mut rx: watch::Receiver<bool>,
 ) {
 	loop {
 		let (stream, clientaddr) = tokio::select! {
 			_ = rx.changed() => break,
-			Ok((stream, clientaddr)) = listener.accept() => (stream, clientaddr)
+			Ok((stream, clientaddr)) = socket.accept() => (stream, clientaddr)
 		};
 
-		println!("connection from {}", clientaddr);
+		println!(
+			"connection from {} on {:?} listener",
+			clientaddr, listener.mode
+		);
+
+		tokio::spawn(serve(
+			stream,
+			listener.clone(),
+			config.clone(),
+			tls.clone(),
+			rx.clone(),
+		));
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		path::{Path, PathBuf},
+		sync::Arc,
+	};
+
+	use sail::{net::dns::StaticResolver, smtp::args::Domain};
+	use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+	use tokio_rustls::{
+		rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+		TlsAcceptor, TlsConnector,
+	};
+
+	use super::*;
+	use crate::{fs::MailCache, queue::Queue};
+
+	/// A directory of the test's own, for it to remove when it's done
+	fn test_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("saild-{}-{}", name, std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// A self-signed certificate for localhost, written to `dir`, and a
+	/// connector that trusts it.
+	fn self_signed(dir: &Path) -> (TlsAcceptor, TlsConnector) {
+		let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+		let cert_path = dir.join("cert.pem");
+		let key_path = dir.join("key.pem");
+		std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+		std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+		let acceptor = crate::tls::acceptor(&cert_path, &key_path).unwrap();
+
+		let mut roots = RootCertStore::empty();
+		roots.add(cert.cert.der().clone()).unwrap();
+		let config = ClientConfig::builder_with_provider(Arc::new(
+			tokio_rustls::rustls::crypto::ring::default_provider(),
+		))
+		.with_safe_default_protocol_versions()
+		.unwrap()
+		.with_root_certificates(roots)
+		.with_no_client_auth();
+
+		(acceptor, TlsConnector::from(Arc::new(config)))
+	}
+
+	fn policy(starttls: bool, dir: &Path) -> Arc<ServerPolicy> {
+		Arc::new(ServerPolicy {
+			hostnames: vec![Domain::FQDN("localhost".into())],
+			relays: vec![],
+			users: None,
+			maildir: "maildir/{destination user}".parse().unwrap(),
+			max_message_size: None,
+			starttls,
+			credentials: None,
+			aliases: Default::default(),
+			queue: Queue::new(
+				MailCache::new(dir.join("spool")),
+				Default::default(),
+				Default::default(),
+				Arc::new(StaticResolver::new()),
+				Domain::FQDN("localhost".into()),
+			)
+			.0,
+		})
+	}
+
+	fn mode(mode: ListenerMode) -> Listener {
+		Listener {
+			address: "127.0.0.1".parse().unwrap(),
+			port: 0,
+			mode,
+			require_auth: mode.requires_auth(),
+		}
+	}
+
+	/// Read a whole, possibly multiline, reply
+	async fn reply<S: AsyncBufReadExt + Unpin>(stream: &mut S) -> String {
+		let mut reply = String::new();
+
+		loop {
+			let mut line = String::new();
+			stream.read_line(&mut line).await.unwrap();
+			reply.push_str(&line);
+
+			if line.as_bytes().get(3) != Some(&b'-') {
+				return reply;
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn starttls_session() {
+		let dir = test_dir("starttls-session");
+		let (acceptor, connector) = self_signed(&dir);
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (_tx, rx) = watch::channel(false);
+		tokio::spawn(listen(
+			listener,
+			mode(ListenerMode::Mta),
+			policy(true, &dir),
+			Some(acceptor),
+			rx,
+		));
+
+		let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+		assert!(reply(&mut stream).await.starts_with("220 "));
+
+		stream.write_all(b"EHLO client.test\r\n").await.unwrap();
+		assert!(reply(&mut stream).await.contains("250-STARTTLS\r\n"));
+
+		stream.write_all(b"STARTTLS\r\n").await.unwrap();
+		assert!(reply(&mut stream).await.starts_with("220 "));
+
+		let stream = connector
+			.connect(
+				ServerName::try_from("localhost").unwrap(),
+				stream.into_inner(),
+			)
+			.await
+			.unwrap();
+		let mut stream = BufReader::new(stream);
+
+		stream.write_all(b"EHLO client.test\r\n").await.unwrap();
+		let ehlo = reply(&mut stream).await;
+		assert!(ehlo.starts_with("250-"));
+		assert!(!ehlo.contains("STARTTLS"));
+
+		stream.write_all(b"QUIT\r\n").await.unwrap();
+		assert!(reply(&mut stream).await.starts_with("221 "));
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn no_starttls_without_certificate() {
+		let dir = test_dir("no-starttls");
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (_tx, rx) = watch::channel(false);
+		tokio::spawn(listen(
+			listener,
+			mode(ListenerMode::Mta),
+			policy(false, &dir),
+			None,
+			rx,
+		));
+
+		let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+		reply(&mut stream).await;
+
+		stream.write_all(b"EHLO client.test\r\n").await.unwrap();
+		assert!(!reply(&mut stream).await.contains("STARTTLS"));
+
+		stream.write_all(b"STARTTLS\r\n").await.unwrap();
+		assert!(reply(&mut stream).await.starts_with("500 "));
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn implicit_tls_listeners() {
+		let dir = test_dir("implicit-tls");
+		let (acceptor, connector) = self_signed(&dir);
+		let (_tx, rx) = watch::channel(false);
+
+		// Two listeners sharing one policy, as saild would set them up
+		let policy = policy(true, &dir);
+		let plain = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let plain_addr = plain.local_addr().unwrap();
+		let implicit = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let implicit_addr = implicit.local_addr().unwrap();
+		tokio::spawn(listen(
+			plain,
+			mode(ListenerMode::Submission),
+			policy.clone(),
+			Some(acceptor.clone()),
+			rx.clone(),
+		));
+		tokio::spawn(listen(
+			implicit,
+			mode(ListenerMode::Submissions),
+			policy,
+			Some(acceptor),
+			rx,
+		));
+
+		let mut stream = BufReader::new(TcpStream::connect(plain_addr).await.unwrap());
+		assert!(reply(&mut stream).await.starts_with("220 "));
+
+		// The handshake comes first on an implicit TLS listener
+		let stream = TcpStream::connect(implicit_addr).await.unwrap();
+		let stream = connector
+			.connect(ServerName::try_from("localhost").unwrap(), stream)
+			.await
+			.unwrap();
+		let mut stream = BufReader::new(stream);
+		assert!(reply(&mut stream).await.starts_with("220 "));
+
+		stream.write_all(b"EHLO client.test\r\n").await.unwrap();
+		assert!(!reply(&mut stream).await.contains("STARTTLS"));
 
-		tokio::spawn(serve(stream, config.clone(), rx.clone()));
+		std::fs::remove_dir_all(dir).unwrap();
 	}
 }
@@ -1,12 +1,19 @@
-use crate::{config::MaildirTemplate, fs::Maildir};
+use crate::{
+	alias::AliasMap,
+	config::MaildirTemplate,
+	fs::{Maildir, QueuedMail},
+	queue::{report_content, Queue},
+	userdb::UserDatabase,
+};
 
-use std::collections::HashMap;
+use std::{collections::HashMap, io, path::PathBuf, sync::Arc, time::SystemTime};
 
 use sail::{
-	policy::Policy,
+	policy::{CredentialBackend, MessageSink, Policy, Rejection},
 	smtp::{
-		args::{Domain, ForeignPath, ForwardPath, LocalPart, Path},
-		Envelope, ForeignEnvelope, Message, Response, ResponseCode,
+		args::{Domain, ForeignPath, ForwardPath, LocalPart, Parameter, Parameters, Path},
+		Action, Dsn, Envelope, Extension, Extensions, ForeignEnvelope, Message, OriginalRecipient,
+		RecipientStatus, Response, ResponseCode,
 	},
 };
 
@@ -14,8 +21,18 @@ use sail::{
 pub struct ServerPolicy {
 	pub hostnames: Vec<Domain>,
 	pub relays: Vec<Domain>,
-	pub users: Vec<LocalPart>,
+	/// Who can receive mail here. Without one, any local part is accepted.
+	pub users: Option<Arc<dyn UserDatabase>>,
 	pub maildir: MaildirTemplate,
+	pub max_message_size: Option<usize>,
+	/// Whether we have a certificate and can offer STARTTLS
+	pub starttls: bool,
+	/// Where to check passwords for AUTH. AUTH isn't offered without one.
+	pub credentials: Option<Arc<dyn CredentialBackend>>,
+	/// Aliases and virtual domains, applied to local recipients before delivery
+	pub aliases: AliasMap,
+	/// Outbound mail is spooled here before we accept it
+	pub queue: Queue,
 }
 
 impl ServerPolicy {
@@ -29,17 +46,107 @@ impl ServerPolicy {
 		self.relays.contains(&path.domain)
 	}
 
-	/// Check that the localpart is a valid user. This **does not** check the domain
-	fn user_is_valid(&self, local: &LocalPart) -> bool {
-		self.users.contains(local)
+	/// Check that the local part of a path is a valid user with room in their
+	/// maildir. This **does not** check the domain
+	fn check_user(&self, path: &Path) -> Result<(), Rejection> {
+		let users = match &self.users {
+			None => return Ok(()),
+			Some(users) => users,
+		};
+
+		let user = users
+			.user_for_local_part(&path.local_part.to_string())
+			.ok_or(Rejection::NoSuchUser)?;
+		let Some(quota) = user.quota else {
+			return Ok(());
+		};
+
+		match Maildir::new(self.maildir_path(&ForwardPath::Regular(path.clone())))
+			.size()
+			.is_ok_and(|size| size < quota)
+		{
+			true => Ok(()),
+			false => Err(Rejection::MailboxFull),
+		}
+	}
+
+	/// Where mail for a local forward path is delivered. Users can override
+	/// the Maildir template in the user database, and otherwise the template
+	/// is filled in with the name of the user the path matched, so a `+tag`
+	/// subaddress ends up in its user's maildir.
+	fn maildir_path(&self, forward: &ForwardPath) -> PathBuf {
+		let (ForwardPath::Regular(path), Some(users)) = (forward, &self.users) else {
+			return self.maildir.as_path(forward);
+		};
+
+		let Some(user) = users.user_for_local_part(&path.local_part.to_string()) else {
+			return self.maildir.as_path(forward);
+		};
+
+		if let Some(maildir) = &user.maildir {
+			return maildir.clone();
+		}
+
+		match user.name.parse::<LocalPart>() {
+			Ok(local) => self
+				.maildir
+				.as_path(&ForwardPath::Regular(Path::new(local, path.domain.clone()))),
+			Err(_) => self.maildir.as_path(forward),
+		}
 	}
 
-	/// True if the forward path is postmaster or `path_is_local` is true
-	fn forward_path_is_local(&self, forward: &ForwardPath) -> bool {
-		match forward {
-			ForwardPath::Postmaster => true,
-			ForwardPath::Regular(path) => self.path_is_local(path),
+	/// Expand the forward paths through our aliases. Paths that aren't ours
+	/// are left alone, and anything that fails to resolve is dropped. Targets
+	/// keep the parameters of the path they came from, and get an ORCPT if it
+	/// didn't have one so reports still name the address the sender used.
+	fn expand_aliases(
+		&self,
+		forwards: Vec<(ForwardPath, Parameters)>,
+	) -> Vec<(ForwardPath, Parameters)> {
+		let mut expanded: Vec<(ForwardPath, Parameters)> = vec![];
+
+		for (forward, parameters) in forwards {
+			let paths = match &forward {
+				ForwardPath::Regular(path)
+					if self.path_is_local(path) || self.aliases.has_domain(&path.domain) =>
+				{
+					match self.aliases.resolve(path, &self.hostnames) {
+						Ok(paths) => paths
+							.into_iter()
+							.map(|target| {
+								let mut parameters = parameters.clone();
+								if target.to_string() != path.to_string()
+									&& !parameters.contains("ORCPT")
+								{
+									parameters.push(Parameter::new(
+										"ORCPT",
+										Some(OriginalRecipient::rfc822(path).to_string()),
+									));
+								}
+								(ForwardPath::Regular(target), parameters)
+							})
+							.collect(),
+						Err(e) => {
+							eprintln!("Failed to resolve aliases for {}: {}", path, e);
+							vec![]
+						}
+					}
+				}
+				_ => vec![(forward, parameters)],
+			};
+
+			for (path, parameters) in paths {
+				let address = path.to_string().to_lowercase();
+				if !expanded
+					.iter()
+					.any(|(seen, _)| seen.to_string().to_lowercase() == address)
+				{
+					expanded.push((path, parameters));
+				}
+			}
 		}
+
+		expanded
 	}
 }
 
@@ -51,68 +158,270 @@ impl Policy for ServerPolicy {
 			.unwrap_or(Domain::FQDN("localhost".to_owned()))
 	}
 
-	fn path_is_valid(&self, path: &Path) -> bool {
-		self.path_is_foreign(path)
-			|| (self.path_is_local(path)/* && self.user_is_valid(&path.local_part) */)
+	fn extensions(&self) -> Extensions {
+		let mut extensions = Extensions::new()
+			.with(Extension::Size)
+			.with(Extension::Pipelining)
+			.with(Extension::EightBitMime)
+			.with(Extension::Chunking)
+			.with(Extension::BinaryMime)
+			.with(Extension::SmtpUtf8)
+			.with(Extension::Dsn);
+
+		if self.starttls {
+			extensions.enable(Extension::StartTls);
+		}
+
+		if self.credentials.is_some() {
+			extensions.enable(Extension::Auth);
+		}
+
+		extensions
+	}
+
+	fn credentials(&self) -> Option<&dyn CredentialBackend> {
+		self.credentials.as_deref()
+	}
+
+	fn max_message_size(&self) -> Option<usize> {
+		self.max_message_size
 	}
 
-	fn message_received(&mut self, message: Envelope) -> Response {
-		let (reverse, forwards, content) = message.into_parts();
+	fn check_path(&self, path: &Path) -> Result<(), Rejection> {
+		if self.path_is_foreign(path) {
+			return Ok(());
+		}
+
+		if !self.path_is_local(path) && !self.aliases.has_domain(&path.domain) {
+			return Err(Rejection::RelayDenied);
+		}
+
+		// An alias is deliverable if any of its targets is. Targets elsewhere
+		// were put there by whoever wrote the alias table, so we trust them.
+		// Otherwise the last target says why not.
+		let paths = self
+			.aliases
+			.resolve(path, &self.hostnames)
+			.map_err(|_| Rejection::NoSuchUser)?;
+
+		let mut rejection = Rejection::NoSuchUser;
+		for path in &paths {
+			match self.path_is_local(path) {
+				false => return Ok(()),
+				true => match self.check_user(path) {
+					Ok(()) => return Ok(()),
+					Err(e) => rejection = e,
+				},
+			}
+		}
+
+		Err(rejection)
+	}
+
+	fn message_sink(&mut self) -> io::Result<Box<dyn MessageSink>> {
+		Ok(Box::new(self.queue.receive()?))
+	}
+
+	fn message_received(&mut self, message: Envelope, mut data: Box<dyn MessageSink>) -> Response {
+		let arrival = SystemTime::now();
+		let Envelope {
+			reverse_path: reverse,
+			forward_paths,
+			mail_parameters,
+			rcpt_parameters,
+			..
+		} = message;
+		let forwards =
+			self.expand_aliases(forward_paths.into_iter().zip(rcpt_parameters).collect());
 		// Seperate the message by domains and whether or not the message is local.
-		//TODO: divide message into local and relay
 
-		let mut foreign_map: HashMap<Domain, Vec<ForeignPath>> = HashMap::new();
-		let locals: Vec<ForwardPath> = forwards
-			.into_iter()
-			.filter(|forward| {
-				if self.forward_path_is_local(forward) {
-					true // locals stay in the vec
-				} else if let ForwardPath::Regular(path) = forward {
-					// get the vector for a specific domain, but if there isn't one, make it.
-					match foreign_map.get_mut(&path.domain) {
-						Some(vec) => vec.push(ForeignPath(path.clone())),
-						None => {
-							foreign_map
-								.insert(path.domain.clone(), vec![ForeignPath(path.clone())]);
-						}
-					}
+		let mut foreign_map: HashMap<Domain, ForeignEnvelope> = HashMap::new();
+		let mut locals = Envelope::new(reverse.clone());
+		locals.mail_parameters = mail_parameters.clone();
 
-					false
-				} else {
-					// should've been caught by forward_path_is_local, maybe
-					// print a warning if we reach here?
-					true
+		for (forward, parameters) in forwards {
+			match forward {
+				ForwardPath::Regular(path) if !self.path_is_local(&path) => {
+					// get the envelope for a specific domain, but if there isn't one, make it.
+					let envelope = foreign_map.entry(path.domain.clone()).or_insert_with(|| {
+						let mut envelope =
+							ForeignEnvelope::from_parts(reverse.clone(), vec![], Message::empty());
+						envelope.mail_parameters = mail_parameters.clone();
+						envelope
+					});
+					envelope.add_recipient_with(ForeignPath(path), parameters);
 				}
-			})
-			.collect();
+				local => locals.add_recipient_with(local, parameters),
+			}
+		}
 
-		// # Saving locally
-		// Try and save it to the file system. If we fail, tell the server that it's rejected
-		// as we have nowhere to save it! If it succeeds, tell the server as such (return 250).
-		//TODO: How do we handle partial failures?
-		//TODO: Save the local bits to disk
-		for local in locals {
-			let md = Maildir::new(self.maildir.as_path(&local));
-			md.create_directories().unwrap();
-			md.save(content.clone()).unwrap();
+		// The message stays in the sink, which is linked into the spool and copied into
+		// the maildirs. Mail for other servers only needs its header section in memory.
+		if !foreign_map.is_empty() {
+			let headers = match data.header_section() {
+				Ok(headers) => headers,
+				Err(e) => {
+					eprintln!("Failed to read back the message: {}", e);
+					return Response::with_message(
+						ResponseCode::ProcessingError,
+						"Failed to read the message, try again later",
+					);
+				}
+			};
+
+			for envelope in foreign_map.values_mut() {
+				envelope.data = headers.clone();
+			}
+		}
+
+		// Every maildir has to be there before anything is spooled or saved, so if one
+		// can't be made we can refuse the whole message without anyone getting it twice.
+		let mut maildirs = vec![];
+		for local in &locals.forward_paths {
+			let maildir = Maildir::new(self.maildir_path(local));
+			if let Err(e) = maildir.create_directories() {
+				eprintln!("Failed to create the maildir for {}: {}", local, e);
+				return Response::with_message(
+					ResponseCode::ProcessingError,
+					"Failed to deliver the message, try again later",
+				);
+			}
+			maildirs.push((local, maildir));
 		}
 
 		// # Relaying Onwards
 		// First, check if the server this would relay to is in our list that we're allowed to
 		// relay to (we do NOT want to be an open relay, that is a bad thing).
-		// There's an async task setup to deal with mail relay. If we accept the mail, send it
-		// there and return 250. If we don't accept it, tell the server as such.
-		for (domain, forwards) in foreign_map.into_iter() {
-			let envelope = ForeignEnvelope::from_parts(reverse.clone(), forwards, content.clone());
+		// Outbound mail is spooled before anything is saved locally, so if the spool fails
+		// we can refuse the whole message and the client can try again without anyone
+		// getting it twice.
+		let outbound = foreign_map
+			.into_iter()
+			.map(|(domain, envelope)| QueuedMail::new(domain, envelope))
+			.collect();
+
+		if let Err(e) = self.queue.enqueue(outbound, data.as_mut()) {
+			eprintln!("Failed to spool outbound mail: {}", e);
+			return Response::with_message(
+				ResponseCode::ProcessingError,
+				"Failed to queue the message, try again later",
+			);
+		}
 
-			tokio::spawn(sail::net::relay(domain, envelope));
+		// # Saving locally
+		// Outbound mail is already queued, so it's too late to refuse the message. If a
+		// save fails now, the sender is told that recipient failed instead.
+		let mut statuses = vec![];
+		for (local, maildir) in &maildirs {
+			let saved = data
+				.reader()
+				.and_then(|mut reader| maildir.save(&mut reader));
+			if let Err(e) = &saved {
+				eprintln!("Failed to save mail for {}: {}", local, e);
+			}
+
+			if let ForwardPath::Regular(path) = local {
+				statuses.push(match saved {
+					Ok(()) => RecipientStatus {
+						recipient: path.clone(),
+						action: Action::Delivered,
+						status: String::from("2.0.0"),
+						diagnostic: None,
+						original_recipient: None,
+					},
+					Err(_) => RecipientStatus {
+						recipient: path.clone(),
+						action: Action::Failed,
+						status: String::from("5.3.0"),
+						diagnostic: Some(String::from("Failed to save the message")),
+						original_recipient: None,
+					},
+				});
+			}
+		}
+
+		// A failure report carries the original headers, even if nobody asked with NOTIFY
+		let wants_report = locals
+			.rcpt_parameters
+			.iter()
+			.any(|parameters| parameters.contains("NOTIFY"));
+		let failed = statuses
+			.iter()
+			.any(|status| status.action == Action::Failed);
+		if wants_report || failed {
+			match report_content(&locals.mail_parameters, data.as_mut()) {
+				Ok(content) => locals.data = content,
+				Err(e) => eprintln!("Failed to read back the message for a report: {}", e),
+			}
+		}
+
+		// Anyone that asked with NOTIFY=SUCCESS gets told it arrived
+		if let Some(report) = Dsn::report(self.primary_host(), &locals, arrival, statuses) {
+			if let Err(e) = self.queue.send_report(report) {
+				eprintln!("Failed to spool a report: {}", e);
+			}
 		}
 
 		Response::new(ResponseCode::Okay)
 	}
 }
 
-struct DeliveryEnvelope {
-	foreign: Vec<(Domain, Vec<ForeignPath>)>,
-	message: Message,
+#[cfg(test)]
+mod test {
+	use sail::{net::dns::StaticResolver, smtp::args::ReversePath};
+
+	use super::*;
+	use crate::fs::MailCache;
+
+	fn policy(dir: &std::path::Path, maildir: &str) -> ServerPolicy {
+		ServerPolicy {
+			hostnames: vec![Domain::FQDN("localhost".into())],
+			relays: vec![],
+			users: None,
+			maildir: maildir.parse().unwrap(),
+			max_message_size: None,
+			starttls: false,
+			credentials: None,
+			aliases: Default::default(),
+			queue: Queue::new(
+				MailCache::new(dir.join("spool")),
+				Default::default(),
+				Default::default(),
+				Arc::new(StaticResolver::new()),
+				Domain::FQDN("localhost".into()),
+			)
+			.0,
+		}
+	}
+
+	fn envelope() -> Envelope {
+		let mut envelope = Envelope::new(ReversePath::Null);
+		envelope.add_recipient(ForwardPath::Regular("<gen@localhost>".parse().unwrap()));
+		envelope
+	}
+
+	#[test]
+	fn local_delivery() {
+		let dir = std::env::temp_dir().join(format!("saild-policy-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+
+		// A maildir that can't be made refuses the message for now
+		let blocker = dir.join("blocker");
+		std::fs::write(&blocker, b"").unwrap();
+		let mut blocked = policy(&dir, &format!("{}/{{destination user}}", blocker.display()));
+		let response = blocked.message_received(envelope(), Box::new(b"hi\r\n".to_vec()));
+		assert_eq!(response.code, ResponseCode::ProcessingError);
+
+		let maildirs = dir.join("maildirs");
+		let mut working = policy(
+			&dir,
+			&format!("{}/{{destination user}}", maildirs.display()),
+		);
+		let response = working.message_received(envelope(), Box::new(b"hi\r\n".to_vec()));
+		assert_eq!(response.code, ResponseCode::Okay);
+		let new = std::fs::read_dir(maildirs.join("gen").join("new")).unwrap();
+		assert_eq!(new.count(), 1);
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
 }
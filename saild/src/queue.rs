@@ -0,0 +1,489 @@
+use std::{
+	collections::HashMap,
+	io,
+	sync::Arc,
+	time::{Duration, SystemTime},
+};
+
+use sail::{
+	net::{
+		dns::{DnsLookupError, Resolver},
+		relay, DeliveryStatus, RecipientOutcome, RelayError, Route,
+	},
+	policy::MessageSink,
+	smtp::{
+		args::{Domain, ForeignPath, Parameters},
+		Action, Dsn, Envelope, Extension, MailDsn, Message, RecipientStatus, Ret,
+	},
+};
+use tokio::sync::mpsc;
+
+use crate::fs::{unix_now, IncomingMessage, MailCache, ParseQueuedMailError, QueuedMail};
+
+/// How long to wait before trying a report that couldn't be delivered again
+const REPORT_RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// The outbound queue. Mail is spooled to disk before we accept it and is
+/// only removed once it's been delivered or bounced, so nothing is lost if
+/// the remote is down or we're restarted.
+#[derive(Clone, Debug)]
+pub struct Queue {
+	cache: MailCache,
+	retry: Retry,
+	transports: Transports,
+	/// Shared by every delivery so lookups are cached
+	resolver: Arc<dyn Resolver>,
+	/// Who we say we are in delivery reports
+	hostname: Domain,
+	/// The ids of spooled reports, which go back through the same delivery
+	/// as any other mail
+	reports: mpsc::UnboundedSender<String>,
+}
+
+/// How long to wait between delivery attempts, and when to give up
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retry {
+	/// The wait after each failed attempt. Once we run out, the last interval
+	/// is used for every attempt after.
+	pub intervals: Vec<Duration>,
+	/// How long mail can sit in the queue before we bounce it
+	pub lifetime: Duration,
+}
+
+impl Default for Retry {
+	fn default() -> Self {
+		const MINUTE: u64 = 60;
+		const HOUR: u64 = 60 * MINUTE;
+
+		Self {
+			intervals: vec![
+				Duration::from_secs(5 * MINUTE),
+				Duration::from_secs(30 * MINUTE),
+				Duration::from_secs(2 * HOUR),
+				Duration::from_secs(8 * HOUR),
+			],
+			// RFC 5321 section 4.5.4.1 says at least 4-5 days
+			lifetime: Duration::from_secs(5 * 24 * HOUR),
+		}
+	}
+}
+
+impl Retry {
+	/// When to try again after `attempts` failures, or None if the mail has
+	/// been in the queue too long. The last attempt happens right as the
+	/// lifetime runs out.
+	pub fn next_attempt(&self, queued: u64, attempts: u32, now: u64) -> Option<u64> {
+		let expires = queued + self.lifetime.as_secs();
+		if now >= expires {
+			return None;
+		}
+
+		let index = (attempts as usize).saturating_sub(1);
+		let interval = self
+			.intervals
+			.get(index)
+			.or(self.intervals.last())
+			.map(Duration::as_secs)
+			.unwrap_or(0);
+
+		Some((now + interval).min(expires))
+	}
+}
+
+/// Which way mail leaves for each destination domain
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transports {
+	/// For domains without their own entry
+	pub default: Route,
+	/// Keyed by the lowercased domain
+	pub domains: HashMap<String, Route>,
+}
+
+impl Transports {
+	pub fn route(&self, domain: &Domain) -> &Route {
+		self.domains
+			.get(&domain.to_string().to_lowercase())
+			.unwrap_or(&self.default)
+	}
+}
+
+impl Queue {
+	/// Returns the queue and where the ids of its delivery reports come out.
+	/// The reports need to be delivered like any other mail we've received;
+	/// see [Queue::read_report].
+	pub fn new(
+		cache: MailCache,
+		retry: Retry,
+		transports: Transports,
+		resolver: Arc<dyn Resolver>,
+		hostname: Domain,
+	) -> (Self, mpsc::UnboundedReceiver<String>) {
+		let (reports, rx) = mpsc::unbounded_channel();
+
+		(
+			Self {
+				cache,
+				retry,
+				transports,
+				resolver,
+				hostname,
+				reports,
+			},
+			rx,
+		)
+	}
+
+	/// Spool the mail, which all has the message in `data`, and start trying
+	/// to deliver it. Either everything is queued or, if this returns an
+	/// error, nothing is and the mail shouldn't be accepted.
+	pub fn enqueue(&self, mails: Vec<QueuedMail>, data: &mut dyn MessageSink) -> io::Result<()> {
+		let mut spooled = vec![];
+
+		for mail in mails {
+			match self.cache.spool(&mail, data) {
+				Ok(id) => spooled.push((id, mail)),
+				Err(e) => {
+					for (id, _) in spooled {
+						let _ = self.cache.remove(&id);
+					}
+					return Err(e);
+				}
+			}
+		}
+
+		for (id, mail) in spooled {
+			tokio::spawn(self.clone().deliver(id, mail));
+		}
+
+		Ok(())
+	}
+
+	/// Somewhere on disk to receive a message into
+	pub fn receive(&self) -> io::Result<IncomingMessage> {
+		self.cache.receive()
+	}
+
+	/// Start delivering whatever was left in the queue when we last exited.
+	/// Entries keep their schedule, and reports that were waiting are handed
+	/// over again. Returns how many entries were picked up. This has to
+	/// happen before any mail is received.
+	pub fn resume(&self) -> io::Result<usize> {
+		self.cache.clean_up()?;
+
+		let entries = self.cache.entries()?;
+		let count = entries.len();
+
+		for (id, mail) in entries {
+			tokio::spawn(self.clone().deliver(id, mail));
+		}
+
+		for id in self.cache.reports()? {
+			let _ = self.reports.send(id);
+		}
+
+		Ok(count)
+	}
+
+	/// Keep trying to deliver the mail until it goes through, is rejected, or
+	/// has been in the queue too long
+	async fn deliver(self, id: String, mut mail: QueuedMail) {
+		loop {
+			let wait = mail.next_attempt.saturating_sub(unix_now());
+			if wait > 0 {
+				tokio::time::sleep(Duration::from_secs(wait)).await;
+			}
+
+			let mut data = match self.cache.open_message(&id) {
+				Ok(data) => data,
+				Err(e) => {
+					// Trying again won't bring it back
+					eprintln!("Queue entry {} has lost its message: {}", id, e);
+					let recipients = mail
+						.envelope
+						.forward_paths
+						.iter()
+						.map(|path| status(path, Action::Failed, "5.3.0", Some(e.to_string())))
+						.collect();
+					self.report(&id, &mail, recipients).await;
+					break;
+				}
+			};
+
+			let result = relay(
+				self.resolver.as_ref(),
+				self.transports.route(&mail.domain),
+				mail.domain.clone(),
+				mail.envelope.clone(),
+				&mut data,
+			)
+			.await;
+
+			// The recipients worth trying again and what the server said, or
+			// why we couldn't ask
+			let (deferred, code, reason) = match result {
+				Ok(report) => {
+					println!("Queue entry {}:\n{}", id, report);
+
+					let failed: Vec<RecipientStatus> = report
+						.with_status(DeliveryStatus::Failed)
+						.map(|outcome| outcome_status(outcome, Action::Failed))
+						.collect();
+					if !failed.is_empty() {
+						self.report(&id, &mail, failed).await;
+					}
+
+					// The next hop only reports on success if it knows about DSN
+					if !report.extensions.contains(Extension::Dsn) {
+						let relayed: Vec<RecipientStatus> = report
+							.with_status(DeliveryStatus::Delivered)
+							.map(|outcome| outcome_status(outcome, Action::Relayed))
+							.collect();
+						if !relayed.is_empty() {
+							self.report(&id, &mail, relayed).await;
+						}
+					}
+
+					let deferred: Vec<RecipientOutcome> = report
+						.with_status(DeliveryStatus::Deferred)
+						.cloned()
+						.collect();
+					if deferred.is_empty() {
+						break;
+					}
+
+					let addresses: Vec<String> = deferred
+						.iter()
+						.map(|outcome| outcome.recipient.0.to_string())
+						.collect();
+					mail.envelope
+						.retain_recipients(|path| addresses.contains(&path.0.to_string()));
+
+					let reason =
+						format!("{} recipients deferred by {}", deferred.len(), report.host);
+					(deferred, "4.0.0", reason)
+				}
+				Err(e) if e.is_transient() => (vec![], error_code(&e), e.to_string()),
+				Err(e) => {
+					let recipients = mail
+						.envelope
+						.forward_paths
+						.iter()
+						.map(|path| {
+							status(path, Action::Failed, error_code(&e), Some(e.to_string()))
+						})
+						.collect();
+					self.report(&id, &mail, recipients).await;
+					break;
+				}
+			};
+
+			mail.attempts += 1;
+			let statuses = |action| -> Vec<RecipientStatus> {
+				match deferred.is_empty() {
+					true => mail
+						.envelope
+						.forward_paths
+						.iter()
+						.map(|path| status(path, action, code, Some(reason.clone())))
+						.collect(),
+					false => deferred
+						.iter()
+						.map(|outcome| outcome_status(outcome, action))
+						.collect(),
+				}
+			};
+
+			match self
+				.retry
+				.next_attempt(mail.queued, mail.attempts, unix_now())
+			{
+				None => {
+					// Delivery time expired (RFC 3463 section 3.5)
+					self.report(&id, &mail, expired(statuses(Action::Failed)))
+						.await;
+					break;
+				}
+				Some(next) => {
+					eprintln!("Delivery of queue entry {} deferred: {}", id, reason);
+					mail.next_attempt = next;
+
+					// Only warn about the delay once
+					if mail.attempts == 1 {
+						self.report(&id, &mail, statuses(Action::Delayed)).await;
+					}
+
+					if let Err(e) = self.cache.update(&id, &mail) {
+						eprintln!("Failed to update queue entry {}: {}", id, e);
+					}
+				}
+			}
+		}
+
+		if let Err(e) = self.cache.remove(&id) {
+			eprintln!("Failed to remove queue entry {}: {}", id, e);
+		}
+	}
+
+	/// Tell the sender what happened to the recipients that asked to know.
+	/// The entry is only removed once its reports are safe on disk, so this
+	/// keeps trying until the report is spooled.
+	async fn report(&self, id: &str, mail: &QueuedMail, statuses: Vec<RecipientStatus>) {
+		let mut envelope: Envelope = mail.envelope.clone().into();
+		let content = self
+			.cache
+			.open_message(id)
+			.and_then(|mut data| report_content(&envelope.mail_parameters, &mut data));
+		// The header section we already have is better than nothing
+		if let Ok(content) = content {
+			envelope.data = content;
+		}
+
+		let report = Dsn::report(
+			self.hostname.clone(),
+			&envelope,
+			SystemTime::UNIX_EPOCH + Duration::from_secs(mail.queued),
+			statuses,
+		);
+
+		if let Some(report) = report {
+			while let Err(e) = self.send_report(report.clone()) {
+				eprintln!("Failed to spool a report, will try again: {}", e);
+				tokio::time::sleep(REPORT_RETRY).await;
+			}
+		}
+	}
+
+	/// Spool a report and hand it over to be delivered. Once this returns the
+	/// report survives a restart, even if nobody picks it up right away.
+	pub fn send_report(&self, report: Envelope) -> io::Result<()> {
+		let Some(report) = QueuedMail::from_report(report) else {
+			return Ok(());
+		};
+
+		let id = self.cache.spool_report(&report)?;
+		// If nobody's listening it's picked up again when we next start
+		let _ = self.reports.send(id);
+
+		Ok(())
+	}
+
+	/// A report that's waiting to be delivered
+	pub fn read_report(&self, id: &str) -> Result<Envelope, ParseQueuedMailError> {
+		Ok(self.cache.read_report(id)?.envelope.into())
+	}
+
+	/// Forget a report once it's been delivered or queued
+	pub fn report_delivered(&self, id: &str) {
+		if let Err(e) = self.cache.remove_report(id) {
+			eprintln!("Failed to remove report {}: {}", id, e);
+		}
+	}
+
+	/// Hand a report back to be delivered again after a while, like when a
+	/// maildir couldn't be written to. It stays spooled in the meantime.
+	pub fn retry_report(&self, id: String) {
+		let reports = self.reports.clone();
+		tokio::spawn(async move {
+			tokio::time::sleep(REPORT_RETRY).await;
+			let _ = reports.send(id);
+		});
+	}
+}
+
+/// As much of the message as a report about it carries: the header section,
+/// or all of it if the sender asked with RET=FULL (RFC 3461 section 4.3)
+pub fn report_content(parameters: &Parameters, data: &mut dyn MessageSink) -> io::Result<Message> {
+	match MailDsn::from_parameters(parameters).map(|dsn| dsn.ret) {
+		Ok(Some(Ret::Full)) => data.message(),
+		_ => data.header_section(),
+	}
+}
+
+/// A status from what the server said about a recipient
+fn outcome_status(outcome: &RecipientOutcome, action: Action) -> RecipientStatus {
+	RecipientStatus::from_reply(outcome.recipient.0.clone(), action, &outcome.reply)
+}
+
+/// The enhanced status code (RFC 3463) for when we couldn't get a server to
+/// tell us anything
+fn error_code(error: &RelayError) -> &'static str {
+	match error {
+		// RFC 7505 section 4.2
+		RelayError::Dns(DnsLookupError::NullMx) => "5.1.10",
+		// Bad destination address, or the DNS server didn't answer
+		RelayError::Dns(_) if !error.is_transient() => "5.1.2",
+		RelayError::Dns(_) => "4.4.3",
+		RelayError::ConnectionTimeout(_)
+		| RelayError::ConnectionClosed
+		| RelayError::ConnectionError(_) => "4.4.1",
+		// The certificate didn't check out, or the handshake went wrong
+		RelayError::TlsHandshake(_) => "4.7.0",
+		_ => "5.0.0",
+	}
+}
+
+/// A status for a recipient we don't have a reply about
+fn status(
+	path: &ForeignPath,
+	action: Action,
+	code: &str,
+	diagnostic: Option<String>,
+) -> RecipientStatus {
+	RecipientStatus {
+		recipient: path.0.clone(),
+		action,
+		status: code.to_owned(),
+		diagnostic,
+		original_recipient: None,
+	}
+}
+
+/// We've given up, so temporary failures are now permanent ones
+fn expired(mut recipients: Vec<RecipientStatus>) -> Vec<RecipientStatus> {
+	for recipient in &mut recipients {
+		if recipient.status.starts_with('4') {
+			recipient.status = String::from("5.4.7");
+		}
+	}
+
+	recipients
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn transport_map() {
+		let relayhost = Route::Relayhost("smtp.example.net:587".parse().unwrap());
+		let transports = Transports {
+			default: relayhost.clone(),
+			domains: HashMap::from([(String::from("nyble.dev"), Route::Mx)]),
+		};
+
+		assert_eq!(transports.route(&"NYBLE.dev".parse().unwrap()), &Route::Mx);
+		assert_eq!(transports.route(&"nove.dev".parse().unwrap()), &relayhost);
+	}
+
+	#[test]
+	fn backoff() {
+		let retry = Retry::default();
+		let queued = 1_000_000;
+
+		// The first failure waits 5 minutes, then it grows
+		assert_eq!(retry.next_attempt(queued, 1, queued), Some(queued + 300));
+		assert_eq!(
+			retry.next_attempt(queued, 2, queued + 300),
+			Some(queued + 300 + 1800)
+		);
+
+		// We keep using the last interval
+		let now = queued + 86_400;
+		assert_eq!(retry.next_attempt(queued, 10, now), Some(now + 8 * 3600));
+
+		// The last attempt lines up with the lifetime, and after that we give up
+		let expires = queued + 5 * 86_400;
+		assert_eq!(retry.next_attempt(queued, 20, expires - 60), Some(expires));
+		assert_eq!(retry.next_attempt(queued, 21, expires), None);
+	}
+}
@@ -0,0 +1,42 @@
+use std::{path::Path, sync::Arc};
+
+use thiserror::Error;
+use tokio_rustls::{
+	rustls::{
+		self,
+		crypto::ring,
+		pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+		ServerConfig,
+	},
+	TlsAcceptor,
+};
+
+/// Build a TLS acceptor from a PEM certificate chain and private key
+pub fn acceptor<C: AsRef<Path>, K: AsRef<Path>>(
+	certificate: C,
+	key: K,
+) -> Result<TlsAcceptor, TlsConfigError> {
+	let chain = CertificateDer::pem_file_iter(certificate)?.collect::<Result<Vec<_>, _>>()?;
+	if chain.is_empty() {
+		return Err(TlsConfigError::NoCertificates);
+	}
+
+	let key = PrivateKeyDer::from_pem_file(key)?;
+
+	let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+		.with_safe_default_protocol_versions()?
+		.with_no_client_auth()
+		.with_single_cert(chain, key)?;
+
+	Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[derive(Debug, Error)]
+pub enum TlsConfigError {
+	#[error("failed to read PEM file: {0}")]
+	Pem(#[from] rustls::pki_types::pem::Error),
+	#[error("the certificate file contained no certificates")]
+	NoCertificates,
+	#[error("{0}")]
+	Rustls(#[from] rustls::Error),
+}
@@ -0,0 +1,162 @@
+use std::{path::Path, str::FromStr};
+
+use sail::policy::CredentialBackend;
+use thiserror::Error;
+
+use super::{User, UserDatabase};
+
+/// A passwd-style user file. Each line is one user, with fields separated by
+/// colons. Only the name is required, the rest may be left empty:
+/// ```text
+/// # name:password hash:maildir:quota
+/// gen:$argon2id$v=19$m=19456,t=2,p=1$...:/srv/mail/gen:1073741824
+/// postbox:::
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FlatFile {
+	users: Vec<User>,
+}
+
+impl FlatFile {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ParseFlatFileError> {
+		std::fs::read_to_string(path)?.parse()
+	}
+}
+
+impl UserDatabase for FlatFile {
+	fn user(&self, name: &str) -> Option<&User> {
+		self.users
+			.iter()
+			.find(|user| user.name.eq_ignore_ascii_case(name))
+	}
+}
+
+impl CredentialBackend for FlatFile {
+	fn verify(&self, username: &str, password: &str) -> bool {
+		self.user(username)
+			.is_some_and(|user| user.verify_password(password))
+	}
+}
+
+impl FromStr for FlatFile {
+	type Err = ParseFlatFileError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut users = vec![];
+
+		for (idx, line) in s.lines().enumerate() {
+			let line_number = idx + 1;
+			let line = line.trim();
+
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let mut fields = line.split(':');
+			let name = fields.next().unwrap_or_default();
+			let password_hash = fields.next().unwrap_or_default();
+			let maildir = fields.next().unwrap_or_default();
+			let quota = fields.next().unwrap_or_default();
+
+			if fields.next().is_some() {
+				return Err(ParseFlatFileError::TooManyFields(line_number));
+			}
+
+			if name.is_empty() {
+				return Err(ParseFlatFileError::MissingName(line_number));
+			}
+
+			let quota = match quota {
+				"" => None,
+				quota => match quota.parse() {
+					Ok(quota) => Some(quota),
+					Err(_e) => return Err(ParseFlatFileError::InvalidQuota(line_number)),
+				},
+			};
+
+			users.push(User {
+				name: name.to_owned(),
+				password_hash: password_hash.to_owned(),
+				maildir: (!maildir.is_empty()).then(|| maildir.into()),
+				quota,
+			});
+		}
+
+		Ok(Self { users })
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum ParseFlatFileError {
+	#[error("failed to read user file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("line {0} has no user name")]
+	MissingName(usize),
+	#[error("line {0} has more than four fields")]
+	TooManyFields(usize),
+	#[error("line {0} has an invalid quota")]
+	InvalidQuota(usize),
+}
+
+#[cfg(test)]
+mod test {
+	use std::path::PathBuf;
+
+	use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+
+	use super::*;
+
+	fn hash(password: &str) -> String {
+		let salt = SaltString::from_b64("c2FpbHNhbHRzYWx0").unwrap();
+		Argon2::default()
+			.hash_password(password.as_bytes(), &salt)
+			.unwrap()
+			.to_string()
+	}
+
+	#[test]
+	fn parse_users() {
+		let file = format!(
+			"# a comment\n\ngen:{}:/srv/mail/gen:1024\npostbox:::\n",
+			hash("hunter2")
+		);
+		let db: FlatFile = file.parse().unwrap();
+
+		let gen = db.user("GEN").unwrap();
+		assert_eq!(gen.maildir, Some(PathBuf::from("/srv/mail/gen")));
+		assert_eq!(gen.quota, Some(1024));
+
+		let postbox = db.user("postbox").unwrap();
+		assert_eq!(postbox.maildir, None);
+		assert_eq!(postbox.quota, None);
+
+		assert_eq!(db.user_for_local_part("gen+lists").unwrap().name, "gen");
+		assert!(db.user("nobody").is_none());
+	}
+
+	#[test]
+	fn verify() {
+		let db: FlatFile = format!("gen:{}\npostbox", hash("hunter2")).parse().unwrap();
+
+		assert!(db.verify("gen", "hunter2"));
+		assert!(!db.verify("gen", "hunter3"));
+		assert!(!db.verify("postbox", ""));
+		assert!(!db.verify("nobody", "hunter2"));
+	}
+
+	#[test]
+	fn parse_errors() {
+		assert!(matches!(
+			":hash".parse::<FlatFile>(),
+			Err(ParseFlatFileError::MissingName(1))
+		));
+		assert!(matches!(
+			"gen:::1024:extra".parse::<FlatFile>(),
+			Err(ParseFlatFileError::TooManyFields(1))
+		));
+		assert!(matches!(
+			"\ngen:::lots".parse::<FlatFile>(),
+			Err(ParseFlatFileError::InvalidQuota(2))
+		));
+	}
+}
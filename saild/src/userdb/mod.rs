@@ -0,0 +1,46 @@
+mod flatfile;
+
+pub use flatfile::FlatFile;
+
+use std::path::PathBuf;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+
+/// Somewhere saild can find out who has a mailbox here
+pub trait UserDatabase: Send + Sync {
+	/// Find a user by name. Names are matched case-insensitively.
+	fn user(&self, name: &str) -> Option<&User>;
+
+	/// Find the user that a local part delivers to. A `+tag` subaddress is
+	/// ignored if there's no user with the whole local part.
+	fn user_for_local_part(&self, local_part: &str) -> Option<&User> {
+		self.user(local_part).or_else(|| {
+			local_part
+				.split_once('+')
+				.and_then(|(name, _tag)| self.user(name))
+		})
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+	pub name: String,
+	/// A PHC string, like the ones `argon2` produces. A user with an empty
+	/// hash can receive mail but never authenticate.
+	pub password_hash: String,
+	/// Deliver here instead of wherever the Maildir template says
+	pub maildir: Option<PathBuf>,
+	/// The most the maildir may hold, in octets
+	pub quota: Option<u64>,
+}
+
+impl User {
+	pub fn verify_password(&self, password: &str) -> bool {
+		match PasswordHash::new(&self.password_hash) {
+			Ok(hash) => Argon2::default()
+				.verify_password(password.as_bytes(), &hash)
+				.is_ok(),
+			Err(_e) => false,
+		}
+	}
+}